@@ -84,6 +84,22 @@ function loadData() {
 
 }
 
+// Loads a tree written by the taintTree subcommand. The file already is
+// nested and aggregated, and names its own value field.
+function loadJson(path) {
+
+    d3.json(path, function (json) {
+
+        data = json;
+        valueFields = json.valueFields;
+        valueField = valueFields[0];
+
+        initialize();
+
+    });
+
+}
+
 function prepData(csv) {
 
     var values=[];
@@ -179,10 +195,12 @@ function initialize() {
     //We use this function to size the components based on the selected value from the RadiaLProgressTest.html page.
     changeSize(d3.select("#currentDisplay").attr("item_value"));
 
-    // Open up some of the tree branches.
-    viz.toggleNode(data.values[2]);
-    viz.toggleNode(data.values[2].values[0]);
-    viz.toggleNode(data.values[3]);
+    // Open up some of the tree branches, a tree without children has no values.
+    if (data.values && data.values.length > 3 && data.values[2].values) {
+        viz.toggleNode(data.values[2]);
+        viz.toggleNode(data.values[2].values[0]);
+        viz.toggleNode(data.values[3]);
+    }
 
 }
 
@@ -271,7 +289,7 @@ function changeSize(val) {
 
 //This sets the same value for each radial progress
 function changeData(val) {
-    valueField=valueFields[Number(val)];
+    valueField=valueFields[Number(val)] || valueFields[0];
     viz.update();
 }
 
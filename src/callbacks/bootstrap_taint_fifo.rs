@@ -1,11 +1,13 @@
 extern crate csv;
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::{self, File, OpenOptions};
 use std::io::{LineWriter, Write};
 use std::hash::{BuildHasherDefault};
 use std::path::PathBuf;
-use std::cmp;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 
 use clap::{Arg, ArgMatches, App, SubCommand};
 use rustc_serialize::Decodable;
@@ -13,12 +15,26 @@ use rustc_serialize::json::{self, Json, Decoder};
 use twox_hash::XxHash;
 
 use callbacks::Callback;
-use errors::{OpError, OpResult};
+use callbacks::taint_output::{TaintOutput, OutputFormat, OutputTables, TaintOverlap, SeriesSample, AddressBalance,
+                              TaintFlow, PendingFiles, write_pending, sha256_file, TAINTED_UTXO_SCHEMA_VERSION};
+use callbacks::taint_engine::{TaintPart, AddressInfo, FifoEngine, EngineObserver, Outpoint, BlockData, Transaction, TxIn, TxOut,
+                              TaintVec, LabelSums, touched_outpoints, to_stored, reduce_taint, taint_to_sum,
+                              non_white_taint_to_sum, timestamp_to_date};
+use callbacks::taint_graph::TaintGraph;
+use callbacks::taint_svg::TaintSvg;
+use callbacks::taint_undo::{UndoJournal, BlockUndo, StateView};
+use callbacks::taint_metrics::{StateCounters, Metrics, MetricsFormat, MetricsSample};
+use callbacks::taint_trace::TxTracer;
+use callbacks::taint_audit::{Audit, AuditMode};
+use callbacks::taint_results::read_seeds;
+use callbacks::taint_state::{RunState, AddressBook, sorted_by_outpoint, write_state, load_state, read_utxo_snapshot, insert_utxo,
+                             remove_utxo, PENDING_SEEDS_FILE};
+use errors::{OpError, OpErrorKind, OpResult};
 
 use blockchain::parser::types::CoinType;
 use blockchain::proto::block::Block;
 use blockchain::proto::Hashed;
-use blockchain::proto::tx::{Tx, TxOutpoint, TxInput, TxOutput, EvaluatedTxOut};
+use blockchain::proto::tx::{Tx, TxOutpoint, TxInput};
 use blockchain::utils::{arr_to_hex_swapped, hex_to_arr32_swapped};
 use blockchain::utils::csv::CsvFile;
 use blockchain::utils;
@@ -44,45 +60,22 @@ use unionfind::{UnionFinder, UnionFinderImpl};
 extern crate chrono;
 use self::chrono::*;
 
+extern crate ctrlc;
+
 use std::collections::VecDeque;
 
 use blockchain::proto::script::{ScriptEvaluator, ScriptPattern, ScriptError, eval_from_stack, eval_from_bytes};
 
-#[derive(PartialEq, Eq, Hash, Default, Debug, RustcDecodable, RustcEncodable, Clone)]
-pub struct TaintPart {
-    name : u16,
-    value: u64
-}
-
-#[derive(PartialEq, Eq, Default, Hash, Debug, RustcDecodable, RustcEncodable, Clone)]
-pub struct AddressInfo {
-    timestamp: String,
-    current_balance: u64,
-    tainted_balance: Option<VecDeque<TaintPart>>
-}
-
-use std::fmt;
-impl fmt::Display for AddressInfo {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let yo:String = if let Some(ref x) = self.tainted_balance {
-           x.iter().fold(String::new(), |acc, k| { acc + &k.name.to_string() + " " + &k.value.to_string() + ","})
-        }else{
-           String::from("")
-        };
-
-        write!(f, "{},{},{}",
-               self.timestamp, self.current_balance, yo)
-    }
-}
-
 /// Groups addresses into ownership clusters.
 pub struct TaintFifo {
     dump_folder: PathBuf,
-    address_info_writer: LineWriter<File>,  // The file for address information
-    utxo_writer: LineWriter<File>,          // The file for utxo information
-    taint_mapping_writer: LineWriter<File>, // The file for taint mapping storage
-    overlap_writer: LineWriter<File>,       // The file for overlapping taint information
-    utxo_set: HashMap<TxOutpoint, String, BuildHasherDefault<XxHash>>, // a map of all UTXO->string
+    output: Box<TaintOutput>,       // Where the result tables are written
+    output_format: OutputFormat,    // The format of the result tables
+    output_tables: OutputTables,    // The result tables written by this run
+    graph: Option<TaintGraph>,      // The transaction graph reached by the selected labels
+    svg: Option<TaintSvg>,          // The tainted outputs of a height range, drawn as SVG
+    utxo_set: HashMap<TxOutpoint, u32, BuildHasherDefault<XxHash>>, // a map of all UTXO->address id
+    addresses: AddressBook,         // The addresses of the UTXO set, each stored once
     address_mapping: HashMap<TxOutpoint, AddressInfo, BuildHasherDefault<XxHash>>, //Mapping from the UTXO to the state of it
     bootstrap_addresses: HashMap<String, VecDeque<TaintPart>>, // Input address
 
@@ -90,303 +83,310 @@ pub struct TaintFifo {
     end_height: usize,
     max_height: usize,
 
+    series_interval: usize,          // Sample the taint series every N blocks (0 disables it)
+    series_daily: bool,              // Sample the taint series once per day of block time
+    series_last_date: Option<String>, // Latest block time seen so far, as a date. Block times are not monotonic,
+                                      // so a day is over once this moves past it
+
+    snapshot_heights: BTreeSet<usize>,  // Heights after which a full snapshot is written
+    snapshot_dates: BTreeSet<NaiveDate>, // Days of block time at whose end a full snapshot is written
+    snapshot_files: Vec<PathBuf>,        // Snapshot files committed so far, relative to the dump folder
+
     address_file: String,   // Path of the list to transactions
+    parameters: BTreeMap<String, String>, // The command line arguments given, for the manifest
     taint_collisions: u32,  // Number of tainted transactions
-    total_taint_for_tx: VecDeque<TaintPart>, // VecDeque that keeps the taint for current transactions
+    engine: FifoEngine,     // Matches the taint of every block, see taint_engine
     whitename: String,     // A constant name for the clean money
-    dirtmapper: HashMap<String, u16> // This the translation mapper for the dirt names.
-}
-
-fn count_fragments(am: &HashMap<TxOutpoint, AddressInfo, BuildHasherDefault<XxHash>>)->usize{
-    return am.iter().fold(0, |a, v| a+if let Some(ref x) = v.1.tainted_balance{x.len()}else{0});
+    dirtmapper: HashMap<String, u16>, // This the translation mapper for the dirt names.
+
+    interrupted: Arc<AtomicBool>,   // Set by SIGINT/SIGTERM, the run stops after the current block
+    checkpoint: bool,               // Write a resumable checkpoint when interrupted
+    resume_from: Option<PathBuf>,   // Checkpoint to continue from instead of the address file
+    utxo_snapshot: Option<PathBuf>, // UTXO set to start from at a height after genesis
+    incremental: Option<PathBuf>,   // Dump folder of the run this one follows up
+    save_state: bool,               // Save the end state for a follow-up run
+    series_closing_rows: usize,     // Rows of the last series sample written only because the run ended
+    undo: UndoJournal,              // What the most recent blocks changed, to roll them back on a reorg
+    counters: StateCounters,        // Fragments and tainted outpoints, kept up to date per block
+    metrics: Option<Metrics>,       // Where progress metrics are written
+    metrics_interval: usize,        // Write a metrics sample every N blocks
+    tracer: Option<TxTracer>,       // Explains the matching of the transactions given with --trace-tx
+    audit: Audit,                   // Conservation checks of every block and transaction
+    partial: bool,                  // The outputs cover only part of the requested blocks
+    damaged: bool                   // A failed block could not be put back, the state is not exported
 }
 
-fn count_accounts(am: &HashMap<TxOutpoint, AddressInfo, BuildHasherDefault<XxHash>>)->usize{
-    return am.iter().fold(0, |a, v| a+if let Some(ref _x) = v.1.tainted_balance{1}else{0});
-}
-
-fn txo_to_string(txo: &TxOutpoint, utxo_set: &HashMap<TxOutpoint, String, BuildHasherDefault<XxHash>>)->String{
-
-    if utxo_set.contains_key(txo){
-        return utxo_set.get(&txo).unwrap().clone();
-    }else{
-        return String::from("");
+/// The command line arguments of `taintFIFO` recorded in the manifest.
+const PARAMETERS: &'static [&'static str] = &[
+    "dump-folder", "address-file", "max-height", "series-interval", "series-daily", "snapshot-at",
+    "export-addresses", "export-tainted-utxos", "output-format", "record-flows", "graph-labels",
+    "graph-max-hops", "graph-min-value", "svg-from", "svg-to", "svg-scale", "checkpoint", "resume-from",
+    "utxo-snapshot", "save-state", "incremental", "undo-depth", "metrics-file", "metrics-format", "metrics-interval",
+    "trace-tx", "trace-file", "audit", "audit-log",
+];
+
+/// First opcode of a null data output, which can never be spent.
+const OP_RETURN: u8 = 0x6a;
+
+impl Outpoint for TxOutpoint {
+    fn describe(&self) -> String {
+        format!("{}:{}", arr_to_hex_swapped(&self.txid), self.index)
     }
-
 }
 
-fn taint_to_sum(vdtp: &Option<VecDeque<TaintPart>>)->u64{
-    if let &Some(ref x) = vdtp{
-        return x.iter().fold(0, |a, v| a+&v.value); 
+/// The block in the transaction model of the engine. The parser only knows
+/// the value of an input that spends an output of the same block, the others
+/// are left to the engine's state.
+fn engine_block(block: &Block, block_height: usize) -> BlockData<TxOutpoint> {
+    let mut created: HashMap<TxOutpoint, u64> = HashMap::new();
+    let mut txs = Vec::with_capacity(block.txs.len());
+    for tx in block.txs.iter() {
+        let coinbase = tx.value.is_coinbase();
+        let inputs = if coinbase {
+            Vec::new()
+        } else {
+            tx.value.inputs.iter().map(|input| {
+                let outpoint = TxOutpoint {txid: input.outpoint.txid, index: input.outpoint.index};
+                let value = created.get(&outpoint).cloned();
+                TxIn {outpoint: outpoint, value: value}
+            }).collect()
+        };
+        let outputs: Vec<TxOut<TxOutpoint>> = tx.value.outputs.iter().enumerate().map(|(i, output)| TxOut {
+            outpoint: TxOutpoint {txid: tx.hash, index: i as u32},
+            address: output.script.address.to_owned(),
+            value: output.out.value,
+            unspendable: output.out.script_pubkey.first() == Some(&OP_RETURN),
+        }).collect();
+        for output in outputs.iter() {
+            created.insert(output.outpoint.clone(), output.value);
+        }
+        txs.push(Transaction {
+            txid: arr_to_hex_swapped(&tx.hash),
+            coinbase: coinbase,
+            inputs: inputs,
+            outputs: outputs,
+        });
     }
-    return 0;
+    BlockData {height: block_height, time: block.header.value.timestamp, txs: txs}
 }
 
-fn non_white_taint_to_sum(vdtp: &VecDeque<TaintPart>)->u64{
-    return vdtp.iter().fold(0, |acc, k| {acc + if k.name!=0{k.value}else{0}})
+/// Passes what the engine does on to the outputs, the graph, the SVG, the
+/// tracer and the audit of `taintFIFO`.
+struct FifoObserver<'a> {
+    height: usize,
+    record_flows: bool,
+    output: &'a mut TaintOutput,
+    graph: Option<&'a mut TaintGraph>,
+    svg: Option<&'a mut TaintSvg>,
+    tracer: Option<&'a mut TxTracer>,
+    audit: &'a mut Audit,
 }
 
-
-fn taint_to_string(vdtp: &Option<VecDeque<TaintPart>>)->String{
-    if let &Some(ref x) = vdtp{
-        return x.iter().fold(String::new(), |a, k| {a+&k.name.to_string() + " " + &k.value.to_string() + ","});
+impl<'a> EngineObserver<TxOutpoint> for FifoObserver<'a> {
+    fn wants_flows(&self) -> bool {
+        self.record_flows || self.graph.is_some()
     }
-    return String::from("");
-}
 
-fn inputs_to_string(vdtp: &Hashed<Tx>)->String{
-        return format!("{},{}",vdtp.value.inputs.len(), vdtp.value.inputs.iter().fold(String::new(), |a, k| {a+&k.outpoint.to_string() + ","}));
-}
+    fn flow(&mut self, flow: &TaintFlow) -> OpResult<()> {
+        if self.record_flows {try!(self.output.write_flow(flow));}
+        if let Some(ref mut graph) = self.graph {graph.add_flow(flow);}
+        Ok(())
+    }
 
+    fn collision(&mut self, overlap: &TaintOverlap) -> OpResult<()> {
+        self.output.write_overlap(overlap)
+    }
 
-fn extract_taint(given_taints: &mut VecDeque<TaintPart>, value: u64)->VecDeque<TaintPart>{
-    let mut remaining = value;
-    let mut new_tainted_balance = VecDeque::new();
-
-    while remaining > 0{
-        if given_taints.is_empty(){
-            new_tainted_balance.push_back(TaintPart{name: 0, value:remaining});
-            assert!(remaining != 0);
-            remaining = 0;
-        }else{
-            let mut ctaint = given_taints.pop_front().unwrap();
-            if remaining >= ctaint.value{
-                assert!(ctaint.value != 0);
-                remaining -= ctaint.value;
-                new_tainted_balance.push_back(ctaint);
-            }else{
-                ctaint.value -= remaining;
-                assert!(remaining != 0, "Remaining balance should not be zero");
-                new_tainted_balance.push_back(TaintPart{name:ctaint.name, value:remaining});
-                given_taints.push_front(ctaint);
-                remaining = 0;
+    fn output(&mut self, tx: &Transaction<TxOutpoint>, index: usize, info: &AddressInfo) -> OpResult<()> {
+        if let (Some(svg), Some(x)) = (self.svg.as_mut(), info.tainted_balance.as_ref()) {
+            if svg.in_range(self.height) && !tx.inputs.is_empty() {
+                let inputs: Vec<String> = tx.inputs.iter().map(|input| arr_to_hex_swapped(&input.outpoint.txid)).collect();
+                svg.add_utxo(self.height, format!("{}:{}", tx.txid, index), info.current_balance, x, &inputs);
             }
         }
+        Ok(())
     }
 
-    if remaining > 0{
-        new_tainted_balance.push_back(TaintPart{name:0, value: remaining});
+    fn spent(&mut self, outpoint: &TxOutpoint) -> OpResult<()> {
+        if let Some(ref mut graph) = self.graph {graph.spend(outpoint);}
+        Ok(())
     }
 
-    return new_tainted_balance;
-}
-
-fn combine_taints(taints: &mut VecDeque<TaintPart>, tainted_balance: &mut VecDeque<TaintPart>, mut writer_to_notify: Option<&mut LineWriter<File>>, collision_name: &String)->u32{
+    fn traces(&self, txid: &str) -> bool {
+        self.tracer.as_ref().map_or(false, |tracer| tracer.traces(txid))
+    }
 
-	let mut new_taint = VecDeque::new();
-    let mut location = 0;
-    let mut number_of_collisions = 0;
+    fn begin_trace(&mut self, txid: &str, block_height: usize) -> OpResult<()> {
+        if let Some(ref mut tracer) = self.tracer {tracer.begin(txid, block_height);}
+        Ok(())
+    }
 
-	while !taints.is_empty() && !tainted_balance.is_empty(){
-		let mut left  = taints.pop_front().unwrap();
-		let mut right = tainted_balance.pop_front().unwrap();
+    fn trace(&mut self, line: &str) -> OpResult<()> {
+        if let Some(ref mut tracer) = self.tracer {tracer.line(line);}
+        Ok(())
+    }
 
-		// If the saved chunk is smaller
-		if left.value <= right.value{
-			//if left.name != 0 && right.name != 0 && left.name != right.name{
-			if left.name != 0 && right.name != 0{
-                number_of_collisions += 1;
-                
-                if let Some(ref mut x) = writer_to_notify{
-                    x.write_all(format!("{},{},{},{},{}\n", left.name, right.name, cmp::min(left.value, right.value), collision_name, location).as_bytes()).unwrap();
-                }
-            }
+    fn describe(&self, sums: &LabelSums) -> String {
+        self.audit.describe(sums)
+    }
 
-			// Left taint always has precedent, but if it is empty we merge it
-			// regardless
-			if left.name == 0 {
-				left.name = right.name;
-			}
-
-			right.value -= left.value;
-            location += left.value;
-
-			if new_taint.is_empty(){
-				assert!(left.value != 0, "Trying to enter zero left");
-				new_taint.push_back(left);
-			}else{
-				let mut nyan = new_taint.pop_back().unwrap();
-				if nyan.name == left.name {
-					nyan.value += left.value;
-					assert!(nyan.value != 0, "The nyan value is zero");
-					new_taint.push_back(nyan);
-				}else{
-					assert!(nyan.value != 0);
-					assert!(left.value != 0);
-					new_taint.push_back(nyan);
-					new_taint.push_back(left);
-				}
-			}
-
-			if right.value > 0{
-				assert!(right.value != 0, "Right somehow became 0");
-				tainted_balance.push_front(right);
-			}
-
-		}else{
-			// if the new chunk is actually smaller
-			// then why dont we break them down into parts 
-			assert!((left.value - right.value)!=0, "Difference is zero");
-			assert!(right.value != 0, "Right is zero!");
-			taints.push_front(TaintPart{name: left.name, value: left.value-right.value});
-			taints.push_front(TaintPart{name: left.name, value: right.value});
-			tainted_balance.push_front(right);
-		}
-	}
-
-    if !tainted_balance.is_empty(){
-        new_taint.append(tainted_balance);
-    }
-
-    if !taints.is_empty(){
-        new_taint.append(taints);
-    }
-
-    taints.append(&mut new_taint);
-
-    return number_of_collisions;
+    fn discrepancy(&mut self, txid: Option<&str>, check: &str, detail: &str) -> OpResult<()> {
+        self.audit.report(txid, check, detail)
+    }
 }
 
-fn reduce_taint(tainted_balance: &mut VecDeque<TaintPart>){
-
-    let mut lname = 0; let mut vl=0;
-
-    let hru = tainted_balance.drain(..).collect::<VecDeque<TaintPart>>();
-
-    for elem in hru.iter() {
-        if elem.name == lname{
-            vl += elem.value;
-        }else{
-            if vl != 0{
-                tainted_balance.push_back(TaintPart{name:lname, value:vl});
+/// Sums the taint held in unspent outputs per label, skipping clean chunks.
+/// Returns label -> (tainted value, number of outputs carrying that label).
+fn taint_per_label(am: &HashMap<TxOutpoint, AddressInfo, BuildHasherDefault<XxHash>>)->BTreeMap<u16, (u64, usize)>{
+    let mut per_label: BTreeMap<u16, (u64, usize)> = BTreeMap::new();
+    for info in am.values(){
+        if let Some(ref x) = info.tainted_balance{
+            let mut seen: HashSet<u16> = HashSet::new();
+            for tp in x.iter().filter(|tp| tp.name != 0){
+                let entry = per_label.entry(tp.name).or_insert((0, 0));
+                entry.0 += tp.value;
+                if seen.insert(tp.name){
+                    entry.1 += 1;
+                }
             }
-            vl=elem.value;lname=elem.name;
         }
     }
+    per_label
+}
 
-    if vl!=0{tainted_balance.push_back(TaintPart{name:lname,value:vl});}
-
-    if tainted_balance.len() == 1{
-        // we check if it just consists of white chunks
-        let jj = tainted_balance.pop_front().unwrap();
-        if jj.name != 0{
-            assert!(jj.value !=0);
-            tainted_balance.push_front(jj);
-        }
-    }
+fn inputs_to_string(vdtp: &Hashed<Tx>)->String{
+        return format!("{},{}",vdtp.value.inputs.len(), vdtp.value.inputs.iter().fold(String::new(), |a, k| {a+&k.outpoint.to_string() + ","}));
 }
 
 
+
+
 impl TaintFifo {
-    fn create_writer(path: PathBuf) -> OpResult<LineWriter<File>> {
-        let file = match OpenOptions::new()
-                  .write(true)
-                  .create(true)
-                  .truncate(true)
-                  .open(&path) {
-            Ok(f) => f,
-            Err(err) => return Err(OpError::from(err)),
+    /// Creates the callback with a flag that stops the run after the current
+    /// block, for drivers that install their own signal handling.
+    pub fn with_interrupt_flag(matches: &ArgMatches, interrupted: Arc<AtomicBool>) -> OpResult<Self> {
+        let ref dump_folder = PathBuf::from(matches.value_of("dump-folder").unwrap());
+        let address_file = matches.value_of("address-file").unwrap();
+        let max_height = value_t!(matches, "max-height", usize).unwrap_or(0);
+        let series_interval = value_t!(matches, "series-interval", usize).unwrap_or(0);
+        let series_daily = matches.is_present("series-daily");
+        let export_addresses = matches.is_present("export-addresses");
+        let export_tainted_utxos = matches.is_present("export-tainted-utxos");
+        let graph = matches.values_of("graph-labels").map(|labels| {
+            TaintGraph::new(labels.map(String::from).collect(),
+                            value_t!(matches, "graph-max-hops", u32).ok(),
+                            value_t!(matches, "graph-min-value", u64).unwrap_or(0))
+        });
+        let svg = match (value_t!(matches, "svg-from", usize), value_t!(matches, "svg-to", usize)) {
+            (Ok(from), Ok(to)) if from <= to => Some(TaintSvg::new(from, to, value_t!(matches, "svg-scale", f64).unwrap_or(10.0))),
+            (Ok(from), Ok(to)) => return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                                                 .join_msg(&format!("--svg-from {} is above --svg-to {}", from, to))),
+            _ => None,
+        };
+        let output_format = try!(OutputFormat::from_name(matches.value_of("output-format").unwrap_or("csv")));
+        let output_tables = OutputTables {
+            series: series_interval > 0 || series_daily,
+            address_balances: export_addresses,
+            tainted_utxos: export_tainted_utxos,
+            flows: matches.is_present("record-flows"),
+            ..OutputTables::core()
         };
-        Ok(LineWriter::new(file))
-    }
 
-    fn export_clusters_to_csv(&mut self) -> OpResult<usize> {
-        
-        for (address, info) in &self.address_mapping {
-            self.address_info_writer
-                .write_all(format!("{},{}\n", address, info).as_bytes())
-                .unwrap();
+        let mut parameters = BTreeMap::new();
+        for name in PARAMETERS.iter() {
+            if let Some(values) = matches.values_of(name) {
+                parameters.insert(String::from(*name), values.collect::<Vec<&str>>().join(","));
+            } else if matches.is_present(name) {
+                parameters.insert(String::from(*name), String::from("true"));
+            }
         }
-        Ok(self.dirtmapper.len())
-    }
-
-    /// Exports UTXO set to a CSV file.
-    fn export_utxo_set_to_csv(&mut self) -> OpResult<usize> {
-        info!(target: "FIFO [export_utxo_set_to_csv]", "Exporting {} UTXOs to CSV...", self.utxo_set.len());
 
-        for (tx_outpoint, address) in self.utxo_set.iter() {
-            self.utxo_writer
-                .write_all(format!("{},{},{}\n",
-                                   arr_to_hex_swapped(&tx_outpoint.txid),
-                                   tx_outpoint.index,
-                                   address)
-                    .as_bytes())
-                .unwrap();
+        let mut snapshot_heights = BTreeSet::new();
+        let mut snapshot_dates = BTreeSet::new();
+        if let Some(points) = matches.values_of("snapshot-at") {
+            for point in points {
+                if let Ok(height) = point.parse::<usize>() {
+                    snapshot_heights.insert(height);
+                } else if let Ok(day) = NaiveDate::parse_from_str(point, "%Y-%m-%d") {
+                    snapshot_dates.insert(day);
+                } else {
+                    return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                               .join_msg(&format!("Invalid --snapshot-at value `{}`, expected a block height or YYYY-MM-DD", point)));
+                }
+            }
         }
+        let incremental = matches.value_of("incremental").map(PathBuf::from);
+        if incremental.is_some() {
+            if output_format != OutputFormat::Csv {
+                return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                           .join_msg("--incremental needs --output-format csv"));
+            }
+            if graph.is_some() {
+                return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                           .join_msg("--graph-labels needs all blocks from the seeds on and cannot be used with --incremental"));
+            }
+        }
+        let metrics = match matches.value_of("metrics-file") {
+            Some(path) => {
+                let format = try!(MetricsFormat::from_name(matches.value_of("metrics-format").unwrap_or("jsonl")));
+                Some(try!(Metrics::new(Path::new(path), format)))
+            },
+            None => None,
+        };
+        let tracer = match matches.values_of("trace-tx") {
+            Some(txids) => {
+                let path = matches.value_of("trace-file").map(PathBuf::from).unwrap_or_else(|| dump_folder.join("trace_tx.txt"));
+                Some(try!(TxTracer::new(&path, txids.map(String::from).collect())))
+            },
+            None => None,
+        };
+        let audit_mode = try!(AuditMode::from_name(matches.value_of("audit").unwrap_or("strict")));
+        let audit_log = matches.value_of("audit-log").map(PathBuf::from).unwrap_or_else(|| dump_folder.join("audit.log"));
+        // A follow-up run can be followed up again
+        let save_state = matches.is_present("save-state") || incremental.is_some();
 
-        info!(target: "FIFO [export_utxo_set_to_csv]", "Exported {} UTXOs to CSV.", self.utxo_set.len());
-        Ok(self.utxo_set.len())
-    }
-
-    /// Renames temporary files.
-    fn rename_tmp_files(&mut self) -> OpResult<usize> {
-        fs::rename(self.dump_folder.as_path().join("taint_mapper.csv.tmp"),
-                   self.dump_folder.as_path().join("taint_mapper.csv"))
-                .expect("Unable to rename taint mapping file");
-        fs::rename(self.dump_folder.as_path().join("taint_overlap.csv.tmp"),
-                   self.dump_folder.as_path().join("taint_overlap.csv"))
-                .expect("Unable to rename ilias file");
-        fs::rename(self.dump_folder.as_path().join("taint_timing_information.csv.tmp"),
-                   self.dump_folder.as_path().join("taint_timing_information.csv"))
-                .expect("Unable to rename taint_clusters.csv.tmp file!");
-        fs::rename(self.dump_folder.as_path().join("taint_utxo.csv.tmp"),
-                   self.dump_folder.as_path().join("taint_utxo.csv"))
-                .expect("Unable to rename taint_utxo.csv.tmp file!");
-        fs::rename(self.dump_folder.as_path().join("address_info.csv.tmp"),
-                   self.dump_folder.as_path().join("address_info.csv"))
-            .expect("Unable to rename address_info.csv.tmp file!");
-
-        Ok(3)
-    }
-}
-
-impl Callback for TaintFifo {
-    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
-        where Self: Sized
-    {
-        SubCommand::with_name("taintFIFO")
-            .about("Taints coins with FIFO.")
-            .version("0.2")
-            .author("Ilia Shumailov <is410@cam.ac.uk>")
-            .arg(Arg::with_name("dump-folder")
-                     .help("Folder where to store the cluster CSV")
-                     .required(true))
-            .arg(Arg::with_name("address-file")
-                     .long("address-file")
-                     .required(true)
-                     .takes_value(true)
-                     .help("File with a list of transactions"))
-            .arg(Arg::with_name("max-height")
-                     .short("m")
-                     .long("max-height")
-                     .takes_value(true)
-                     .help("Stop at a specified block height"))
-    }
-
-    fn new(matches: &ArgMatches) -> OpResult<Self>
-        where Self: Sized
-    {
-        let ref dump_folder = PathBuf::from(matches.value_of("dump-folder").unwrap());
-        let address_file = matches.value_of("address-file").unwrap();
-        let max_height = value_t!(matches, "max-height", usize).unwrap_or(0);
         match (|| -> OpResult<Self> {
             let cb = TaintFifo {
                 dump_folder: PathBuf::from(dump_folder),
                 address_file: address_file.to_owned(),
-                taint_mapping_writer:try!(TaintFifo::create_writer(dump_folder.join("taint_mapper.csv.tmp"))),
-                address_info_writer: try!(TaintFifo::create_writer(dump_folder.join("address_info.csv.tmp"))),
-                utxo_writer: try!(TaintFifo::create_writer(dump_folder.join("taint_utxo.csv.tmp"))),
-                overlap_writer: try!(TaintFifo::create_writer(dump_folder.join("taint_overlap.csv.tmp"))),
+                parameters: parameters,
+                output: try!(output_format.create(dump_folder, output_tables)),
+                output_format: output_format,
+                output_tables: output_tables,
+                graph: graph,
+                svg: svg,
                 utxo_set: Default::default(),
+                addresses: Default::default(),
                 address_mapping: Default::default(),
                 bootstrap_addresses: Default::default(),
                 start_height: 0,
                 end_height: 0,
                 taint_collisions: 0,
                 max_height: max_height,
-                total_taint_for_tx: VecDeque::new(),
+                series_interval: series_interval,
+                series_daily: series_daily,
+                series_last_date: None,
+                snapshot_heights: snapshot_heights,
+                snapshot_dates: snapshot_dates,
+                snapshot_files: Vec::new(),
+                engine: FifoEngine::new(),
                 whitename: String::from("Clean"),
                 dirtmapper: HashMap::new(),
+                interrupted: interrupted,
+                checkpoint: matches.is_present("checkpoint"),
+                resume_from: matches.value_of("resume-from").map(PathBuf::from),
+                utxo_snapshot: matches.value_of("utxo-snapshot").map(PathBuf::from),
+                incremental: incremental,
+                save_state: save_state,
+                series_closing_rows: 0,
+                undo: UndoJournal::new(value_t!(matches, "undo-depth", usize).unwrap_or(0)),
+                counters: StateCounters::default(),
+                metrics: metrics,
+                metrics_interval: value_t!(matches, "metrics-interval", usize).unwrap_or(1000),
+                tracer: tracer,
+                audit: Audit::new(&audit_log, audit_mode),
+                partial: false,
+                damaged: false,
             };
             Ok(cb)
         })() {
@@ -399,352 +399,946 @@ impl Callback for TaintFifo {
         }
     }
 
-    fn on_start(&mut self, _: CoinType, block_height: usize) {
+    fn export_clusters_to_csv(&mut self) -> OpResult<usize> {
+        
+        for (address, info) in sorted_by_outpoint(&self.address_mapping) {
+            try!(self.output.write_address_info(address, info));
+        }
+        Ok(self.dirtmapper.len())
+    }
 
-        self.start_height = block_height;
-        info!(target: "Clusterizer [on_start]", "Using `Clusterizer` with dump folder {:?} and start block {}...",&self.dump_folder, self.start_height);
+    /// Exports UTXO set to a CSV file.
+    fn export_utxo_set_to_csv(&mut self) -> OpResult<usize> {
+        info!(target: "FIFO [export_utxo_set_to_csv]", "Exporting {} UTXOs to CSV...", self.utxo_set.len());
 
-        let path = Path::new(&self.address_file);
+        for (tx_outpoint, id) in sorted_by_outpoint(&self.utxo_set) {
+            try!(self.output.write_utxo(tx_outpoint, self.addresses.get(*id)));
+        }
 
-        self.dirtmapper.insert(self.whitename.clone(), 0); // We need to save the value of clean chunks
-        let mut dirt_val: u16 = 1;             // The value for the newcoming chunk
-
-        let file = File::open(&path).unwrap();
-        for line in BufReader::new(file).lines() {
-            let sline = line.unwrap();
-            let mut spt   = sline.split(",");
-            let addr  = spt.next().unwrap();
-
-            if !self.bootstrap_addresses.contains_key(addr){
-                debug!("Loading the transactions: {}", addr);
-
-                let mut hmt: VecDeque<TaintPart> = VecDeque::new();         
-                let mut tag_v = None;
-
-                for s in spt{
-                    if tag_v == None { tag_v = Some(s);}
-                    else{
-                        let tag = String::from(tag_v.unwrap());
-                        if self.dirtmapper.contains_key(&tag){
-                            hmt.push_back(TaintPart {name: *self.dirtmapper.get(&tag).unwrap(), value: s.parse::<u64>().unwrap()}); 
-                        }else{
-                            self.dirtmapper.insert(tag, dirt_val);
-                            hmt.push_back(TaintPart {name: dirt_val, value: s.parse::<u64>().unwrap()}); 
-                            dirt_val += 1;
-                        }
-                        tag_v = None;
-                    }
+        info!(target: "FIFO [export_utxo_set_to_csv]", "Exported {} UTXOs to CSV.", self.utxo_set.len());
+        Ok(self.utxo_set.len())
+    }
+
+    /// Exports the unspent outputs that carry taint, together with their
+    /// address and per-label taint.
+    fn export_tainted_utxos_to_csv(&mut self) -> OpResult<usize> {
+        let mut written = 0;
+        if self.output_tables.tainted_utxos {
+            info!(target: "FIFO [export_tainted_utxos_to_csv]", "Exporting tainted UTXOs to CSV...");
+            for (tx_outpoint, info) in sorted_by_outpoint(&self.address_mapping) {
+                let tainted_value = match info.tainted_balance {
+                    Some(ref x) => non_white_taint_to_sum(x),
+                    None => continue,
+                };
+                if tainted_value == 0 {
+                    continue;
                 }
-                self.bootstrap_addresses.insert(String::from(addr), hmt);
-               }else{
-                debug!("Address {} is already in the map. Duplicate detected!", addr);
+                let address = match self.utxo_set.get(tx_outpoint) {
+                    Some(id) => self.addresses.get(*id),
+                    None => "",
+                };
+                try!(self.output.write_tainted_utxo(tx_outpoint, address, info));
+                written += 1;
             }
+            info!(target: "FIFO [export_tainted_utxos_to_csv]", "Exported {} tainted UTXOs to CSV.", written);
         }
+        Ok(written)
+    }
 
-        for (tag, mapto) in self.dirtmapper.iter() {
-            self.taint_mapping_writer.write_all(format!("{},{}\n", tag, mapto.to_string()).as_bytes()).unwrap();
+    /// Aggregates the unspent outputs of every address that holds taint and
+    /// exports balance and per-label taint per address.
+    fn export_addresses_to_csv(&mut self) -> OpResult<usize> {
+        if !self.output_tables.address_balances {
+            return Ok(0);
         }
 
+        let mut per_address: BTreeMap<&str, (usize, u64, BTreeMap<u16, u64>)> = BTreeMap::new();
+        for (tx_outpoint, info) in self.address_mapping.iter() {
+            let address = match self.utxo_set.get(tx_outpoint) {
+                Some(id) => self.addresses.get(*id),
+                None => continue,
+            };
+            if address.is_empty() {
+                // Non-standard outputs have no address, they do not belong together
+                continue;
+            }
+            let entry = per_address.entry(address).or_insert((0, 0, BTreeMap::new()));
+            entry.0 += 1;
+            entry.1 += info.current_balance;
+            if let Some(ref x) = info.tainted_balance {
+                for tp in x.iter().filter(|tp| tp.name != 0) {
+                    *entry.2.entry(tp.name).or_insert(0) += tp.value;
+                }
+            }
+        }
+
+        info!(target: "FIFO [export_addresses_to_csv]", "Exporting {} addresses to CSV...", per_address.len());
+        let mut written = 0;
+        for (address, &(outputs, balance, ref taints)) in per_address.iter() {
+            if taints.is_empty() {
+                continue;
+            }
+            try!(self.output.write_address_balance(&AddressBalance {
+                address: address,
+                outputs: outputs,
+                balance: balance,
+                taints: taints,
+            }));
+            written += 1;
+        }
+        info!(target: "FIFO [export_addresses_to_csv]", "Exported {} tainted addresses to CSV.", written);
+        Ok(written)
     }
 
-    fn on_block(&mut self, block: Block, block_height: usize) {
+    /// Appends one sample of the per-label taint held in unspent outputs to the series.
+    fn write_series_sample(&mut self, block_height: usize, date: &str) -> OpResult<usize> {
+        let mut written = 0;
+        if self.output_tables.series {
+            for (label, &(value, outputs)) in taint_per_label(&self.address_mapping).iter() {
+                try!(self.output.write_series_sample(&SeriesSample {
+                    height: block_height,
+                    date: date,
+                    label: *label,
+                    value: value,
+                    outputs: outputs,
+                }));
+                written += 1;
+            }
+        }
+        Ok(written)
+    }
 
-        if self.max_height > 0 && block_height >= self.max_height {
-            debug!(target: "FIFO [on_block]", "Skipping block {} because max-height is set to {}.", block_height, self.max_height);
-            return;
+    /// Records the parameters and heights of the run, along with the fields of
+    /// the manifest that describe the run rather than its output files.
+    fn export_metadata(&mut self) -> OpResult<usize> {
+        let mut metadata = vec![
+            (String::from("version"), String::from("0.2")),
+            (String::from("address_file"), self.address_file.clone()),
+            (String::from("seed_file_sha256"), try!(sha256_file(Path::new(&self.address_file)))),
+            (String::from("start_height"), self.start_height.to_string()),
+            (String::from("end_height"), self.end_height.to_string()),
+            (String::from("max_height"), self.max_height.to_string()),
+            (String::from("taint_collisions"), self.taint_collisions.to_string()),
+            (String::from("partial"), self.partial.to_string()),
+            (String::from("audit_mode"), String::from(self.audit.mode().name())),
+            (String::from("audit_discrepancies"), self.audit.discrepancies().to_string()),
+            (String::from("labels"), self.dirtmapper.len().to_string()),
+        ];
+        for (key, value) in self.parameters.iter() {
+            metadata.push((format!("parameters.{}", key), value.clone()));
+        }
+        if let Some(ref snapshot) = self.utxo_snapshot {
+            metadata.push((String::from("utxo_snapshot"), snapshot.to_string_lossy().into_owned()));
+            metadata.push((String::from("utxo_snapshot_sha256"), try!(sha256_file(snapshot))));
+        }
+        if self.output_tables.tainted_utxos {
+            metadata.push((String::from("tainted_utxo_schema_version"), TAINTED_UTXO_SCHEMA_VERSION.to_string()));
+        }
+        for &(ref key, ref value) in metadata.iter() {
+            try!(self.output.write_metadata(key, value));
         }
+        Ok(metadata.len())
+    }
 
-        if (block_height % 1000) == 0 {
-            let fragments = count_fragments(&self.address_mapping);
-            let acc_num   = count_accounts(&self.address_mapping);
-            info!(target: "FIFO [on_block]", "Progress: block {}, {} UTXOs, {} fragments on {} accounts, collisions: {}, tx left: {}",
-                  block_height, self.utxo_set.len(), fragments, acc_num, self.taint_collisions, self.bootstrap_addresses.len());
+    /// Exports the final state and commits every output of the run together:
+    /// either all of them get their final names, or none does.
+    fn finalize(&mut self) -> OpResult<Vec<PathBuf>> {
+        try!(self.export_clusters_to_csv());
+        try!(self.export_utxo_set_to_csv());
+        try!(self.export_addresses_to_csv());
+        try!(self.export_tainted_utxos_to_csv());
+        try!(self.export_metadata());
+
+        let mut pending = PendingFiles::new();
+        if let Some(ref graph) = self.graph {
+            try!(graph.export(&self.dump_folder, &mut pending));
         }
-        debug!("NEW BLOCK HAS {} TRANSACTIONS", block.txs.len());
+        if let Some(ref svg) = self.svg {
+            try!(svg.write_svg(&self.dump_folder.join("taint_utxos.svg"), &mut pending));
+        }
+        try!(self.output.finish(&mut pending));
+        if self.save_state {
+            try!(self.save_state_to(&self.dump_folder.join("state"), &mut pending));
+        }
+        try!(self.export_manifest(&mut pending));
+
+        let marker = self.dump_folder.join("PARTIAL");
+        if self.partial {
+            let (start, end) = (self.start_height, self.end_height);
+            try!(write_pending(&marker, &mut pending, |w| {
+                try!(writeln!(w, "The run was interrupted, these results only cover blocks {} to {}.", start, end));
+                Ok(())
+            }));
+        }
+        let files = try!(pending.commit());
+        if !self.partial && marker.exists() {
+            // Left over from an interrupted run into the same folder
+            try!(fs::remove_file(&marker));
+        }
+        Ok(files)
+    }
 
-        let mut fees_summed        = 0;
-        let mut all_inputs_summed  = 0;
-        let mut all_outputs_summed = 0;
+    /// Exports the state after `end_height`, the last block that was
+    /// processed, and logs the outcome.
+    fn complete(&mut self) {
+        let block_height = self.end_height;
+        self.record_metrics(block_height);
+        if let Some(ref mut tracer) = self.tracer {
+            tracer.finish();
+        }
+        self.audit.finish();
 
-        let mut miner_output_queue: VecDeque<(TxOutpoint, &EvaluatedTxOut, u64)> = VecDeque::new();
+        if self.output_tables.series {
+            // Close the series with the final state of the run
+            let last_date = self.series_last_date.clone().unwrap_or_default();
+            self.series_closing_rows = self.write_series_sample(block_height, &last_date).unwrap_or(0);
+        }
+        for height in self.snapshot_heights.iter() {
+            warn!(target: "FIFO [complete]", "Snapshot at height {} was never reached.", height);
+        }
+        for day in self.snapshot_dates.iter() {
+            warn!(target: "FIFO [complete]", "Snapshot at the end of {} was never reached.", day.format("%Y-%m-%d"));
+        }
+        match (self.finalize(), self.partial) {
+            (Ok(files), false) => info!(target: "FIFO [complete]", "Done.\nProcessed all {} blocks, committed {} output files\n",
+                                        self.end_height + 1, files.len()),
+            (Ok(files), true) => warn!(target: "FIFO [complete]", "Committed {} output files with the partial results up to block {}.",
+                                       files.len(), self.end_height),
+            (Err(err), _) => error!(target: "FIFO [complete]", "Processed blocks up to {}, but the outputs were not committed: {}",
+                                    self.end_height, err),
+        }
+    }
 
-        for tx in block.txs.iter(){
-            // Finding the miners in here
-            if tx.value.is_coinbase(){
-                for (i, output) in tx.value.outputs.iter().enumerate(){
-                    miner_output_queue.push_back((TxOutpoint{txid: tx.hash, index: i as u32}, &output, output.out.value));
-                    let miner_outpoint = TxOutpoint {txid: tx.hash, index: i as u32};
-                    self.utxo_set.insert(miner_outpoint, output.script.address.to_owned());
-                }
-            }
+    /// Called with the first block after a SIGINT or SIGTERM. The blocks before
+    /// it were processed completely, so their state is exported as a partial
+    /// result, optionally with a checkpoint, and the process exits.
+    fn stop(&mut self, block_height: usize) -> ! {
+        if self.max_height > 0 && block_height >= self.max_height {
+            // Every requested block was processed already
+            self.complete();
+            process::exit(0);
         }
 
-        // The story is. There are actually two types of combination
-        // First, is when we have user defined taint which is easy
-        // Second, the miner one. We have to append taint to them and to do that we need to offset 
-        // Them before combining. 
-        let mut miner_offset_collector: HashMap<TxOutpoint, u64> = HashMap::new();
-
-        for (tx_index, tx) in block.txs.iter().enumerate() {
-            debug!("Tx: {}[I:{} O:{}]", arr_to_hex_swapped(&tx.hash), tx.value.inputs.len(), tx.value.outputs.len());
-            if tx.value.is_coinbase(){continue;}
-
-            if self.bootstrap_addresses.len() > 0{
-                let h = &arr_to_hex_swapped(&tx.hash);
-                let mut to_del = false;
-                if self.bootstrap_addresses.contains_key(h){
-                    let mut assigned_dirt = self.bootstrap_addresses.get_mut(h).unwrap();
-
-                    if assigned_dirt.len() > 0{
-                        for (i, output) in tx.value.outputs.iter().enumerate() {
-                            let tx_outpoint = TxOutpoint{txid:tx.hash,index:i as u32};
-                            let address = output.script.address.to_owned();
-                            
-                            assert!(!self.address_mapping.contains_key(&tx_outpoint));
-
-                            let address_info = AddressInfo{
-                                   timestamp: timestamp_to_date(block.header.value.timestamp as i64),
-                                   current_balance: 0,
-                                   tainted_balance: Some(extract_taint(assigned_dirt, output.out.value))
-                            };  
-
-                            assert_eq!(taint_to_sum(&address_info.tainted_balance), output.out.value); 
-                            debug!("Loading taint for {} : {}", address, taint_to_string(&address_info.tainted_balance));
-
-                            self.address_mapping.insert(tx_outpoint, address_info);
-                        }
-                    }
-                    to_del = true;
-                }
-                if to_del{self.bootstrap_addresses.remove(h);}
-            }
+        warn!(target: "FIFO [stop]", "Interrupted, exporting the state after block {}...", self.end_height);
+        self.complete_partial();
+        process::exit(130);
+    }
 
-            trace!(target: "FIFO [on_block]", "Tx: {} ({}/{}).", arr_to_hex_swapped(&tx.hash), tx_index, block.txs.len());
+    /// Stops the run after an error, such as a table that could not be written.
+    /// The state after `end_height` is exported as a partial result, optionally
+    /// with a checkpoint, unless a failed block could not be put back.
+    fn abort(&mut self, err: OpError) -> ! {
+        if self.damaged {
+            error!(target: "FIFO [abort]", "Stopping without exporting the state: {}", err);
+            process::exit(1);
+        }
+        error!(target: "FIFO [abort]", "Stopping after block {}: {}", self.end_height, err);
+        self.complete_partial();
+        process::exit(1);
+    }
+
+    /// Exports the state after `end_height` as a partial result and writes the
+    /// checkpoint, if one was asked for.
+    fn complete_partial(&mut self) {
+        self.partial = true;
+        self.complete();
+        if self.checkpoint {
+            match self.write_checkpoint() {
+                Ok(folder) => info!(target: "FIFO [stop]", "Wrote a checkpoint, continue from block {} with --resume-from {}",
+                                    self.end_height + 1, folder.display()),
+                Err(err) => error!(target: "FIFO [stop]", "Unable to write the checkpoint: {}", err),
+            }
+        }
+    }
 
-            assert!(self.total_taint_for_tx.is_empty(), "A check to see that no taint is being lost from the previous transaction[{}]:{}", self.total_taint_for_tx.len(), taint_to_string(&Some(self.total_taint_for_tx.clone())));
+    /// Saves everything needed to continue the run with `--resume-from` to
+    /// `checkpoint/` inside the dump folder.
+    fn write_checkpoint(&self) -> OpResult<PathBuf> {
+        let folder = self.dump_folder.join("checkpoint");
+        let mut pending = PendingFiles::new();
+        try!(self.save_state_to(&folder, &mut pending));
+        try!(pending.commit());
+        Ok(folder)
+    }
 
+    /// Writes the state after `end_height` into `folder` as pending files.
+    fn save_state_to(&self, folder: &Path, pending: &mut PendingFiles) -> OpResult<()> {
+        let state = RunState {
+            start_height: self.start_height,
+            end_height: self.end_height,
+            taint_collisions: self.taint_collisions,
+            address_file: self.address_file.clone(),
+            address_file_sha256: try!(sha256_file(Path::new(&self.address_file))),
+            series_last_date: self.series_last_date.clone(),
+            series_closing_rows: self.series_closing_rows,
+            snapshot_files: self.snapshot_files.clone(),
+            partial: self.partial,
+        };
+        write_state(folder, &state, &self.labels_by_id(), &self.address_mapping, &self.utxo_set, &self.addresses,
+                    &self.bootstrap_addresses, pending)
+    }
 
-            let mut outputs_summed = 0; // overall outputs value
-            let mut inputs_summed  = 0;
-            let mut miner_fee      = 0; // In here we calculate how much the miner gotten in the transaction
+    /// Writes a metrics sample, if a metrics file was asked for.
+    fn record_metrics(&mut self, block_height: usize) {
+        let sample = MetricsSample {
+            height: block_height,
+            utxos: self.utxo_set.len(),
+            outpoints: self.address_mapping.len(),
+            addresses: self.addresses.len(),
+            fragments: self.counters.fragments,
+            tainted_outputs: self.counters.tainted_outputs,
+            collisions: self.taint_collisions,
+            seeds_left: self.bootstrap_addresses.len(),
+        };
+        if let Some(ref mut metrics) = self.metrics {
+            if let Err(err) = metrics.record(&sample) {
+                warn!(target: "FIFO [record_metrics]", "Unable to write the metrics: {}", err);
+            }
+        }
+    }
 
-            for (i, output) in tx.value.outputs.iter().enumerate() {
-                let tx_outpoint = TxOutpoint {txid: tx.hash, index: i as u32};
-                let address = output.script.address.to_owned();
-                self.utxo_set.insert(tx_outpoint, address);
-                outputs_summed += output.out.value;
+    /// Records the state of every outpoint `block` creates or spends, and the
+    /// seeds it consumes, before the block is processed.
+    fn block_undo(&self, block: &BlockData<TxOutpoint>, touched: &[TxOutpoint], series_last_date: Option<String>) -> BlockUndo {
+        let mut outpoints = Vec::new();
+        for outpoint in touched.iter() {
+            let info = self.address_mapping.get(outpoint).cloned();
+            let address = self.utxo_set.get(outpoint).map(|id| String::from(self.addresses.get(*id)));
+            outpoints.push((outpoint.clone(), info, address));
+        }
+        let mut seeds = Vec::new();
+        for tx in block.txs.iter() {
+            if let Some(seed) = self.bootstrap_addresses.get(&tx.txid) {
+                seeds.push((tx.txid.clone(), seed.clone()));
             }
+        }
+        BlockUndo {
+            height: block.height,
+            outpoints: outpoints,
+            seeds: seeds,
+            taint_collisions: self.taint_collisions,
+            series_last_date: series_last_date,
+        }
+    }
 
-            for (i, input) in tx.value.inputs.iter().enumerate() {
-                let tx_outpoint = TxOutpoint {txid:input.outpoint.txid,index:input.outpoint.index};
-
-                match self.address_mapping.get_mut(&tx_outpoint) {
-                    Some(address_info)=>{
-                        inputs_summed += address_info.current_balance;
-                        debug!("\t{} INP: {} | BAL: {} | T: {} | SUM: {}", i, txo_to_string(&tx_outpoint, &self.utxo_set), address_info.current_balance, taint_to_string(&address_info.tainted_balance), inputs_summed);
-
-                        let mut nullify = false;
-                        if let Some(ref x) = address_info.tainted_balance{
-                            // Push the taints if they exist
-                            for tt in x.iter(){
-                                assert!(tt.value != 0);
-                                self.total_taint_for_tx.push_back(tt.to_owned());
-                            }
-                            nullify = true;
-                        }else{
-                            // No taint is found means that there is full White taint
-                            // If the address has only white taint associated, that means
-                            // that it is clean and we will not save this value at all to
-                            // conserve space
-                            
-                            if address_info.current_balance > 0 {
-                                self.total_taint_for_tx.push_back(TaintPart{name:0, value: address_info.current_balance});
-                            }
-                        }
-                        if nullify {address_info.tainted_balance = None;}
-                        address_info.current_balance = 0;
-                    },
-                    None=>{
-                        assert!(false);
-                    }
-                };
+    /// Puts the state back as it was before a block that could not be
+    /// processed, so that the partial result ends with the block before it.
+    /// The counters of the touched outpoints are put back with them.
+    fn undo_block(&mut self, undo: BlockUndo, touched: &[TxOutpoint]) {
+        self.taint_collisions = undo.taint_collisions;
+        self.series_last_date = undo.series_last_date.clone();
+        undo.restore(&mut self.address_mapping, &mut self.utxo_set, &mut self.addresses, &mut self.bootstrap_addresses);
+        for outpoint in touched.iter() {
+            if let Some(info) = self.address_mapping.get(outpoint) {
+                self.counters.add(info);
             }
+        }
+    }
 
-            let tx_inp_taint_sum = non_white_taint_to_sum(&self.total_taint_for_tx);
-            let mut tx_out_taint_sum = 0;
-            assert!(inputs_summed >= outputs_summed, "Sum of inp {}; Sum of out {}", inputs_summed, outputs_summed);
-            miner_fee = inputs_summed - outputs_summed; 
-            fees_summed += miner_fee;
-
-            // The last value is the offset that needs to be appended to the taint.
-            // We need to do this because of the way we do taint propagation to the miners
-            let mut hul: Vec<(EvaluatedTxOut, TxOutpoint, u64)> = tx.value.outputs.iter().enumerate().map(|(ind, v)| {(v.clone(), TxOutpoint{txid: tx.hash, index: ind as u32}, 0)}).collect();
-
-            if miner_fee > 0{
-                let mut left_to_payoff = miner_fee; 
-                while left_to_payoff > 0{
-                    assert!(!miner_output_queue.is_empty(), "Oops, no more miners left to pay off, but we still need to pay off {}", left_to_payoff);
-                    let mtup     = miner_output_queue.pop_front().unwrap();
-                    let mut ctxo = mtup.0; // The TxOutpoint
-                    let mut cout = mtup.1;  // The Output
-                    let mut val  = mtup.2;
-                    let mut etx  = None;
-
-                    if val > left_to_payoff{
-                        etx = Some(EvaluatedTxOut{script:cout.script.clone(), out:TxOutput{value: left_to_payoff, script_len: cout.out.script_len.clone(), script_pubkey: cout.out.script_pubkey.clone()}});
-                        miner_output_queue.push_front((ctxo.clone(), cout, val-left_to_payoff));
-                        left_to_payoff = 0;
-                    }else{
-                        etx = Some(EvaluatedTxOut{script: cout.script.clone(), out: TxOutput{value: val, script_len: cout.out.script_len.clone(), script_pubkey: cout.out.script_pubkey.clone()}});
-                        left_to_payoff -= val;
-                    }
+    /// The state of an unspent outpoint, clean ones included.
+    pub fn outpoint_state(&self, outpoint: &TxOutpoint) -> Option<&AddressInfo> {
+        self.address_mapping.get(outpoint)
+    }
 
-                    if let Some(xxx)=etx{
-                        let mut offset = 0;
-                        if miner_offset_collector.contains_key(&ctxo){
-                            offset += miner_offset_collector.get(&ctxo).unwrap();
-                        }
-                        hul.push((xxx.clone(), ctxo.clone(), offset));
-                        miner_offset_collector.insert(ctxo, offset + xxx.out.value);
-                    }
-                }
-            }
+    pub fn taint_collisions(&self) -> u32 {
+        self.taint_collisions
+    }
 
-            for (output, tx_outpoint, taint_offset) in hul {
+    pub fn audit_discrepancies(&self) -> usize {
+        self.audit.discrepancies()
+    }
 
-                let mut add_bal: u64 = 0;
-                assert!(output.out.value <= (outputs_summed+miner_fee), "output.out.value <= outputs_summed+miner_fee | {}<={}", output.out.value, outputs_summed);
+    /// A copy of the state that `rollback` restores.
+    pub fn state_view(&self) -> StateView {
+        StateView::new(&self.address_mapping, &self.utxo_set, &self.addresses, &self.bootstrap_addresses, self.taint_collisions)
+    }
 
-                let mut tainted_balance: VecDeque<TaintPart> = extract_taint(&mut self.total_taint_for_tx, output.out.value); 
-                assert_eq!(taint_to_sum(&Some(tainted_balance.clone())), output.out.value); 
+    /// Loads the seeds, a UTXO snapshot or a saved state before `block_height`.
+    pub fn start(&mut self, block_height: usize) -> OpResult<()> {
 
-                match self.address_mapping.get_mut(&tx_outpoint){
-                    Some(address_info)=>{
-                        add_bal += address_info.current_balance;
-                        assert_eq!(add_bal, taint_offset);
+        self.end_height = block_height.saturating_sub(1);
+        self.start_height = block_height;
+        info!(target: "Clusterizer [on_start]", "Using `Clusterizer` with dump folder {:?} and start block {}...",&self.dump_folder, self.start_height);
 
-                        if let Some(ref mut x) = address_info.tainted_balance{
-                            if taint_offset > 0{tainted_balance.push_front(TaintPart{name:0,value:taint_offset});}
-                            self.taint_collisions += combine_taints(&mut tainted_balance, x, Some(&mut self.overlap_writer), &arr_to_hex_swapped(&tx.hash));
-                        }else{
-                            tainted_balance.push_front(TaintPart{name:0, value:add_bal});
-                        }
-                    },
-                    None=>{}
-                };
- 
-                reduce_taint(&mut tainted_balance); 
+        self.dirtmapper.insert(self.whitename.clone(), 0); // We need to save the value of clean chunks
 
-                let info = AddressInfo {
-                    timestamp:timestamp_to_date(block.header.value.timestamp as i64),
-                    current_balance: output.out.value + add_bal, 
-                    tainted_balance: if !tainted_balance.is_empty(){Some(tainted_balance.clone())}else{None},
-                };
+        try!(match (self.incremental.clone(), self.resume_from.clone(), self.utxo_snapshot.clone()) {
+            (Some(previous), _, _) => self.follow_up(&previous, block_height),
+            (None, Some(folder), _) => self.resume(&folder, block_height),
+            (None, None, snapshot) => {
+                let path = PathBuf::from(&self.address_file);
+                self.load_seeds(&path).and_then(|_| match snapshot {
+                    Some(snapshot) => self.import_utxos(&snapshot, block_height).map(|_| ()),
+                    None => Ok(()),
+                })
+            },
+        });
+        self.counters = StateCounters::count(&self.address_mapping);
+
+        for (mapto, tag) in self.labels_by_id() {
+            try!(self.output.write_label(&tag, mapto));
+        }
 
-                if taint_offset > 0{
-                    debug!("\tMINER OUT: {} | VAL: {} | TO: {} | T: {}", output.script.address, output.out.value, taint_offset, taint_to_string(&info.tainted_balance));
-                }else{
-                    debug!("\t      OUT: {} | VAL: {} | TO: {} | T: {}", output.script.address, output.out.value, taint_offset, taint_to_string(&info.tainted_balance));
-                    tx_out_taint_sum += non_white_taint_to_sum(&tainted_balance);
+        if let Some(ref mut graph) = self.graph {
+            for name in graph.resolve_labels(&self.dirtmapper) {
+                warn!(target: "FIFO [start]", "Graph label `{}` does not appear in the address file.", name);
+            }
+        }
+        if let Some(ref mut svg) = self.svg {
+            svg.set_labels(&self.dirtmapper);
+        }
+        let labels = self.labels_by_id();
+        if let Some(ref mut tracer) = self.tracer {
+            tracer.write_labels(&labels);
+        }
+        self.audit.set_labels(&labels);
+        Ok(())
+    }
+
+    /// Processes one block. On an error the state is left after the last
+    /// block that was processed completely, `end_height`, if the undo journal
+    /// is enabled. Without it a block that failed midway stays half processed.
+    pub fn process(&mut self, block: Block, block_height: usize) -> OpResult<()> {
+
+        let date_before = self.series_last_date.clone();
+        // Block times are not monotonic, a block may be up to two hours older
+        // than one before it. The series goes by the latest time seen so far.
+        let block_date = timestamp_to_date(block.header.value.timestamp as i64);
+        let latest_date = match self.series_last_date.clone() {
+            Some(last_date) => {
+                if last_date >= block_date {
+                    last_date
+                } else {
+                    // A later day of block time closes the previous one, so we
+                    // sample the state as it was after the block before.
+                    if self.series_daily && last_date[..8] != block_date[..8] {
+                        try!(self.write_series_sample(block_height.saturating_sub(1), &last_date));
+                    }
+                    block_date
                 }
+            },
+            None => block_date,
+        };
 
-                self.address_mapping.insert(tx_outpoint, info);
+        // Same for snapshots, a day is complete once the latest block time is on a later day
+        if let Ok(latest_day) = NaiveDate::parse_from_str(&latest_date[..8], "%Y%m%d") {
+            let due_days: Vec<NaiveDate> = self.snapshot_dates.iter().filter(|day| **day < latest_day).cloned().collect();
+            for day in due_days {
+                try!(self.write_snapshot(&format!("date-{}", day.format("%Y-%m-%d")), block_height.saturating_sub(1)));
+                self.snapshot_dates.remove(&day);
+            }
+        }
+        self.series_last_date = Some(latest_date.clone());
+
+        let block_data = engine_block(&block, block_height);
+        let touched = touched_outpoints(&block_data);
+        // The undo record is only kept with the journal, it copies every outpoint the block touches
+        let undo = if self.undo.is_enabled() {
+            Some(self.block_undo(&block_data, &touched, date_before))
+        } else {
+            None
+        };
+        self.audit.begin_block(block_height, arr_to_hex_swapped(&block.header.hash));
+        // The counters are taken out for the outpoints of this block and put back once it is done
+        for outpoint in touched.iter() {
+            if let Some(info) = self.address_mapping.get(outpoint) {
+                self.counters.remove(info);
             }
+        }
+
+        if (block_height % 1000) == 0 {
+            info!(target: "FIFO [process]", "Progress: block {}, {} UTXOs, {} fragments on {} accounts, collisions: {}, tx left: {}",
+                  block_height, self.utxo_set.len(), self.counters.fragments, self.counters.tainted_outputs, self.taint_collisions, self.bootstrap_addresses.len());
+        }
+        debug!("NEW BLOCK HAS {} TRANSACTIONS", block.txs.len());
 
-            for mt in miner_offset_collector.iter(){
-                let mut mtox = mt.0;
-                
-                match self.address_mapping.get(&mtox){
-                    Some(address_info)=>{
-                        if let Some(ref x) = address_info.tainted_balance{
-                            tx_out_taint_sum += non_white_taint_to_sum(&x);
-                        }
+        for tx in block_data.txs.iter() {
+            for output in tx.outputs.iter() {
+                insert_utxo(&mut self.utxo_set, &mut self.addresses, output.outpoint.clone(), &output.address);
+            }
+        }
+
+        let processed = {
+            let mut observer = FifoObserver {
+                height: block_height,
+                record_flows: self.output_tables.flows,
+                output: &mut *self.output,
+                graph: self.graph.as_mut(),
+                svg: self.svg.as_mut(),
+                tracer: self.tracer.as_mut(),
+                audit: &mut self.audit,
+            };
+            self.engine.process_block(&block_data, &mut self.address_mapping, &mut self.bootstrap_addresses, &mut observer)
+        };
+        let outcome = match processed.and_then(|outcome| self.audit.end_block(&outcome.ledger).map(|_| outcome)) {
+            Ok(outcome) => outcome,
+            Err(err) => {
+                // A strict audit failure or an output that could not be written,
+                // the results end with the block before
+                match undo {
+                    Some(undo) => self.undo_block(undo, &touched),
+                    None => {
+                        self.damaged = true;
+                        return Err(err.join_msg(&format!("Block {} is half processed, run with --undo-depth 1 to put it back and export the blocks before it",
+                                                         block_height)));
                     },
-                    None=>{assert!(false);}
-                };
+                }
+                return Err(err);
+            },
+        };
+        for tx in block_data.txs.iter() {
+            for input in tx.inputs.iter() {
+                remove_utxo(&mut self.utxo_set, &mut self.addresses, &input.outpoint);
             }
+        }
+        self.taint_collisions += outcome.collisions;
 
-            assert!(tx_inp_taint_sum <= tx_out_taint_sum, "Sum(inp_taint) <= Sum(output_taint) | {} <= {}", tx_inp_taint_sum, tx_out_taint_sum);
-            
-            for input in &tx.value.inputs {
-                let tx_outpoint=TxOutpoint{txid:input.outpoint.txid,index:input.outpoint.index};
-                       self.utxo_set.remove(&tx_outpoint);
-                self.address_mapping.remove(&tx_outpoint);
+        for outpoint in touched.iter() {
+            if let Some(info) = self.address_mapping.get(outpoint) {
+                self.counters.add(info);
             }
+        }
+        if let Some(undo) = undo {
+            self.undo.push(undo);
+        }
+        self.end_height = block_height;
+        if self.series_interval > 0 && (block_height % self.series_interval) == 0 {
+            try!(self.write_series_sample(block_height, &latest_date));
+        }
+        if self.snapshot_heights.remove(&block_height) {
+            try!(self.write_snapshot(&format!("height-{}", block_height), block_height));
+        }
+        if self.metrics_interval > 0 && (block_height % self.metrics_interval) == 0 {
+            self.record_metrics(block_height);
+        }
+        debug!("BLOCK END");
+        Ok(())
+    }
 
-            all_inputs_summed  += inputs_summed;
-            all_outputs_summed += outputs_summed;
+    /// Rolls the state back by the last `blocks` blocks, e.g. when they were
+    /// orphaned by a reorg, and returns the height of the new tip. Rows that
+    /// were already streamed to the output tables are not taken back.
+    pub fn rollback(&mut self, blocks: usize) -> OpResult<usize> {
+        let tip = match self.undo.tip() {
+            Some(tip) if blocks <= self.undo.len() => tip,
+            _ => return Err(OpError::new(OpErrorKind::RuntimeError)
+                            .join_msg(&format!("Cannot roll back {} blocks, the undo journal holds {}", blocks, self.undo.len()))),
+        };
+        for _ in 0..blocks {
+            let undo = self.undo.pop().unwrap();
+            self.taint_collisions = undo.taint_collisions;
+            self.series_last_date = undo.series_last_date.clone();
+            undo.restore(&mut self.address_mapping, &mut self.utxo_set, &mut self.addresses, &mut self.bootstrap_addresses);
         }
+        self.counters = StateCounters::count(&self.address_mapping);
+        self.end_height = tip - blocks;
+        info!(target: "FIFO [rollback]", "Rolled back {} blocks, the tip is now block {}.", blocks, tip - blocks);
+        Ok(tip - blocks)
+    }
 
-        assert!((all_inputs_summed - all_outputs_summed - fees_summed) == 0);
+    /// Replaces the last `depth` blocks with `branch`, the blocks of the new
+    /// chain after the fork point, and returns the height of the new tip.
+    pub fn reorg(&mut self, depth: usize, branch: Vec<Block>) -> OpResult<usize> {
+        let mut height = try!(self.rollback(depth));
+        for block in branch {
+            height += 1;
+            try!(self.process(block, height));
+        }
+        Ok(height)
+    }
 
-        while !miner_output_queue.is_empty(){
-            let mtup     = miner_output_queue.pop_front().unwrap();
-            let mut ctxo = mtup.0; // The TxOutpoint
-            let mut val  = mtup.2;
+    /// Continues the run saved with `--save-state` in `previous`: its state
+    /// replaces the address file and its streamed tables are copied ahead of
+    /// the new rows, so the outputs match one run over all the blocks.
+    fn follow_up(&mut self, previous: &Path, block_height: usize) -> OpResult<()> {
+        let folder = previous.join("state");
+        let state = try!(RunState::load(&folder));
+        if state.address_file_sha256 != try!(sha256_file(Path::new(&self.address_file))) {
+            return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                       .join_msg(&format!("The address file changed since the run in {}, seeds in the blocks it already processed would be missed",
+                                          previous.display())));
+        }
+        if let Some(ref svg) = self.svg {
+            if !svg.starts_after(state.end_height) {
+                return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                           .join_msg(&format!("The SVG of a follow-up run has to start after block {}", state.end_height)));
+            }
+        }
+        try!(self.resume(&folder, block_height));
+        let copied = try!(self.output.carry_over(previous, state.series_closing_rows));
+        info!(target: "FIFO [follow_up]", "Continuing the run in {} from block {}, copied {} rows of its streamed tables.",
+              previous.display(), block_height, copied);
+        Ok(())
+    }
 
-            let rew = val; 
-            let mut found = false;
+    /// Reads the seeds of an address file, giving new taint names the next free ids.
+    fn load_seeds(&mut self, path: &Path) -> OpResult<usize> {
+        for seed in try!(read_seeds(path)) {
+            if self.bootstrap_addresses.contains_key(&seed.txid) {
+                debug!("Address {} is already in the map. Duplicate detected!", seed.txid);
+                continue;
+            }
+            debug!("Loading the transactions: {}", seed.txid);
+            let mut hmt: VecDeque<TaintPart> = VecDeque::new();
+            for (tag, value) in seed.taint {
+                hmt.push_back(TaintPart {name: self.label_id(tag), value: value});
+            }
+            self.bootstrap_addresses.insert(seed.txid, hmt);
+        }
+        Ok(self.bootstrap_addresses.len())
+    }
 
-            match self.address_mapping.get_mut(&ctxo){
-                Some(address_info) => {
-                    address_info.current_balance += rew; 
-                    let mut destroyme = false;
+    /// The id of a taint name, giving new names the next free id.
+    fn label_id(&mut self, tag: String) -> u16 {
+        if let Some(id) = self.dirtmapper.get(&tag) {
+            return *id;
+        }
+        let dirt_val = self.dirtmapper.values().max().map(|id| id + 1).unwrap_or(1); // The value for the newcoming chunk
+        self.dirtmapper.insert(tag, dirt_val);
+        dirt_val
+    }
 
-                    if address_info.tainted_balance != None{
-                        assert_eq!(address_info.current_balance-rew, taint_to_sum(&address_info.tainted_balance));
-                    }
+    /// Starts from an imported UTXO set instead of the empty one at genesis.
+    /// Value not covered by the given taint is clean and comes after it.
+    fn import_utxos(&mut self, path: &Path, block_height: usize) -> OpResult<usize> {
+        info!(target: "FIFO [import_utxos]", "Importing the UTXO set from {}...", path.display());
+        let mut tainted = 0;
+        let imported = try!(read_utxo_snapshot(path, block_height, |utxo| {
+            let mut taint = TaintVec::new();
+            for (name, amount) in utxo.taint {
+                if amount > 0 {
+                    taint.push(TaintPart {name: self.label_id(name), value: amount});
+                }
+            }
+            let clean = utxo.value - taint_to_sum(taint.iter());
+            if !taint.is_empty() {
+                if clean > 0 {
+                    taint.push(TaintPart {name: 0, value: clean});
+                }
+                reduce_taint(&mut taint);
+            }
+            if !taint.is_empty() {
+                tainted += 1;
+            }
+            self.address_mapping.insert(utxo.outpoint.clone(), AddressInfo {
+                time: 0, // Not known
+                current_balance: utxo.value,
+                tainted_balance: to_stored(taint),
+            });
+            insert_utxo(&mut self.utxo_set, &mut self.addresses, utxo.outpoint, &utxo.address);
+            Ok(())
+        }));
+        info!(target: "FIFO [import_utxos]", "Imported {} UTXOs, {} of them tainted.", imported, tainted);
+        Ok(imported)
+    }
 
-                    if let Some(ref mut x) = address_info.tainted_balance{
-                        x.push_back(TaintPart{name: 0, value: rew});
-                        reduce_taint(x);
-                        if x.is_empty(){
-                            destroyme = true;
-                        }
-                    }
+    /// Continues from a checkpoint: its outpoints, UTXO set, taint ids and the
+    /// seeds not reached yet replace the address file.
+    fn resume(&mut self, folder: &Path, block_height: usize) -> OpResult<()> {
+        let state = try!(RunState::load(folder));
+        if state.end_height + 1 != block_height {
+            return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                       .join_msg(&format!("The checkpoint in {} ends at block {}, the parser has to start at block {} instead of {}",
+                                          folder.display(), state.end_height, state.end_height + 1, block_height)));
+        }
+        if state.address_file != self.address_file {
+            warn!(target: "FIFO [resume]", "The checkpoint was written for the address file {}, not {}.", state.address_file, self.address_file);
+        }
+        let labels = try!(load_state(folder, &mut self.address_mapping, &mut self.utxo_set, &mut self.addresses));
+        self.dirtmapper.extend(labels);
+        try!(self.load_seeds(&folder.join(PENDING_SEEDS_FILE)));
+
+        self.start_height = state.start_height;
+        self.taint_collisions = state.taint_collisions;
+        self.series_last_date = state.series_last_date;
+        self.snapshot_files = state.snapshot_files;
+        info!(target: "FIFO [resume]", "Resumed after block {} with {} outpoints, {} UTXOs and {} seeds left.",
+              state.end_height, self.address_mapping.len(), self.utxo_set.len(), self.bootstrap_addresses.len());
+        Ok(())
+    }
 
-                    if destroyme{
-                        address_info.tainted_balance = None;
-                    }
-                    debug!("Miner found {} has new balance of {}", txo_to_string(&ctxo, &self.utxo_set), address_info.current_balance);
-                     
-                    found = true;
-                },
-                None=>{}
-            };
+    /// The taint names ordered by their id.
+    pub fn labels_by_id(&self) -> BTreeMap<u16, String> {
+        self.dirtmapper.iter().map(|(name, id)| (*id, name.clone())).collect()
+    }
 
-            if !found{
-                let info = AddressInfo {
-                    timestamp:timestamp_to_date(block.header.value.timestamp as i64),
-                    current_balance: rew, 
-                    tainted_balance: None, 
-                };
+    /// Writes `manifest.json`: what the run was given, what it covered and a
+    /// checksum of every pending output and of the snapshots committed during
+    /// the run, so that a result can be reproduced and verified. It is
+    /// committed together with the outputs it describes.
+    fn export_manifest(&self, pending: &mut PendingFiles) -> OpResult<usize> {
+        let mut outputs: Vec<(PathBuf, PathBuf)> = pending.files().to_vec();
+        for name in self.snapshot_files.iter() {
+            let path = self.dump_folder.join(name);
+            outputs.push((path.clone(), path));
+        }
+        outputs.sort_by(|a, b| a.1.cmp(&b.1));
+        info!(target: "FIFO [export_manifest]", "Hashing {} output files...", outputs.len());
+        let mut files = Vec::new();
+        for &(ref tmp, ref path) in outputs.iter() {
+            let name = path.strip_prefix(&self.dump_folder).unwrap_or(path).to_string_lossy().into_owned();
+            let mut file = BTreeMap::new();
+            file.insert(String::from("file"), Json::String(name));
+            file.insert(String::from("bytes"), Json::U64(try!(fs::metadata(tmp)).len()));
+            file.insert(String::from("sha256"), Json::String(try!(sha256_file(tmp))));
+            files.push(Json::Object(file));
+        }
 
-                debug!("Miner not found {} has new balance of {}", txo_to_string(&ctxo, &self.utxo_set), info.current_balance);
+        let mut seed_file = BTreeMap::new();
+        seed_file.insert(String::from("path"), Json::String(self.address_file.clone()));
+        seed_file.insert(String::from("sha256"), Json::String(try!(sha256_file(Path::new(&self.address_file)))));
+
+        let labels = self.labels_by_id().into_iter().map(|(id, name)| {
+            let mut label = BTreeMap::new();
+            label.insert(String::from("id"), Json::U64(id as u64));
+            label.insert(String::from("name"), Json::String(name));
+            Json::Object(label)
+        }).collect();
+
+        let mut manifest = BTreeMap::new();
+        manifest.insert(String::from("version"), Json::String(String::from("0.2")));
+        manifest.insert(String::from("parameters"), Json::Object(
+            self.parameters.iter().map(|(k, v)| (k.clone(), Json::String(v.clone()))).collect()));
+        manifest.insert(String::from("start_height"), Json::U64(self.start_height as u64));
+        manifest.insert(String::from("end_height"), Json::U64(self.end_height as u64));
+        manifest.insert(String::from("taint_collisions"), Json::U64(self.taint_collisions as u64));
+        manifest.insert(String::from("partial"), Json::Boolean(self.partial));
+        manifest.insert(String::from("audit_mode"), Json::String(String::from(self.audit.mode().name())));
+        manifest.insert(String::from("audit_discrepancies"), Json::U64(self.audit.discrepancies() as u64));
+        manifest.insert(String::from("seed_file"), Json::Object(seed_file));
+        if let Some(ref snapshot) = self.utxo_snapshot {
+            let mut utxo_snapshot = BTreeMap::new();
+            utxo_snapshot.insert(String::from("path"), Json::String(snapshot.to_string_lossy().into_owned()));
+            utxo_snapshot.insert(String::from("sha256"), Json::String(try!(sha256_file(snapshot))));
+            utxo_snapshot.insert(String::from("height"), Json::U64(self.start_height.saturating_sub(1) as u64));
+            manifest.insert(String::from("utxo_snapshot"), Json::Object(utxo_snapshot));
+        }
+        if self.output_tables.tainted_utxos {
+            manifest.insert(String::from("tainted_utxo_schema_version"), Json::U64(TAINTED_UTXO_SCHEMA_VERSION as u64));
+        }
+        manifest.insert(String::from("labels"), Json::Array(labels));
+        manifest.insert(String::from("outputs"), Json::Array(files));
+
+        try!(write_pending(&self.dump_folder.join("manifest.json"), pending, |w| {
+            try!(w.write_all(format!("{}\n", json::as_pretty_json(&Json::Object(manifest))).as_bytes()));
+            Ok(())
+        }));
+        Ok(outputs.len())
+    }
 
-                self.address_mapping.insert(ctxo, info); 
+    /// Writes the taint labels and every tainted outpoint in the `address_info`
+    /// format to `snapshots/<name>/` inside the dump folder.
+    fn write_snapshot(&mut self, name: &str, block_height: usize) -> OpResult<usize> {
+        let folder = self.dump_folder.join("snapshots").join(name);
+        try!(fs::create_dir_all(&folder));
+
+        let mut output = try!(self.output_format.create(&folder, OutputTables::snapshot()));
+        for (mapto, tag) in self.labels_by_id() {
+            try!(output.write_label(&tag, mapto));
+        }
+        let mut written = 0;
+        for (address, info) in sorted_by_outpoint(&self.address_mapping).into_iter().filter(|&(_, info)| info.tainted_balance.is_some()) {
+            try!(output.write_address_info(address, info));
+            written += 1;
+        }
+        let mut pending = PendingFiles::new();
+        try!(output.finish(&mut pending));
+        for path in try!(pending.commit()) {
+            let name = path.strip_prefix(&self.dump_folder).map(Path::to_path_buf).unwrap_or(path);
+            if !self.snapshot_files.contains(&name) {
+                self.snapshot_files.push(name);
             }
         }
 
-        assert!(self.total_taint_for_tx.is_empty());
-        debug!("BLOCK END");
+        info!(target: "FIFO [write_snapshot]", "Wrote snapshot {} after block {} with {} tainted outpoints.", name, block_height, written);
+        Ok(written)
     }
+}
 
-    fn on_complete(&mut self, block_height: usize) {
-        self.end_height = block_height;
+impl Callback for TaintFifo {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+        where Self: Sized
+    {
+        SubCommand::with_name("taintFIFO")
+            .about("Taints coins with FIFO.")
+            .version("0.2")
+            .author("Ilia Shumailov <is410@cam.ac.uk>")
+            .arg(Arg::with_name("dump-folder")
+                     .help("Folder where to store the cluster CSV")
+                     .required(true))
+            .arg(Arg::with_name("address-file")
+                     .long("address-file")
+                     .required(true)
+                     .takes_value(true)
+                     .help("File with a list of transactions"))
+            .arg(Arg::with_name("max-height")
+                     .short("m")
+                     .long("max-height")
+                     .takes_value(true)
+                     .help("Stop at a specified block height"))
+            .arg(Arg::with_name("series-interval")
+                     .long("series-interval")
+                     .takes_value(true)
+                     .help("Sample the per-label tainted UTXO value every N blocks"))
+            .arg(Arg::with_name("series-daily")
+                     .long("series-daily")
+                     .help("Sample the per-label tainted UTXO value once per day of block time"))
+            .arg(Arg::with_name("snapshot-at")
+                     .long("snapshot-at")
+                     .takes_value(true)
+                     .multiple(true)
+                     .use_delimiter(true)
+                     .help("Dump every tainted outpoint after the given block heights or once the latest block time passes the given days (YYYY-MM-DD)"))
+            .arg(Arg::with_name("export-addresses")
+                     .long("export-addresses")
+                     .help("Also export balance and taint aggregated per address"))
+            .arg(Arg::with_name("export-tainted-utxos")
+                     .long("export-tainted-utxos")
+                     .help("Also export the tainted UTXOs with their address, value and taint"))
+            .arg(Arg::with_name("output-format")
+                     .long("output-format")
+                     .takes_value(true)
+                     .possible_values(&["csv", "parquet", "sqlite"])
+                     .default_value("csv")
+                     .help("File format of the result tables"))
+            .arg(Arg::with_name("record-flows")
+                     .long("record-flows")
+                     .help("Record every movement of taint between transactions and outpoints"))
+            .arg(Arg::with_name("graph-labels")
+                     .long("graph-labels")
+                     .takes_value(true)
+                     .multiple(true)
+                     .use_delimiter(true)
+                     .help("Export the transaction graph reached by these taint names as GraphML, DOT and Neo4j CSV"))
+            .arg(Arg::with_name("graph-max-hops")
+                     .long("graph-max-hops")
+                     .takes_value(true)
+                     .help("Leave out transactions more than N hops away from the seeds (default: no limit)"))
+            .arg(Arg::with_name("graph-min-value")
+                     .long("graph-min-value")
+                     .takes_value(true)
+                     .help("Leave out edges carrying less than this many satoshi of the selected taint"))
+            .arg(Arg::with_name("svg-from")
+                     .long("svg-from")
+                     .takes_value(true)
+                     .requires("svg-to")
+                     .help("Draw the tainted outputs created from this height on to taint_utxos.svg"))
+            .arg(Arg::with_name("svg-to")
+                     .long("svg-to")
+                     .takes_value(true)
+                     .requires("svg-from")
+                     .help("Last height drawn to taint_utxos.svg"))
+            .arg(Arg::with_name("svg-scale")
+                     .long("svg-scale")
+                     .takes_value(true)
+                     .help("Height in pixels of one BTC of taint in taint_utxos.svg (default: 10)"))
+            .arg(Arg::with_name("checkpoint")
+                     .long("checkpoint")
+                     .help("When interrupted, also write a checkpoint that --resume-from can continue from"))
+            .arg(Arg::with_name("resume-from")
+                     .long("resume-from")
+                     .takes_value(true)
+                     .help("Continue from a checkpoint folder instead of starting with the address file"))
+            .arg(Arg::with_name("utxo-snapshot")
+                     .long("utxo-snapshot")
+                     .takes_value(true)
+                     .conflicts_with("resume-from")
+                     .help("Start from this UTXO set (`# height <n>`, then txid:index,value,address[,taint name,amount...]) instead of an empty one"))
+            .arg(Arg::with_name("save-state")
+                     .long("save-state")
+                     .help("Save the end state to state/ in the dump folder, so that --incremental can follow the run up"))
+            .arg(Arg::with_name("incremental")
+                     .long("incremental")
+                     .takes_value(true)
+                     .conflicts_with_all(&["resume-from", "utxo-snapshot"])
+                     .help("Process only the blocks after the run saved with --save-state in this dump folder"))
+            .arg(Arg::with_name("undo-depth")
+                     .long("undo-depth")
+                     .takes_value(true)
+                     .help("Keep an undo journal of the last N blocks, so that a reorg can be rolled back (default: 0, off)"))
+            .arg(Arg::with_name("metrics-file")
+                     .long("metrics-file")
+                     .takes_value(true)
+                     .help("Write progress metrics to this file"))
+            .arg(Arg::with_name("metrics-format")
+                     .long("metrics-format")
+                     .takes_value(true)
+                     .possible_values(&["jsonl", "prometheus"])
+                     .default_value("jsonl")
+                     .help("Append JSON lines, or replace a Prometheus text file with every sample"))
+            .arg(Arg::with_name("metrics-interval")
+                     .long("metrics-interval")
+                     .takes_value(true)
+                     .help("Write a metrics sample every N blocks (default: 1000)"))
+            .arg(Arg::with_name("trace-tx")
+                     .long("trace-tx")
+                     .takes_value(true)
+                     .multiple(true)
+                     .use_delimiter(true)
+                     .help("Explain step by step how the taint of these transactions is matched"))
+            .arg(Arg::with_name("trace-file")
+                     .long("trace-file")
+                     .takes_value(true)
+                     .requires("trace-tx")
+                     .help("Where to write the explanations of --trace-tx (default: trace_tx.txt in the dump folder)"))
+            .arg(Arg::with_name("audit")
+                     .long("audit")
+                     .takes_value(true)
+                     .possible_values(&["strict", "lenient"])
+                     .default_value("strict")
+                     .help("Stop at the first taint conservation discrepancy, or log it and carry on"))
+            .arg(Arg::with_name("audit-log")
+                     .long("audit-log")
+                     .takes_value(true)
+                     .help("Where to write the audit discrepancies (default: audit.log in the dump folder)"))
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+        where Self: Sized
+    {
+        // The handler only raises a flag, the current block is always finished
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let flag = interrupted.clone();
+        try!(ctrlc::set_handler(move || {
+            // A second signal does not wait any more
+            if flag.swap(true, AtomicOrdering::SeqCst) {
+                process::exit(130);
+            }
+        }).map_err(|err| OpError::new(OpErrorKind::RuntimeError)
+                       .join_msg(&format!("Unable to install the signal handler: {}", err))));
 
-        let _ = self.export_clusters_to_csv();
-        let _ = self.export_utxo_set_to_csv();
-        let _ = self.rename_tmp_files();
-        info!(target: "FIFO [on_complete]", "Done.\nProcessed all {} blocks\n",
-             self.end_height + 1);
+        TaintFifo::with_interrupt_flag(matches, interrupted)
     }
-}
 
-fn timestamp_to_date(timestamp: i64) -> String {
-    let naive_datetime = NaiveDateTime::from_timestamp(timestamp, 0);
-    let datetime_again: DateTime<Utc> = DateTime::from_utc(naive_datetime, Utc);
-    return datetime_again.format("%Y%m%d-%H%M%S").to_string()
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        if let Err(err) = self.start(block_height) {
+            error!(target: "FIFO [on_start]", "{}", err);
+            process::exit(1);
+        }
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+
+        if self.interrupted.load(AtomicOrdering::SeqCst) {
+            self.stop(block_height);
+        }
+
+        if self.max_height > 0 && block_height >= self.max_height {
+            debug!(target: "FIFO [on_block]", "Skipping block {} because max-height is set to {}.", block_height, self.max_height);
+            return;
+        }
+
+        if let Err(err) = self.process(block, block_height) {
+            // A strict audit failure or an output that could not be written,
+            // the results end with the last complete block
+            self.abort(err);
+        }
+    }
+
+    fn on_complete(&mut self, _: usize) {
+        // With --max-height the parser's last block may not have been processed
+        self.complete();
+    }
 }
 
 
@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::{Path, PathBuf};
+
+use errors::{OpError, OpErrorKind, OpResult};
+
+use callbacks::taint_engine::{LabelSums, BlockLedger};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditMode {
+    Strict,  // Stop the run at the first discrepancy
+    Lenient, // Log it and carry on
+}
+
+impl AuditMode {
+    pub fn from_name(name: &str) -> OpResult<Self> {
+        match name {
+            "strict" => Ok(AuditMode::Strict),
+            "lenient" => Ok(AuditMode::Lenient),
+            _ => Err(OpError::new(OpErrorKind::InvalidArgsError)
+                         .join_msg(&format!("Unknown audit mode `{}`", name))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AuditMode::Strict => "strict",
+            AuditMode::Lenient => "lenient",
+        }
+    }
+}
+
+/// Checks that no taint is created or lost unaccounted, and writes every
+/// discrepancy with its context to the audit log.
+pub struct Audit {
+    mode: AuditMode,
+    path: PathBuf,
+    writer: Option<LineWriter<File>>, // Created with the first discrepancy
+    labels: BTreeMap<u16, String>,
+    height: usize,
+    block_hash: String,
+    discrepancies: usize,
+}
+
+impl Audit {
+    pub fn new(path: &Path, mode: AuditMode) -> Self {
+        Audit {
+            mode: mode,
+            path: path.to_path_buf(),
+            writer: None,
+            labels: BTreeMap::new(),
+            height: 0,
+            block_hash: String::new(),
+            discrepancies: 0,
+        }
+    }
+
+    pub fn mode(&self) -> AuditMode {
+        self.mode
+    }
+
+    pub fn discrepancies(&self) -> usize {
+        self.discrepancies
+    }
+
+    pub fn set_labels(&mut self, labels: &BTreeMap<u16, String>) {
+        self.labels = labels.clone();
+    }
+
+    pub fn begin_block(&mut self, height: usize, block_hash: String) {
+        self.height = height;
+        self.block_hash = block_hash;
+    }
+
+    fn label(&self, name: u16) -> String {
+        match self.labels.get(&name) {
+            Some(label) => format!("{} ({})", label, name),
+            None => format!("{}", name),
+        }
+    }
+
+    /// Taint per label with the label names, for the detail of a discrepancy.
+    pub fn describe(&self, sums: &LabelSums) -> String {
+        let parts: Vec<String> = sums.iter().map(|(name, value)| format!("{} {}", self.label(*name), value)).collect();
+        parts.join(", ")
+    }
+
+    /// Reports a failed check of transaction `txid`, or of the whole block.
+    /// In strict mode it is returned as an error, for the caller to stop the run.
+    pub fn report(&mut self, txid: Option<&str>, check: &str, detail: &str) -> OpResult<()> {
+        self.discrepancies += 1;
+        let line = format!("block {} {} tx {}: {}: {}", self.height, self.block_hash, txid.unwrap_or("-"), check, detail);
+        warn!(target: "FIFO [audit]", "{}", line);
+
+        if self.writer.is_none() {
+            match File::create(&self.path) {
+                Ok(file) => self.writer = Some(LineWriter::new(file)),
+                Err(err) => error!(target: "FIFO [audit]", "Unable to create {}: {}", self.path.display(), err),
+            }
+        }
+        if let Some(ref mut writer) = self.writer {
+            if let Err(err) = writeln!(writer, "{}", line) {
+                error!(target: "FIFO [audit]", "Unable to write to {}: {}", self.path.display(), err);
+            }
+        }
+
+        if self.mode == AuditMode::Strict {
+            if let Some(ref mut writer) = self.writer {
+                let _ = writer.flush();
+            }
+            return Err(OpError::new(OpErrorKind::RuntimeError)
+                       .join_msg(&format!("{} in block {} in strict audit mode, see {}", check, self.height, self.path.display())));
+        }
+        Ok(())
+    }
+
+    /// Checks the conservation of every label over the block just processed.
+    pub fn end_block(&mut self, ledger: &BlockLedger) -> OpResult<()> {
+        for (name, came, went) in ledger.imbalances() {
+            let get = |sums: &LabelSums| sums.get(&name).cloned().unwrap_or(0);
+            let detail = format!("label {} held before {} + seeded {} = {}, but held after {} + burned {} + dropped {} + paid to miners {} = {}",
+                                 self.label(name), get(&ledger.held_before), get(&ledger.seeded), came,
+                                 get(&ledger.held_after), get(&ledger.burned), get(&ledger.dropped),
+                                 get(&ledger.miners), went);
+            try!(self.report(None, "taint not conserved", &detail));
+        }
+        Ok(())
+    }
+
+    pub fn finish(&mut self) {
+        if self.discrepancies > 0 {
+            warn!(target: "FIFO [audit]", "{} audit discrepancies, see {}.", self.discrepancies, self.path.display());
+        }
+        if let Some(ref mut writer) = self.writer {
+            let _ = writer.flush();
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, App, SubCommand};
+
+use errors::OpResult;
+
+use callbacks::taint_output::{write_file, csv_escape};
+use callbacks::taint_results::{load_labels, read_address_info, read_overlaps};
+
+/// The tainted end state of one run, with labels resolved to their names.
+struct RunState {
+    outpoints: HashMap<String, BTreeMap<String, u64>>, // outpoint -> name -> amount
+    remaining: BTreeMap<String, (u64, usize)>,         // name -> (amount, outputs)
+    overlaps: BTreeMap<(String, String), (usize, u64)>, // (kept, lost) -> (collisions, amount)
+}
+
+impl RunState {
+    fn load(folder: &Path) -> OpResult<Self> {
+        // Numeric ids depend on the order of the seed file, names do not
+        let names: BTreeMap<u16, String> = try!(load_labels(folder)).into_iter().map(|(name, id)| (id, name)).collect();
+        let name_of = |label: u16| names.get(&label).cloned().unwrap_or_else(|| format!("#{}", label));
+
+        info!(target: "taintCompare", "Reading {}...", folder.display());
+        let mut outpoints = HashMap::new();
+        let mut remaining = BTreeMap::new();
+        try!(read_address_info(folder, |record| {
+            let mut per_name: BTreeMap<String, u64> = BTreeMap::new();
+            for &(label, amount) in record.taint.iter().filter(|&&(label, _)| label != 0) {
+                *per_name.entry(name_of(label)).or_insert(0) += amount;
+            }
+            if per_name.is_empty() {
+                return;
+            }
+            for (name, amount) in per_name.iter() {
+                let entry = remaining.entry(name.clone()).or_insert((0, 0));
+                entry.0 += *amount;
+                entry.1 += 1;
+            }
+            outpoints.insert(record.outpoint, per_name);
+        }));
+
+        let mut overlaps = BTreeMap::new();
+        if folder.join("taint_overlap.csv").exists() {
+            try!(read_overlaps(folder, |overlap| {
+                let entry = overlaps.entry((name_of(overlap.left), name_of(overlap.right))).or_insert((0, 0));
+                entry.0 += 1;
+                entry.1 += overlap.value;
+            }));
+        }
+
+        Ok(RunState {
+            outpoints: outpoints,
+            remaining: remaining,
+            overlaps: overlaps,
+        })
+    }
+}
+
+fn signed(before: u64, after: u64) -> String {
+    if after >= before {
+        format!("+{}", after - before)
+    } else {
+        format!("-{}", before - after)
+    }
+}
+
+fn amounts_to_string(amounts: &BTreeMap<String, u64>) -> String {
+    let parts: Vec<String> = amounts.iter().map(|(name, amount)| format!("{} {}", name, amount)).collect();
+    parts.join(";")
+}
+
+pub fn build_subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("taintCompare")
+        .about("Shows what changed between the dump folders of two runs.")
+        .version("0.2")
+        .arg(Arg::with_name("before")
+                 .help("Dump folder of the first run")
+                 .required(true))
+        .arg(Arg::with_name("after")
+                 .help("Dump folder of the second run")
+                 .required(true))
+        .arg(Arg::with_name("outpoints")
+                 .long("outpoints")
+                 .takes_value(true)
+                 .help("Write every new, vanished and changed tainted outpoint to this CSV file"))
+}
+
+pub fn run(matches: &ArgMatches) -> OpResult<()> {
+    let before_folder = PathBuf::from(matches.value_of("before").unwrap());
+    let after_folder = PathBuf::from(matches.value_of("after").unwrap());
+    let before = try!(RunState::load(&before_folder));
+    let after = try!(RunState::load(&after_folder));
+
+    let mut new = 0;
+    let mut vanished = 0;
+    let mut changed = 0;
+    let mut changes: Vec<(&str, &String)> = Vec::new(); // (kind, outpoint)
+    for (outpoint, amounts) in after.outpoints.iter() {
+        match before.outpoints.get(outpoint) {
+            None => { new += 1; changes.push(("new", outpoint)); },
+            Some(old) if old != amounts => { changed += 1; changes.push(("changed", outpoint)); },
+            Some(_) => {},
+        }
+    }
+    for outpoint in before.outpoints.keys().filter(|o| !after.outpoints.contains_key(*o)) {
+        vanished += 1;
+        changes.push(("vanished", outpoint));
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    try!(writeln!(out, "Comparing {} to {}\n", before_folder.display(), after_folder.display()));
+
+    try!(writeln!(out, "Remaining taint in unspent outputs (satoshi, outputs):"));
+    let names: BTreeSet<&String> = before.remaining.keys().chain(after.remaining.keys()).collect();
+    for name in names {
+        let (b_value, b_outputs) = before.remaining.get(name).cloned().unwrap_or((0, 0));
+        let (a_value, a_outputs) = after.remaining.get(name).cloned().unwrap_or((0, 0));
+        let mark = if !before.remaining.contains_key(name) {
+            "  (only in the second run)"
+        } else if !after.remaining.contains_key(name) {
+            "  (only in the first run)"
+        } else {
+            ""
+        };
+        try!(writeln!(out, "  {}: {} -> {} ({}), {} -> {} outputs ({}){}",
+                      name, b_value, a_value, signed(b_value, a_value),
+                      b_outputs, a_outputs, signed(b_outputs as u64, a_outputs as u64), mark));
+    }
+
+    try!(writeln!(out, "\nTainted outpoints: {} new, {} vanished, {} with changed taint", new, vanished, changed));
+
+    try!(writeln!(out, "\nCollisions (kept over lost: collisions, amount):"));
+    let pairs: BTreeSet<&(String, String)> = before.overlaps.keys().chain(after.overlaps.keys()).collect();
+    let mut unchanged = 0;
+    for pair in pairs {
+        let (b_count, b_value) = before.overlaps.get(pair).cloned().unwrap_or((0, 0));
+        let (a_count, a_value) = after.overlaps.get(pair).cloned().unwrap_or((0, 0));
+        if b_count == a_count && b_value == a_value {
+            unchanged += 1;
+            continue;
+        }
+        try!(writeln!(out, "  {} over {}: {} -> {} ({}), {} -> {} ({})",
+                      pair.0, pair.1, b_count, a_count, signed(b_count as u64, a_count as u64),
+                      b_value, a_value, signed(b_value, a_value)));
+    }
+    try!(writeln!(out, "  {} pairs unchanged", unchanged));
+
+    if let Some(path) = matches.value_of("outpoints") {
+        changes.sort_by(|a, b| a.1.cmp(b.1).then(a.0.cmp(b.0)));
+        let empty = BTreeMap::new();
+        try!(write_file(&PathBuf::from(path), |w| {
+            try!(writeln!(w, "change,outpoint,before,after"));
+            for &(kind, outpoint) in changes.iter() {
+                let old = before.outpoints.get(outpoint).unwrap_or(&empty);
+                let new = after.outpoints.get(outpoint).unwrap_or(&empty);
+                try!(writeln!(w, "{},{},{},{}", kind, outpoint,
+                              csv_escape(&amounts_to_string(old)), csv_escape(&amounts_to_string(new))));
+            }
+            Ok(())
+        }));
+        info!(target: "taintCompare", "Wrote {} outpoints to {}.", changes.len(), path);
+    }
+    Ok(())
+}
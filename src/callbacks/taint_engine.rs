@@ -0,0 +1,911 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::str::FromStr;
+
+extern crate chrono;
+use self::chrono::*;
+
+extern crate smallvec;
+use self::smallvec::SmallVec;
+
+use errors::{OpError, OpErrorKind, OpResult};
+
+#[derive(PartialEq, Eq, Hash, Default, Debug, RustcDecodable, RustcEncodable, Clone)]
+pub struct TaintPart {
+    pub name : u16,
+    pub value: u64
+}
+
+/// The taint of one outpoint in FIFO order. Most outpoints carry one or two
+/// fragments, which are kept inline instead of in a separate allocation.
+pub type TaintVec = SmallVec<[TaintPart; 2]>;
+
+#[derive(PartialEq, Eq, Default, Hash, Debug, Clone)]
+pub struct AddressInfo {
+    pub time: u32,              // Block time of the output, formatted only on export
+    pub current_balance: u64,
+    pub tainted_balance: Option<TaintVec>
+}
+
+impl AddressInfo {
+    /// The block time as `%Y%m%d-%H%M%S`, the format of the exports.
+    pub fn timestamp(&self) -> String {
+        timestamp_to_date(self.time as i64)
+    }
+
+    /// The taint fragments, none for a clean outpoint.
+    pub fn taint(&self) -> &[TaintPart] {
+        match self.tainted_balance {
+            Some(ref x) => x,
+            None => &[],
+        }
+    }
+}
+
+impl fmt::Display for AddressInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let yo:String = self.taint().iter().fold(String::new(), |acc, k| { acc + &k.name.to_string() + " " + &k.value.to_string() + ","});
+
+        write!(f, "{},{},{}",
+               self.timestamp(), self.current_balance, yo)
+    }
+}
+
+/// Keeps the taint of an outpoint; an empty taint means the outpoint is clean.
+pub fn to_stored(taint: TaintVec) -> Option<TaintVec> {
+    if taint.is_empty() { None } else { Some(taint) }
+}
+
+pub fn timestamp_to_date(timestamp: i64) -> String {
+    let naive_datetime = NaiveDateTime::from_timestamp(timestamp, 0);
+    let datetime_again: DateTime<Utc> = DateTime::from_utc(naive_datetime, Utc);
+    return datetime_again.format("%Y%m%d-%H%M%S").to_string()
+}
+
+/// Reads a time written by `timestamp_to_date` back, 0 if it is not one.
+pub fn date_to_timestamp(date: &str) -> u32 {
+    NaiveDateTime::parse_from_str(date, "%Y%m%d-%H%M%S").map(|t| t.timestamp() as u32).unwrap_or(0)
+}
+
+pub fn taint_to_sum<'a, I: IntoIterator<Item = &'a TaintPart>>(vdtp: I)->u64{
+    return vdtp.into_iter().fold(0, |a, v| a+&v.value);
+}
+
+pub fn non_white_taint_to_sum<'a, I: IntoIterator<Item = &'a TaintPart>>(vdtp: I)->u64{
+    return vdtp.into_iter().fold(0, |acc, k| {acc + if k.name!=0{k.value}else{0}})
+}
+
+pub fn taint_to_string<'a, I: IntoIterator<Item = &'a TaintPart>>(vdtp: I)->String{
+    return vdtp.into_iter().fold(String::new(), |a, k| {a+&k.name.to_string() + " " + &k.value.to_string() + ","});
+}
+
+/// Takes `value` off the front of `given_taints` and appends it to
+/// `extracted`. What the pool cannot cover is clean.
+pub fn extract_taint(given_taints: &mut VecDeque<TaintPart>, value: u64, extracted: &mut TaintVec){
+    let mut remaining = value;
+
+    while remaining > 0{
+        match given_taints.pop_front(){
+            Some(mut ctaint) => {
+                if ctaint.value == 0 {
+                    // Nothing to take, the callers report empty fragments where they enter
+                    continue;
+                }
+                if remaining >= ctaint.value{
+                    remaining -= ctaint.value;
+                    extracted.push(ctaint);
+                }else{
+                    ctaint.value -= remaining;
+                    extracted.push(TaintPart{name:ctaint.name, value:remaining});
+                    given_taints.push_front(ctaint);
+                    remaining = 0;
+                }
+            },
+            None => {
+                extracted.push(TaintPart{name: 0, value:remaining});
+                remaining = 0;
+            },
+        }
+    }
+}
+
+/// Appends a chunk, merged into the last one if it has the same name.
+fn push_merged(taint: &mut TaintVec, name: u16, value: u64){
+    if value == 0 {
+        return;
+    }
+    if let Some(last) = taint.last_mut(){
+        if last.name == name {
+            last.value += value;
+            return;
+        }
+    }
+    taint.push(TaintPart{name: name, value: value});
+}
+
+/// Lays `taints` over `held` chunk by chunk into `combined`, `taints` wins
+/// where both are tainted. Every such overlap is a collision, passed to
+/// `on_collision` and counted. What is longer than the other is appended.
+pub fn combine_taints(taints: &[TaintPart], held: &[TaintPart], combined: &mut TaintVec, mut on_collision: Option<&mut FnMut(&TaintOverlap) -> OpResult<()>>, collision_name: &str)->OpResult<u32>{
+
+    // Both sides are walked at once, each step covers the overlap of their current chunks
+    let (mut left_index, mut right_index) = (0, 0);
+    let (mut left_used, mut right_used) = (0, 0);
+    let mut location = 0;
+    let mut number_of_collisions = 0;
+
+    while left_index < taints.len() && right_index < held.len(){
+        let left  = &taints[left_index];
+        let right = &held[right_index];
+        let value = cmp::min(left.value - left_used, right.value - right_used);
+
+        if value > 0 && left.name != 0 && right.name != 0{
+            number_of_collisions += 1;
+
+            if let Some(ref mut notify) = on_collision{
+                try!(notify(&TaintOverlap {
+                    left: left.name,
+                    right: right.name,
+                    value: value,
+                    txid: collision_name,
+                    location: location,
+                }));
+            }
+        }
+
+        // Left taint always has precedent, but if it is empty we merge it
+        // regardless
+        push_merged(combined, if left.name == 0 {right.name} else {left.name}, value);
+        location += value;
+
+        left_used += value;
+        right_used += value;
+        if left_used == left.value{
+            left_index += 1;
+            left_used = 0;
+        }
+        if right_used == right.value{
+            right_index += 1;
+            right_used = 0;
+        }
+    }
+
+    for &(rest, index, used) in [(held, right_index, right_used), (taints, left_index, left_used)].iter(){
+        if index < rest.len(){
+            combined.push(TaintPart{name: rest[index].name, value: rest[index].value - used});
+            combined.extend(rest[index + 1..].iter().cloned());
+        }
+    }
+
+    Ok(number_of_collisions)
+}
+
+/// Merges neighbouring chunks of the same name and drops empty ones. A taint
+/// that is only clean is emptied, as clean outpoints keep no taint.
+pub fn reduce_taint(tainted_balance: &mut TaintVec){
+
+    let mut kept = 0;
+    for i in 0..tainted_balance.len() {
+        let elem = tainted_balance[i].clone();
+        if elem.value == 0 {
+            continue;
+        }
+        if kept > 0 && tainted_balance[kept - 1].name == elem.name {
+            tainted_balance[kept - 1].value += elem.value;
+        }else{
+            tainted_balance[kept] = elem;
+            kept += 1;
+        }
+    }
+    tainted_balance.truncate(kept);
+
+    if tainted_balance.len() == 1 && tainted_balance[0].name == 0 {
+        // we check if it just consists of white chunks
+        tainted_balance.clear();
+    }
+}
+
+/// A taint collision found while combining the taint of an output.
+pub struct TaintOverlap<'a> {
+    pub left: u16,      // The taint that was kept
+    pub right: u16,     // The taint that got overwritten
+    pub value: u64,     // Size of the overlap
+    pub txid: &'a str,  // Transaction in which it happened
+    pub location: u64,  // Offset of the overlap inside the output
+}
+
+/// How taint moved between a transaction and an outpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlowKind {
+    Seed,   // The outpoint was tainted by the seed file
+    Input,  // The outpoint was spent by the transaction
+    Output, // The transaction created the outpoint
+    Fee,    // The transaction paid part of its fee to the coinbase outpoint
+}
+
+impl FlowKind {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            FlowKind::Seed => "seed",
+            FlowKind::Input => "in",
+            FlowKind::Output => "out",
+            FlowKind::Fee => "fee",
+        }
+    }
+
+}
+
+impl FromStr for FlowKind {
+    type Err = OpError;
+
+    fn from_str(kind: &str) -> OpResult<Self> {
+        match kind {
+            "seed" => Ok(FlowKind::Seed),
+            "in" => Ok(FlowKind::Input),
+            "out" => Ok(FlowKind::Output),
+            "fee" => Ok(FlowKind::Fee),
+            _ => Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(&format!("Unknown flow kind `{}`", kind))),
+        }
+    }
+}
+
+/// Taint carried along one edge of the transaction graph.
+pub struct Flow<'a, K: 'a> {
+    pub height: usize,
+    pub txid: &'a str,
+    pub kind: FlowKind,
+    pub outpoint: &'a K,
+    pub value: u64,                     // Value moved along the edge
+    pub taint: &'a [TaintPart],         // Its taint, in FIFO order
+}
+
+/// Identifies an output of a transaction in the model of the caller, e.g. a
+/// txid and an index.
+pub trait Outpoint: Clone + Eq + Hash {
+    /// How the outpoint is written in traces, discrepancies and the log.
+    fn describe(&self) -> String;
+}
+
+/// An input of a transaction.
+#[derive(Clone, Debug)]
+pub struct TxIn<K> {
+    pub outpoint: K,
+    pub value: Option<u64>, // Value of the spent output as the block source gives it, None when it does not know it
+}
+
+#[derive(Clone, Debug)]
+pub struct TxOut<K> {
+    pub outpoint: K,
+    pub address: String,
+    pub value: u64,
+    pub unspendable: bool, // Nobody can ever spend it, e.g. an OP_RETURN output
+}
+
+#[derive(Clone, Debug)]
+pub struct Transaction<K> {
+    pub txid: String,   // As written in the seed file
+    pub coinbase: bool,
+    pub inputs: Vec<TxIn<K>>, // Empty for a coinbase
+    pub outputs: Vec<TxOut<K>>,
+}
+
+/// A block in the order its transactions were mined, the coinbase first.
+#[derive(Clone, Debug)]
+pub struct BlockData<K> {
+    pub height: usize,
+    pub time: u32,
+    pub txs: Vec<Transaction<K>>,
+}
+
+/// Every outpoint a block creates or spends, the only ones whose state it can change.
+pub fn touched_outpoints<K: Outpoint>(block: &BlockData<K>) -> Vec<K> {
+    let mut seen = HashSet::new();
+    let mut outpoints = Vec::new();
+    for tx in block.txs.iter() {
+        let created = tx.outputs.iter().map(|output| &output.outpoint);
+        let spent = tx.inputs.iter().map(|input| &input.outpoint);
+        for outpoint in created.chain(spent) {
+            if seen.insert(outpoint.clone()) {
+                outpoints.push(outpoint.clone());
+            }
+        }
+    }
+    outpoints
+}
+
+/// Taint fragments as `[label:amount ...]`, in FIFO order.
+pub fn parts_to_string<'a, I: IntoIterator<Item = &'a TaintPart>>(parts: I) -> String {
+    let parts: Vec<String> = parts.into_iter().map(|tp| format!("{}:{}", tp.name, tp.value)).collect();
+    format!("[{}]", parts.join(" "))
+}
+
+/// Tainted value per label, clean chunks left out.
+pub type LabelSums = BTreeMap<u16, u64>;
+
+pub fn add_label_sums<'a, I: IntoIterator<Item = &'a TaintPart>>(sums: &mut LabelSums, taint: I) {
+    for tp in taint.into_iter().filter(|tp| tp.name != 0) {
+        *sums.entry(tp.name).or_insert(0) += tp.value;
+    }
+}
+
+/// Adds what `before` has over `after`, per label, to `dropped`. A label that
+/// grew is not a drop, the block balance shows it.
+pub fn add_lost(dropped: &mut LabelSums, before: &LabelSums, after: &LabelSums) {
+    for (name, value) in before.iter() {
+        let left = after.get(name).cloned().unwrap_or(0);
+        if *value > left {
+            *dropped.entry(*name).or_insert(0) += *value - left;
+        }
+    }
+}
+
+/// Where the taint of each label went in one block. Per label,
+/// `seeded - burned - dropped` must equal the change of the held taint plus
+/// the taint paid to the miners.
+#[derive(Default)]
+pub struct BlockLedger {
+    pub seeded: LabelSums,      // Taint of the seeds consumed by the block
+    pub burned: LabelSums,      // Taint sent to provably unspendable outputs
+    pub dropped: LabelSums,     // Taint lost to collisions, unassigned seeds and leftovers
+    pub held_before: LabelSums, // Taint of the outpoints the block touches, before it
+    pub held_after: LabelSums,  // The same outpoints after it, without burns and coinbase outputs
+    pub miners: LabelSums,      // Taint of the coinbase outputs of the block
+}
+
+impl BlockLedger {
+    /// Labels that do not balance, with `held_before + seeded` and
+    /// `held_after + burned + dropped + miners`.
+    pub fn imbalances(&self) -> Vec<(u16, u64, u64)> {
+        let mut names: Vec<u16> = Vec::new();
+        for sums in [&self.seeded, &self.burned, &self.dropped, &self.held_before, &self.held_after, &self.miners].iter() {
+            names.extend(sums.keys().cloned());
+        }
+        names.sort();
+        names.dedup();
+
+        let get = |sums: &LabelSums, name: u16| sums.get(&name).cloned().unwrap_or(0);
+        names.into_iter().filter_map(|name| {
+            let came = get(&self.held_before, name) + get(&self.seeded, name);
+            let went = get(&self.held_after, name) + get(&self.burned, name) + get(&self.dropped, name) + get(&self.miners, name);
+            if came != went { Some((name, came, went)) } else { None }
+        }).collect()
+    }
+}
+
+/// What happened in a processed block.
+#[derive(Default)]
+pub struct BlockOutcome {
+    pub collisions: u32,
+    pub fees: u64,
+    pub ledger: BlockLedger,
+}
+
+/// Learns what the engine does while it processes a block. Every method
+/// has a default that ignores it. An error stops the block where it is, the
+/// caller has to restore the outpoints and seeds it touched.
+pub trait EngineObserver<K> {
+    /// Whether flows are wanted at all, they are not built otherwise.
+    fn wants_flows(&self) -> bool { false }
+    fn flow(&mut self, _flow: &Flow<K>) -> OpResult<()> { Ok(()) }
+    fn collision(&mut self, _overlap: &TaintOverlap) -> OpResult<()> { Ok(()) }
+    /// A transaction output got its taint; not called for the fee chunks of the miners.
+    fn output(&mut self, _tx: &Transaction<K>, _index: usize, _info: &AddressInfo) -> OpResult<()> { Ok(()) }
+    fn spent(&mut self, _outpoint: &K) -> OpResult<()> { Ok(()) }
+
+    /// Whether to explain the matching of this transaction step by step.
+    fn traces(&self, _txid: &str) -> bool { false }
+    fn begin_trace(&mut self, _txid: &str, _height: usize) -> OpResult<()> { Ok(()) }
+    fn trace(&mut self, _line: &str) -> OpResult<()> { Ok(()) }
+
+    /// Taint per label as written in discrepancies, by default with the label ids.
+    fn describe(&self, sums: &LabelSums) -> String {
+        let parts: Vec<String> = sums.iter().map(|(name, value)| format!("{} {}", name, value)).collect();
+        parts.join(", ")
+    }
+
+    /// A check failed. The engine carries on after it in a defined way,
+    /// unless an error is returned.
+    fn discrepancy(&mut self, txid: Option<&str>, check: &str, detail: &str) -> OpResult<()> {
+        warn!(target: "FIFO [engine]", "tx {}: {}: {}", txid.unwrap_or("-"), check, detail);
+        Ok(())
+    }
+}
+
+/// The FIFO taint matching, independent of a block parser. The caller owns
+/// the state, a map from outpoints to `AddressInfo`, and the seeds, a map
+/// from txids to the taint their outputs get, and passes blocks in order.
+#[derive(Default)]
+pub struct FifoEngine {
+    pool: VecDeque<TaintPart>, // The taint of the inputs of the current transaction, in FIFO order
+}
+
+impl FifoEngine {
+    pub fn new() -> Self {
+        FifoEngine::default()
+    }
+
+    /// Matches the taint of every transaction of `block` to its outputs and
+    /// fees, and pays the fees and the clean rest of the reward to the
+    /// coinbase outputs. Spent outpoints are removed from `outpoints`, seeds
+    /// that were reached from `seeds`.
+    pub fn process_block<K, S, O>(&mut self,
+                                  block: &BlockData<K>,
+                                  outpoints: &mut HashMap<K, AddressInfo, S>,
+                                  seeds: &mut HashMap<String, VecDeque<TaintPart>>,
+                                  observer: &mut O) -> OpResult<BlockOutcome>
+        where K: Outpoint, S: BuildHasher, O: EngineObserver<K>
+    {
+        // A block that failed may have left taint behind
+        self.pool.clear();
+        let mut outcome = BlockOutcome::default();
+        let touched = touched_outpoints(block);
+        for outpoint in touched.iter() {
+            if let Some(info) = outpoints.get(outpoint) {
+                add_label_sums(&mut outcome.ledger.held_before, info.taint());
+            }
+        }
+
+        let mut all_inputs_summed  = 0;
+        let mut all_outputs_summed = 0;
+
+        // The coinbase outputs take the fee chunks in order, each with what is left of its value
+        let mut miner_output_queue: VecDeque<(&K, u64, &str)> = VecDeque::new();
+        for tx in block.txs.iter().filter(|tx| tx.coinbase) {
+            for output in tx.outputs.iter() {
+                miner_output_queue.push_back((&output.outpoint, output.value, tx.txid.as_str()));
+            }
+        }
+
+        // The story is. There are actually two types of combination
+        // First, is when we have user defined taint which is easy
+        // Second, the miner one. We have to append taint to them and to do that we need to offset 
+        // Them before combining. 
+        let mut miner_offset_collector: HashMap<K, u64> = HashMap::new();
+
+        for tx in block.txs.iter() {
+            debug!("Tx: {}[I:{} O:{}]", tx.txid, tx.inputs.len(), tx.outputs.len());
+            let tracing = observer.traces(&tx.txid);
+            if tracing {
+                try!(observer.begin_trace(&tx.txid, block.height));
+                if tx.coinbase {
+                    try!(observer.trace("  coinbase, its outputs take the fee chunks of the block, the rest of the reward is clean"));
+                }
+            }
+            if tx.coinbase {continue;}
+
+            if let Some(mut seed) = seeds.remove(&tx.txid) {
+                try!(self.seed_outputs(block, tx, &mut seed, outpoints, &mut outcome.ledger, observer, tracing));
+            }
+
+            let outputs_summed = tx.outputs.iter().fold(0, |acc, output| acc + output.value);
+            let inputs_summed = try!(self.pool_inputs(tx, outpoints, block.height, observer, tracing));
+
+            let tx_inp_taint_sum = non_white_taint_to_sum(&self.pool);
+            if inputs_summed < outputs_summed {
+                // The outputs are filled up with clean value and no fee is paid
+                try!(observer.discrepancy(Some(&tx.txid), "inputs below outputs",
+                                          &format!("inputs sum to {} but outputs to {}", inputs_summed, outputs_summed)));
+            }
+            let miner_fee = inputs_summed.saturating_sub(outputs_summed);
+            outcome.fees += miner_fee;
+            if tracing {
+                try!(observer.trace(&format!("  inputs {}, outputs {}, fee {}; the outputs take from the pool in order, then the fee chunks",
+                                             inputs_summed, outputs_summed, miner_fee)));
+            }
+
+            // The value, the outpoint it goes to and the offset that needs to be appended to the taint.
+            // We need to do this because of the way we do taint propagation to the miners
+            let mut hul: Vec<(u64, K, u64)> = tx.outputs.iter().map(|output| (output.value, output.outpoint.clone(), 0)).collect();
+
+            let mut left_to_payoff = miner_fee;
+            while left_to_payoff > 0 {
+                let (miner_outpoint, value, coinbase_txid) = match miner_output_queue.pop_front() {
+                    Some(miner) => miner,
+                    None => {
+                        // What the fee chunks do not take stays in the pool and is dropped with it
+                        try!(observer.discrepancy(Some(&tx.txid), "fee above the coinbase outputs",
+                                                  &format!("no coinbase output left to take {} of the fee {}", left_to_payoff, miner_fee)));
+                        break;
+                    },
+                };
+                let chunk = if value > left_to_payoff {
+                    miner_output_queue.push_front((miner_outpoint, value - left_to_payoff, coinbase_txid));
+                    left_to_payoff
+                } else {
+                    value
+                };
+                left_to_payoff -= chunk;
+
+                let offset = miner_offset_collector.get(miner_outpoint).cloned().unwrap_or(0);
+                if tracing {
+                    try!(observer.trace(&format!("  fee chunk {} to miner output {}, after the {} it already got in this block",
+                                                 chunk, miner_outpoint.describe(), offset)));
+                }
+                hul.push((chunk, miner_outpoint.clone(), offset));
+                miner_offset_collector.insert(miner_outpoint.clone(), offset + chunk);
+            }
+
+            let mut tx_out_taint_sum = 0;
+            for (hul_index, (value, outpoint, taint_offset)) in hul.into_iter().enumerate() {
+                tx_out_taint_sum += try!(self.assign_output(block, tx, hul_index, value, outpoint, taint_offset,
+                                                            outpoints, &mut outcome, observer, tracing));
+            }
+
+            for miner_outpoint in miner_offset_collector.keys() {
+                match outpoints.get(miner_outpoint) {
+                    Some(info) => tx_out_taint_sum += non_white_taint_to_sum(info.taint()),
+                    None => try!(observer.discrepancy(Some(&tx.txid), "coinbase output not in the state",
+                                                      &format!("fee chunks went to {}", miner_outpoint.describe()))),
+                }
+            }
+
+            if !self.pool.is_empty() {
+                let mut left = LabelSums::new();
+                add_label_sums(&mut left, &self.pool);
+                let detail = format!("{} was not passed on: {}", taint_to_string(&self.pool), observer.describe(&left));
+                try!(observer.discrepancy(Some(&tx.txid), "taint left in the pool", &detail));
+                add_label_sums(&mut outcome.ledger.dropped, &self.pool);
+                self.pool.clear();
+            }
+            if tx_inp_taint_sum > tx_out_taint_sum {
+                try!(observer.discrepancy(Some(&tx.txid), "outputs carry less taint than the inputs",
+                                          &format!("inputs carry {} but outputs and fee chunks {}", tx_inp_taint_sum, tx_out_taint_sum)));
+            }
+
+            for input in tx.inputs.iter() {
+                outpoints.remove(&input.outpoint);
+                try!(observer.spent(&input.outpoint));
+            }
+
+            all_inputs_summed  += inputs_summed;
+            all_outputs_summed += outputs_summed;
+        }
+
+        if all_inputs_summed != all_outputs_summed + outcome.fees {
+            try!(observer.discrepancy(None, "fees do not add up",
+                                      &format!("inputs {} but outputs {} and fees {}", all_inputs_summed, all_outputs_summed, outcome.fees)));
+        }
+
+        // The clean rest of the reward goes after the fee chunks
+        while let Some((miner_outpoint, rew, coinbase_txid)) = miner_output_queue.pop_front() {
+            let found = match outpoints.get_mut(miner_outpoint) {
+                Some(address_info) => {
+                    address_info.current_balance += rew; 
+
+                    if address_info.tainted_balance != None && address_info.current_balance - rew != taint_to_sum(address_info.taint()) {
+                        try!(observer.discrepancy(None, "coinbase taint differs from the fees",
+                                                  &format!("{} got fees {} but taint {}", miner_outpoint.describe(),
+                                                           address_info.current_balance - rew, taint_to_string(address_info.taint()))));
+                    }
+
+                    let mut destroyme = false;
+                    if let Some(ref mut x) = address_info.tainted_balance{
+                        x.push(TaintPart{name: 0, value: rew});
+                        reduce_taint(x);
+                        if x.is_empty(){
+                            destroyme = true;
+                        }
+                    }
+
+                    if destroyme{
+                        address_info.tainted_balance = None;
+                    }
+                    debug!("Miner found {} has new balance of {}", miner_outpoint.describe(), address_info.current_balance);
+                    true
+                },
+                None => false,
+            };
+
+            if !found{
+                let info = AddressInfo {
+                    time: block.time,
+                    current_balance: rew, 
+                    tainted_balance: None, 
+                };
+
+                debug!("Miner not found {} has new balance of {}", miner_outpoint.describe(), info.current_balance);
+
+                outpoints.insert(miner_outpoint.clone(), info); 
+            }
+
+            if observer.traces(coinbase_txid) {
+                let taint = outpoints.get(miner_outpoint).map(|info| format!("balance {}, taint {}", info.current_balance, parts_to_string(info.taint())));
+                try!(observer.trace(&format!("  miner output {}: the clean rest of the reward, {}, goes after the fee chunks -> {} (block {})",
+                                             miner_outpoint.describe(), rew, taint.unwrap_or_default(), block.height)));
+            }
+        }
+
+        let mut mined: HashSet<&K> = HashSet::new();
+        let mut burned: HashSet<&K> = HashSet::new();
+        for tx in block.txs.iter() {
+            for output in tx.outputs.iter() {
+                if tx.coinbase {
+                    mined.insert(&output.outpoint);
+                } else if output.unspendable {
+                    burned.insert(&output.outpoint);
+                }
+            }
+        }
+        for outpoint in touched.iter() {
+            if let Some(info) = outpoints.get(outpoint) {
+                let sums = if mined.contains(outpoint) {
+                    &mut outcome.ledger.miners
+                } else if burned.contains(outpoint) {
+                    &mut outcome.ledger.burned
+                } else {
+                    &mut outcome.ledger.held_after
+                };
+                add_label_sums(sums, info.taint());
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Gives the outputs of a seeded transaction the taint of its seed in order.
+    fn seed_outputs<K, S, O>(&mut self,
+                             block: &BlockData<K>,
+                             tx: &Transaction<K>,
+                             assigned_dirt: &mut VecDeque<TaintPart>,
+                             outpoints: &mut HashMap<K, AddressInfo, S>,
+                             ledger: &mut BlockLedger,
+                             observer: &mut O,
+                             tracing: bool) -> OpResult<()>
+        where K: Outpoint, S: BuildHasher, O: EngineObserver<K>
+    {
+        if assigned_dirt.iter().any(|tp| tp.value == 0) {
+            try!(observer.discrepancy(Some(&tx.txid), "empty taint fragment",
+                                      &format!("the seed {} has fragments without value, they are left out", taint_to_string(assigned_dirt.iter()))));
+            assigned_dirt.retain(|tp| tp.value != 0);
+        }
+        add_label_sums(&mut ledger.seeded, assigned_dirt.iter());
+
+        if assigned_dirt.len() > 0{
+            for (i, output) in tx.outputs.iter().enumerate() {
+                if outpoints.contains_key(&output.outpoint) {
+                    try!(observer.discrepancy(Some(&tx.txid), "seed output already known", &format!("output {} was created before, its state is replaced", i)));
+                }
+                let seed_before = if tracing {parts_to_string(assigned_dirt.iter())} else {String::new()};
+
+                let mut taint = TaintVec::new();
+                extract_taint(assigned_dirt, output.value, &mut taint);
+                let address_info = AddressInfo{
+                       time: block.time,
+                       current_balance: 0,
+                       tainted_balance: Some(taint)
+                };  
+
+                if taint_to_sum(address_info.taint()) != output.value {
+                    try!(observer.discrepancy(Some(&tx.txid), "seed taint differs from the output value",
+                                              &format!("output {} has value {} but got taint {}", i, output.value, taint_to_string(address_info.taint()))));
+                }
+                debug!("Loading taint for {} : {}", output.address, taint_to_string(address_info.taint()));
+                if tracing {
+                    try!(observer.trace(&format!("  seed output {}: extract_taint({}) from the seed {} -> {}, the seed keeps {}",
+                                                 i, output.value, seed_before, parts_to_string(address_info.taint()), parts_to_string(assigned_dirt.iter()))));
+                }
+
+                if observer.wants_flows() {
+                    try!(observer.flow(&Flow{
+                        height: block.height,
+                        txid: &tx.txid,
+                        kind: FlowKind::Seed,
+                        outpoint: &output.outpoint,
+                        value: output.value,
+                        taint: address_info.taint(),
+                    }));
+                }
+
+                outpoints.insert(output.outpoint.clone(), address_info);
+            }
+        }
+        if non_white_taint_to_sum(assigned_dirt.iter()) > 0 {
+            // The seed is larger than the outputs of its transaction
+            warn!(target: "FIFO [engine]", "Seed {} is larger than its outputs, {} is dropped.", tx.txid, taint_to_string(assigned_dirt.iter()));
+            add_label_sums(&mut ledger.dropped, assigned_dirt.iter());
+        }
+        Ok(())
+    }
+
+    /// Moves the taint of the inputs into the pool and returns their value.
+    fn pool_inputs<K, S, O>(&mut self,
+                            tx: &Transaction<K>,
+                            outpoints: &mut HashMap<K, AddressInfo, S>,
+                            block_height: usize,
+                            observer: &mut O,
+                            tracing: bool) -> OpResult<u64>
+        where K: Outpoint, S: BuildHasher, O: EngineObserver<K>
+    {
+        let mut inputs_summed = 0;
+        for (i, input) in tx.inputs.iter().enumerate() {
+            match outpoints.get_mut(&input.outpoint) {
+                Some(address_info)=>{
+                    match input.value {
+                        Some(value) if value != address_info.current_balance => {
+                            try!(observer.discrepancy(Some(&tx.txid), "input value differs from the state",
+                                                      &format!("input {} spends {} of {}, the state has {}", i, input.outpoint.describe(), value, address_info.current_balance)));
+                        },
+                        _ => {},
+                    }
+                    inputs_summed += address_info.current_balance;
+                    debug!("\t{} INP: {} | BAL: {} | T: {} | SUM: {}", i, input.outpoint.describe(), address_info.current_balance, taint_to_string(address_info.taint()), inputs_summed);
+
+                    if observer.wants_flows() && non_white_taint_to_sum(address_info.taint()) > 0 {
+                        try!(observer.flow(&Flow{
+                            height: block_height,
+                            txid: &tx.txid,
+                            kind: FlowKind::Input,
+                            outpoint: &input.outpoint,
+                            value: address_info.current_balance,
+                            taint: address_info.taint(),
+                        }));
+                    }
+
+                    let input_taint = if tracing {parts_to_string(address_info.taint())} else {String::new()};
+                    let mut nullify = false;
+                    if let Some(x) = address_info.tainted_balance.take(){
+                        // Push the taints if they exist
+                        for tt in x.into_iter(){
+                            if tt.value == 0 {
+                                try!(observer.discrepancy(Some(&tx.txid), "empty taint fragment",
+                                                          &format!("input {} spends {}, whose taint has a fragment of label {} without value",
+                                                                   i, input.outpoint.describe(), tt.name)));
+                                continue;
+                            }
+                            self.pool.push_back(tt);
+                        }
+                        nullify = true;
+                    }else{
+                        // No taint is found means that there is full White taint
+                        // If the address has only white taint associated, that means
+                        // that it is clean and we will not save this value at all to
+                        // conserve space
+                        
+                        if address_info.current_balance > 0 {
+                            self.pool.push_back(TaintPart{name:0, value: address_info.current_balance});
+                        }
+                    }
+                    if tracing {
+                        try!(observer.trace(&format!("  input {} {}: balance {}, taint {}{} -> pool {}",
+                                                     i, input.outpoint.describe(), address_info.current_balance, input_taint,
+                                                     if nullify {""} else {" (clean)"}, parts_to_string(&self.pool))));
+                    }
+                    address_info.current_balance = 0;
+                },
+                None=>{
+                    // Counted as clean, with the value the transaction gives for it
+                    try!(observer.discrepancy(Some(&tx.txid), "input not in the state",
+                                              &format!("input {} spends {}, which was never created or already spent", i, input.outpoint.describe())));
+                    let value = input.value.unwrap_or(0);
+                    if value > 0 {
+                        inputs_summed += value;
+                        self.pool.push_back(TaintPart{name:0, value: value});
+                    }
+                }
+            };
+        }
+        Ok(inputs_summed)
+    }
+
+    /// Gives an output, or a fee chunk of a coinbase output, its share of the
+    /// pool and returns the tainted value it counts for the transaction.
+    fn assign_output<K, S, O>(&mut self,
+                              block: &BlockData<K>,
+                              tx: &Transaction<K>,
+                              hul_index: usize,
+                              value: u64,
+                              tx_outpoint: K,
+                              taint_offset: u64,
+                              outpoints: &mut HashMap<K, AddressInfo, S>,
+                              outcome: &mut BlockOutcome,
+                              observer: &mut O,
+                              tracing: bool) -> OpResult<u64>
+        where K: Outpoint, S: BuildHasher, O: EngineObserver<K>
+    {
+        // The entries after the transaction outputs are the fee chunks paid to the miners
+        let is_output = hul_index < tx.outputs.len();
+        let mut add_bal: u64 = 0;
+
+        let mut tainted_balance = TaintVec::new();
+        extract_taint(&mut self.pool, value, &mut tainted_balance);
+        if tracing {
+            let target = if is_output {format!("output {}", tx_outpoint.describe())} else {format!("fee chunk to miner output {}", tx_outpoint.describe())};
+            try!(observer.trace(&format!("  {}: extract_taint({}) -> {}, pool left {}",
+                                         target, value, parts_to_string(&tainted_balance), parts_to_string(&self.pool))));
+        }
+        if taint_to_sum(&tainted_balance) != value {
+            try!(observer.discrepancy(Some(&tx.txid), "extracted taint differs from the value",
+                                      &format!("{} has value {} but got taint {}", tx_outpoint.describe(), value, taint_to_string(&tainted_balance))));
+        }
+
+        // An output's flow is recorded with its final taint below, that of a seed included
+        if !is_output && observer.wants_flows() && non_white_taint_to_sum(&tainted_balance) > 0 {
+            try!(observer.flow(&Flow{
+                height: block.height,
+                txid: &tx.txid,
+                kind: FlowKind::Fee,
+                outpoint: &tx_outpoint,
+                value: value,
+                taint: &tainted_balance,
+            }));
+        }
+
+        match outpoints.get_mut(&tx_outpoint){
+            Some(address_info)=>{
+                add_bal += address_info.current_balance;
+                if add_bal != taint_offset {
+                    try!(observer.discrepancy(Some(&tx.txid), "offset differs from the held balance",
+                                              &format!("{} holds {} but the fee chunk is placed after {}", tx_outpoint.describe(), add_bal, taint_offset)));
+                }
+
+                if let Some(ref held) = address_info.tainted_balance{
+                    if taint_offset > 0{tainted_balance.insert(0, TaintPart{name:0,value:taint_offset});}
+                    let before = if tracing {format!("{} with {}", parts_to_string(&tainted_balance), parts_to_string(held))} else {String::new()};
+                    let mut sums_before = LabelSums::new();
+                    add_label_sums(&mut sums_before, &tainted_balance);
+                    add_label_sums(&mut sums_before, held);
+                    let mut combined = TaintVec::new();
+                    let collisions = try!(combine_taints(&tainted_balance, held, &mut combined,
+                                                         Some(&mut |overlap: &TaintOverlap| observer.collision(overlap)), &tx.txid));
+                    tainted_balance = combined;
+                    outcome.collisions += collisions;
+                    if collisions > 0 {
+                        // The newer taint takes the place of the held one
+                        let mut result = LabelSums::new();
+                        add_label_sums(&mut result, &tainted_balance);
+                        add_lost(&mut outcome.ledger.dropped, &sums_before, &result);
+                    }
+                    if tracing {
+                        try!(observer.trace(&format!("    combine_taints {} already held by the miner output -> {}, {} collisions",
+                                                before, parts_to_string(&tainted_balance), collisions)));
+                    }
+                }else{
+                    if add_bal > 0{tainted_balance.insert(0, TaintPart{name:0, value:add_bal});}
+                    if tracing {
+                        try!(observer.trace(&format!("    the miner output already holds {} clean, put in front -> {}",
+                                                     add_bal, parts_to_string(&tainted_balance))));
+                    }
+                }
+            },
+            None=>{}
+        };
+
+        let unreduced = if tracing {parts_to_string(&tainted_balance)} else {String::new()};
+        reduce_taint(&mut tainted_balance); 
+        if tracing {
+            try!(observer.trace(&format!("    reduce_taint {} -> {}", unreduced, parts_to_string(&tainted_balance))));
+        }
+
+        let info = AddressInfo {
+            time: block.time,
+            current_balance: value + add_bal, 
+            tainted_balance: to_stored(tainted_balance),
+        };
+
+        let mut tainted = 0;
+        if taint_offset > 0{
+            debug!("\tMINER OUT: {} | VAL: {} | TO: {} | T: {}", tx_outpoint.describe(), value, taint_offset, taint_to_string(info.taint()));
+        }else{
+            debug!("\t      OUT: {} | VAL: {} | TO: {} | T: {}", tx_outpoint.describe(), value, taint_offset, taint_to_string(info.taint()));
+            tainted = non_white_taint_to_sum(info.taint());
+        }
+
+        if is_output {
+            if observer.wants_flows() && non_white_taint_to_sum(info.taint()) > 0 {
+                try!(observer.flow(&Flow{
+                    height: block.height,
+                    txid: &tx.txid,
+                    kind: FlowKind::Output,
+                    outpoint: &tx_outpoint,
+                    value: value,
+                    taint: info.taint(),
+                }));
+            }
+            try!(observer.output(tx, hul_index, &info));
+        }
+
+        outpoints.insert(tx_outpoint, info);
+        Ok(tainted)
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::Path;
+
+use errors::OpResult;
+
+use blockchain::proto::tx::TxOutpoint;
+use blockchain::utils::arr_to_hex_swapped;
+
+use callbacks::taint_output::{TaintFlow, FlowKind, PendingFiles, write_pending, csv_escape, markup_escape};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum NodeKind {
+    Transaction,
+    Outpoint,
+}
+
+struct GraphNode {
+    kind: NodeKind,
+    height: usize, // Block of the transaction, or of the transaction creating the outpoint
+    value: u64,    // Value of the outpoint, zero for transactions
+    hop: u32,      // Transactions between the seed and this node
+}
+
+struct GraphEdge {
+    kind: FlowKind,
+    amounts: BTreeMap<u16, u64>, // Taint of the selected labels moved along the edge
+}
+
+/// The part of the transaction graph reached by a chosen set of taint labels.
+///
+/// Seeded transactions are at hop 0, a transaction spending an outpoint at hop
+/// `n` is at hop `n + 1` and the outpoints it creates share its hop. Edges whose
+/// selected taint is below `min_value`, or that would go beyond `max_hops`, are
+/// left out, and so is everything only reachable through them.
+pub struct TaintGraph {
+    label_names: Vec<String>,     // Labels as given on the command line
+    labels: BTreeMap<u16, String>, // Selected label ids and their names
+    max_hops: Option<u32>,
+    min_value: u64,
+    outpoint_hops: HashMap<TxOutpoint, u32>,
+    tx_hops: HashMap<String, u32>,
+    nodes: BTreeMap<String, GraphNode>,
+    edges: BTreeMap<(String, String, &'static str), GraphEdge>, // One edge per flow kind
+}
+
+fn tx_node_id(txid: &str) -> String {
+    format!("tx:{}", txid)
+}
+
+fn outpoint_node_id(outpoint: &TxOutpoint) -> String {
+    format!("{}:{}", arr_to_hex_swapped(&outpoint.txid), outpoint.index)
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl TaintGraph {
+    pub fn new(label_names: Vec<String>, max_hops: Option<u32>, min_value: u64) -> Self {
+        TaintGraph {
+            label_names: label_names,
+            labels: BTreeMap::new(),
+            max_hops: max_hops,
+            min_value: min_value,
+            outpoint_hops: HashMap::new(),
+            tx_hops: HashMap::new(),
+            nodes: BTreeMap::new(),
+            edges: BTreeMap::new(),
+        }
+    }
+
+    /// Resolves the selected label names once the seed file has been loaded.
+    /// Returns the names that do not appear in it.
+    pub fn resolve_labels(&mut self, dirtmapper: &HashMap<String, u16>) -> Vec<String> {
+        let mut unknown = Vec::new();
+        for name in self.label_names.iter() {
+            match dirtmapper.get(name) {
+                Some(id) if *id != 0 => { self.labels.insert(*id, name.clone()); },
+                _ => unknown.push(name.clone()),
+            }
+        }
+        unknown
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn edge_count(&self) -> usize {
+        self.edges.len()
+    }
+
+    fn within_hops(&self, hop: u32) -> bool {
+        match self.max_hops {
+            Some(max_hops) => hop <= max_hops,
+            None => true,
+        }
+    }
+
+    fn add_node(&mut self, id: String, kind: NodeKind, height: usize, value: u64, hop: u32) {
+        let node = self.nodes.entry(id).or_insert(GraphNode {
+            kind: kind,
+            height: height,
+            value: value,
+            hop: hop,
+        });
+        if hop < node.hop {
+            node.hop = hop;
+        }
+    }
+
+    /// Adds up flows of the same kind between two nodes. A seeded outpoint also
+    /// gets the output flow of its transaction, which has the final taint of the
+    /// outpoint with the seed in it, so each kind gets its own edge.
+    fn add_edge(&mut self, from: String, to: String, kind: FlowKind, amounts: BTreeMap<u16, u64>) {
+        let edge = self.edges.entry((from, to, kind.as_str())).or_insert(GraphEdge {
+            kind: kind,
+            amounts: BTreeMap::new(),
+        });
+        for (label, amount) in amounts {
+            *edge.amounts.entry(label).or_insert(0) += amount;
+        }
+    }
+
+    /// Adds one taint flow recorded in `on_block`, if it carries enough of the
+    /// selected labels and connects to the part of the graph reached so far.
+    pub fn add_flow(&mut self, flow: &TaintFlow) {
+        let mut amounts: BTreeMap<u16, u64> = BTreeMap::new();
+        for tp in flow.taint.iter().filter(|tp| self.labels.contains_key(&tp.name)) {
+            *amounts.entry(tp.name).or_insert(0) += tp.value;
+        }
+        let total = amounts.values().fold(0, |a, v| a + v);
+        if total == 0 || total < self.min_value {
+            return;
+        }
+
+        let tx_id = tx_node_id(flow.txid);
+        let outpoint_id = outpoint_node_id(flow.outpoint);
+        match flow.kind {
+            FlowKind::Seed => {
+                self.tx_hops.insert(String::from(flow.txid), 0);
+                self.outpoint_hops.insert(flow.outpoint.clone(), 0);
+                self.add_node(tx_id.clone(), NodeKind::Transaction, flow.height, 0, 0);
+                self.add_node(outpoint_id.clone(), NodeKind::Outpoint, flow.height, flow.value, 0);
+                self.add_edge(tx_id, outpoint_id, flow.kind, amounts);
+            },
+            FlowKind::Input => {
+                let hop = match self.outpoint_hops.get(flow.outpoint) {
+                    Some(hop) => hop + 1,
+                    None => return,
+                };
+                if !self.within_hops(hop) {
+                    return;
+                }
+                let tx_hop = {
+                    let tx_hop = self.tx_hops.entry(String::from(flow.txid)).or_insert(hop);
+                    if hop < *tx_hop {
+                        *tx_hop = hop;
+                    }
+                    *tx_hop
+                };
+                self.add_node(tx_id.clone(), NodeKind::Transaction, flow.height, 0, tx_hop);
+                self.add_edge(outpoint_id, tx_id, flow.kind, amounts);
+            },
+            FlowKind::Output | FlowKind::Fee => {
+                let hop = match self.tx_hops.get(flow.txid) {
+                    Some(hop) => *hop,
+                    None => return,
+                };
+                {
+                    let outpoint_hop = self.outpoint_hops.entry(flow.outpoint.clone()).or_insert(hop);
+                    if hop < *outpoint_hop {
+                        *outpoint_hop = hop;
+                    }
+                }
+                self.add_node(outpoint_id.clone(), NodeKind::Outpoint, flow.height, flow.value, hop);
+                self.add_edge(tx_id, outpoint_id, flow.kind, amounts);
+            },
+        }
+    }
+
+    /// Forgets an outpoint once it has been spent, the graph keeps its node.
+    pub fn spend(&mut self, outpoint: &TxOutpoint) {
+        self.outpoint_hops.remove(outpoint);
+    }
+
+    pub fn write_graphml(&self, path: &Path, pending: &mut PendingFiles) -> OpResult<()> {
+        write_pending(path, pending, |w| {
+            try!(w.write_all(b"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n"));
+            try!(w.write_all(b"<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n"));
+            try!(w.write_all(b"  <key id=\"kind\" for=\"all\" attr.name=\"kind\" attr.type=\"string\"/>\n"));
+            try!(w.write_all(b"  <key id=\"height\" for=\"node\" attr.name=\"height\" attr.type=\"long\"/>\n"));
+            try!(w.write_all(b"  <key id=\"value\" for=\"node\" attr.name=\"value\" attr.type=\"long\"/>\n"));
+            try!(w.write_all(b"  <key id=\"hop\" for=\"node\" attr.name=\"hop\" attr.type=\"int\"/>\n"));
+            try!(w.write_all(b"  <key id=\"total\" for=\"edge\" attr.name=\"total\" attr.type=\"long\"/>\n"));
+            for (label, name) in self.labels.iter() {
+                try!(w.write_all(format!("  <key id=\"label{}\" for=\"edge\" attr.name=\"{}\" attr.type=\"long\"/>\n",
+                                         label, markup_escape(name)).as_bytes()));
+            }
+            try!(w.write_all(b"  <graph id=\"taint\" edgedefault=\"directed\">\n"));
+            for (id, node) in self.nodes.iter() {
+                try!(w.write_all(format!("    <node id=\"{}\"><data key=\"kind\">{}</data><data key=\"height\">{}</data><data key=\"value\">{}</data><data key=\"hop\">{}</data></node>\n",
+                                         markup_escape(id),
+                                         if node.kind == NodeKind::Transaction { "tx" } else { "outpoint" },
+                                         node.height, node.value, node.hop).as_bytes()));
+            }
+            for (&(ref from, ref to, _), edge) in self.edges.iter() {
+                let mut data = format!("<data key=\"kind\">{}</data><data key=\"total\">{}</data>",
+                                       edge.kind.as_str(), edge.amounts.values().fold(0, |a, v| a + v));
+                for (label, amount) in edge.amounts.iter() {
+                    data += &format!("<data key=\"label{}\">{}</data>", label, amount);
+                }
+                try!(w.write_all(format!("    <edge source=\"{}\" target=\"{}\">{}</edge>\n",
+                                         markup_escape(from), markup_escape(to), data).as_bytes()));
+            }
+            try!(w.write_all(b"  </graph>\n</graphml>\n"));
+            Ok(())
+        })
+    }
+
+    pub fn write_dot(&self, path: &Path, pending: &mut PendingFiles) -> OpResult<()> {
+        write_pending(path, pending, |w| {
+            try!(w.write_all(b"digraph taint {\n    rankdir=LR;\n"));
+            for (id, node) in self.nodes.iter() {
+                let attrs = match node.kind {
+                    NodeKind::Transaction => format!("shape=box, label=\"{}\\nblock {}\"", dot_escape(&id[3..]), node.height),
+                    NodeKind::Outpoint => format!("shape=ellipse, label=\"{}\\n{} sat\"", dot_escape(id), node.value),
+                };
+                try!(w.write_all(format!("    \"{}\" [{}];\n", dot_escape(id), attrs).as_bytes()));
+            }
+            for (&(ref from, ref to, _), edge) in self.edges.iter() {
+                let label = edge.amounts.iter()
+                    .map(|(label, amount)| format!("{}: {}", dot_escape(&self.labels[label]), amount))
+                    .collect::<Vec<String>>()
+                    .join("\\n");
+                let style = if edge.kind == FlowKind::Fee { ", style=dashed" } else { "" };
+                try!(w.write_all(format!("    \"{}\" -> \"{}\" [label=\"{}\"{}];\n",
+                                         dot_escape(from), dot_escape(to), label, style).as_bytes()));
+            }
+            try!(w.write_all(b"}\n"));
+            Ok(())
+        })
+    }
+
+    /// Writes the node and relationship files for `neo4j-admin import`.
+    /// The per-label amounts become `label_<id>` properties, see `taint_mapper.csv`.
+    pub fn write_neo4j(&self, nodes_path: &Path, edges_path: &Path, pending: &mut PendingFiles) -> OpResult<()> {
+        try!(write_pending(nodes_path, pending, |w| {
+            try!(w.write_all(b"id:ID,:LABEL,height:long,value:long,hop:int\n"));
+            for (id, node) in self.nodes.iter() {
+                try!(w.write_all(format!("{},{},{},{},{}\n",
+                                         csv_escape(id),
+                                         if node.kind == NodeKind::Transaction { "Transaction" } else { "Outpoint" },
+                                         node.height, node.value, node.hop).as_bytes()));
+            }
+            Ok(())
+        }));
+        write_pending(edges_path, pending, |w| {
+            let mut header = String::from(":START_ID,:END_ID,:TYPE,total:long");
+            for label in self.labels.keys() {
+                header += &format!(",label_{}:long", label);
+            }
+            try!(w.write_all(format!("{}\n", header).as_bytes()));
+            for (&(ref from, ref to, _), edge) in self.edges.iter() {
+                let mut row = format!("{},{},{},{}", csv_escape(from), csv_escape(to),
+                                      match edge.kind {
+                                          FlowKind::Input => "SPENT_BY",
+                                          FlowKind::Fee => "PAYS_FEE",
+                                          _ => "CREATES",
+                                      },
+                                      edge.amounts.values().fold(0, |a, v| a + v));
+                for label in self.labels.keys() {
+                    row += &format!(",{}", edge.amounts.get(label).cloned().unwrap_or(0));
+                }
+                try!(w.write_all(format!("{}\n", row).as_bytes()));
+            }
+            Ok(())
+        })
+    }
+
+    /// Writes all graph formats into the dump folder, committed with the other outputs.
+    pub fn export(&self, folder: &Path, pending: &mut PendingFiles) -> OpResult<usize> {
+        info!(target: "FIFO [graph]", "Exporting taint graph with {} nodes and {} edges...", self.nodes.len(), self.edges.len());
+        try!(self.write_graphml(&folder.join("taint_graph.graphml"), pending));
+        try!(self.write_dot(&folder.join("taint_graph.dot"), pending));
+        try!(self.write_neo4j(&folder.join("taint_graph_nodes.csv"), &folder.join("taint_graph_edges.csv"), pending));
+        Ok(self.edges.len())
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::hash::BuildHasher;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use rustc_serialize::json::Json;
+
+use errors::{OpError, OpErrorKind, OpResult};
+
+use blockchain::proto::tx::TxOutpoint;
+
+use callbacks::taint_engine::AddressInfo;
+use callbacks::taint_output::write_file;
+
+/// Counts over `address_mapping` that are kept up to date block by block
+/// instead of walking the whole map.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StateCounters {
+    pub fragments: usize,       // Taint fragments over all outpoints
+    pub tainted_outputs: usize, // Outpoints that carry any taint
+}
+
+impl StateCounters {
+    /// Counts a whole map, after the state was loaded or rolled back.
+    pub fn count<S: BuildHasher>(address_mapping: &HashMap<TxOutpoint, AddressInfo, S>) -> Self {
+        let mut counters = StateCounters::default();
+        for info in address_mapping.values() {
+            counters.add(info);
+        }
+        counters
+    }
+
+    pub fn add(&mut self, info: &AddressInfo) {
+        if let Some(ref x) = info.tainted_balance {
+            self.fragments += x.len();
+            self.tainted_outputs += 1;
+        }
+    }
+
+    pub fn remove(&mut self, info: &AddressInfo) {
+        if let Some(ref x) = info.tainted_balance {
+            self.fragments -= x.len();
+            self.tainted_outputs -= 1;
+        }
+    }
+}
+
+/// One sample of the progress of a run.
+pub struct MetricsSample {
+    pub height: usize,
+    pub utxos: usize,
+    pub outpoints: usize,
+    pub addresses: usize,
+    pub fragments: usize,
+    pub tainted_outputs: usize,
+    pub collisions: u32,
+    pub seeds_left: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricsFormat {
+    JsonLines,
+    Prometheus,
+}
+
+impl MetricsFormat {
+    pub fn from_name(name: &str) -> OpResult<Self> {
+        match name {
+            "jsonl" => Ok(MetricsFormat::JsonLines),
+            "prometheus" => Ok(MetricsFormat::Prometheus),
+            _ => Err(OpError::new(OpErrorKind::InvalidArgsError)
+                         .join_msg(&format!("Unknown metrics format `{}`", name))),
+        }
+    }
+}
+
+/// The resident memory of this process in bytes, where `/proc` has it.
+pub fn resident_memory() -> Option<u64> {
+    let file = match File::open("/proc/self/status") {
+        Ok(file) => file,
+        Err(_) => return None,
+    };
+    for line in BufReader::new(file).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return None,
+        };
+        if line.starts_with("VmRSS:") {
+            return line.split_whitespace().nth(1).and_then(|kb| kb.parse::<u64>().ok()).map(|kb| kb * 1024);
+        }
+    }
+    None
+}
+
+/// Writes progress metrics as JSON lines appended to a file, or as a
+/// Prometheus text file that is replaced with every sample.
+pub struct Metrics {
+    path: PathBuf,
+    format: MetricsFormat,
+    last: Option<(Instant, usize)>, // Time and height of the previous sample
+}
+
+impl Metrics {
+    pub fn new(path: &Path, format: MetricsFormat) -> OpResult<Self> {
+        if format == MetricsFormat::JsonLines {
+            // Start a new series, like the other outputs of a run
+            try!(File::create(path).map_err(|err| {
+                OpError::from(err).join_msg(&format!("Unable to create {}", path.display()))
+            }));
+        }
+        Ok(Metrics {
+            path: path.to_path_buf(),
+            format: format,
+            last: None,
+        })
+    }
+
+    pub fn record(&mut self, sample: &MetricsSample) -> OpResult<()> {
+        let now = Instant::now();
+        let blocks_per_sec = match self.last {
+            Some((time, height)) if sample.height > height => {
+                let elapsed = now.duration_since(time);
+                let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+                if secs > 0.0 { (sample.height - height) as f64 / secs } else { 0.0 }
+            },
+            _ => 0.0,
+        };
+        self.last = Some((now, sample.height));
+
+        let values: Vec<(&str, &str, f64)> = vec![
+            ("height", "Last processed block", sample.height as f64),
+            ("blocks_per_sec", "Blocks per second since the previous sample", blocks_per_sec),
+            ("utxos", "Entries of the UTXO set", sample.utxos as f64),
+            ("outpoints", "Entries of the outpoint state", sample.outpoints as f64),
+            ("addresses", "Addresses that still hold unspent outputs", sample.addresses as f64),
+            ("fragments", "Taint fragments over all outpoints", sample.fragments as f64),
+            ("tainted_outputs", "Outpoints carrying taint", sample.tainted_outputs as f64),
+            ("collisions", "Taint collisions so far", sample.collisions as f64),
+            ("seeds_left", "Seed transactions not reached yet", sample.seeds_left as f64),
+        ];
+        let rss = resident_memory();
+
+        match self.format {
+            MetricsFormat::JsonLines => {
+                let mut obj = BTreeMap::new();
+                for &(name, _, value) in values.iter() {
+                    obj.insert(String::from(name), if name == "blocks_per_sec" { Json::F64(value) } else { Json::U64(value as u64) });
+                }
+                obj.insert(String::from("rss_bytes"), rss.map(Json::U64).unwrap_or(Json::Null));
+                let mut file = try!(OpenOptions::new().append(true).open(&self.path));
+                try!(writeln!(file, "{}", Json::Object(obj)));
+                try!(file.flush());
+            },
+            MetricsFormat::Prometheus => {
+                try!(write_file(&self.path, |w| {
+                    for &(name, help, value) in values.iter() {
+                        try!(writeln!(w, "# HELP taintfifo_{} {}\n# TYPE taintfifo_{} gauge\ntaintfifo_{} {}", name, help, name, name, value));
+                    }
+                    if let Some(rss) = rss {
+                        try!(writeln!(w, "# HELP taintfifo_rss_bytes Resident memory\n# TYPE taintfifo_rss_bytes gauge\ntaintfifo_rss_bytes {}", rss));
+                    }
+                    Ok(())
+                }));
+            },
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,519 @@
+extern crate csv;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, LineWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use errors::{OpError, OpErrorKind, OpResult};
+
+use blockchain::proto::tx::TxOutpoint;
+use blockchain::utils::arr_to_hex_swapped;
+
+use callbacks::taint_engine::{AddressInfo, Flow};
+pub use callbacks::taint_engine::{TaintOverlap, FlowKind};
+use callbacks::taint_parquet::ParquetOutput;
+use callbacks::taint_sqlite::SqliteOutput;
+
+/// Version of the `tainted_utxo` layout, bumped whenever its columns change.
+pub const TAINTED_UTXO_SCHEMA_VERSION: u32 = 1;
+
+/// One sample of the per-label taint time series.
+pub struct SeriesSample<'a> {
+    pub height: usize,
+    pub date: &'a str,
+    pub label: u16,
+    pub value: u64,     // Taint of this label held in unspent outputs
+    pub outputs: usize, // Number of unspent outputs holding some of it
+}
+
+/// The unspent outputs of one address, aggregated.
+pub struct AddressBalance<'a> {
+    pub address: &'a str,
+    pub outputs: usize,
+    pub balance: u64,
+    pub taints: &'a BTreeMap<u16, u64>,
+}
+
+/// Taint carried along one edge of the transaction graph.
+pub type TaintFlow<'a> = Flow<'a, TxOutpoint>;
+
+/// Selects which result tables an output writes. Writing rows to a table
+/// that is not selected is a no-op.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OutputTables {
+    pub address_info: bool,
+    pub utxo: bool,
+    pub labels: bool,
+    pub overlaps: bool,
+    pub series: bool,
+    pub address_balances: bool,
+    pub tainted_utxos: bool,
+    pub flows: bool,
+}
+
+impl OutputTables {
+    /// The tables every run writes.
+    pub fn core() -> Self {
+        OutputTables {
+            address_info: true,
+            utxo: true,
+            labels: true,
+            overlaps: true,
+            ..Default::default()
+        }
+    }
+
+    /// The tables of a state snapshot.
+    pub fn snapshot() -> Self {
+        OutputTables {
+            address_info: true,
+            labels: true,
+            ..Default::default()
+        }
+    }
+}
+
+/// Sink for the result tables of a taint run.
+pub trait TaintOutput {
+    /// `taint_mapper`: a taint name and its numeric id.
+    fn write_label(&mut self, name: &str, id: u16) -> OpResult<()>;
+    /// `taint_overlap`: one taint collision.
+    fn write_overlap(&mut self, overlap: &TaintOverlap) -> OpResult<()>;
+    /// `address_info`: the state of one outpoint.
+    fn write_address_info(&mut self, tx_outpoint: &TxOutpoint, info: &AddressInfo) -> OpResult<()>;
+    /// `taint_utxo`: one unspent output and its address.
+    fn write_utxo(&mut self, tx_outpoint: &TxOutpoint, address: &str) -> OpResult<()>;
+    /// `taint_series`: one sample of the time series.
+    fn write_series_sample(&mut self, sample: &SeriesSample) -> OpResult<()>;
+    /// `address_balances`: the aggregated outputs of one address.
+    fn write_address_balance(&mut self, balance: &AddressBalance) -> OpResult<()>;
+    /// `tainted_utxo`: one unspent output that carries taint.
+    fn write_tainted_utxo(&mut self, tx_outpoint: &TxOutpoint, address: &str, info: &AddressInfo) -> OpResult<()>;
+    /// `taint_flows`: taint moved into or out of an outpoint by a transaction.
+    fn write_flow(&mut self, flow: &TaintFlow) -> OpResult<()>;
+    /// Run metadata such as parameters and heights, kept only by outputs that have a place for it.
+    fn write_metadata(&mut self, key: &str, value: &str) -> OpResult<()>;
+    /// Flushes all tables, syncs them to disk and adds them to `pending`,
+    /// which moves them to their final names together with the other outputs.
+    fn finish(&mut self, pending: &mut PendingFiles) -> OpResult<()>;
+    /// Copies the streamed tables of an earlier run in `folder` ahead of the
+    /// rows of this run, leaving out the last `skip_series` series rows.
+    fn carry_over(&mut self, _folder: &Path, _skip_series: usize) -> OpResult<usize> {
+        Err(OpError::new(OpErrorKind::InvalidArgsError)
+                .join_msg("Only the CSV output can continue the tables of an earlier run"))
+    }
+}
+
+/// The file formats results can be written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Csv,
+    Parquet,
+    Sqlite,
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> OpResult<Self> {
+        match name {
+            "csv" => Ok(OutputFormat::Csv),
+            "parquet" => Ok(OutputFormat::Parquet),
+            "sqlite" => Ok(OutputFormat::Sqlite),
+            _ => Err(OpError::new(OpErrorKind::InvalidArgsError)
+                         .join_msg(&format!("Unknown output format `{}`", name))),
+        }
+    }
+
+    /// Creates an output writing the selected tables into `folder`.
+    pub fn create(&self, folder: &Path, tables: OutputTables) -> OpResult<Box<TaintOutput>> {
+        match *self {
+            OutputFormat::Csv => Ok(Box::new(try!(CsvOutput::new(folder, tables)))),
+            OutputFormat::Parquet => Ok(Box::new(try!(ParquetOutput::new(folder, tables)))),
+            OutputFormat::Sqlite => Ok(Box::new(try!(SqliteOutput::new(folder, tables)))),
+        }
+    }
+}
+
+/// Quotes a CSV field if it contains a separator, a quote or a line break.
+pub fn csv_escape(s: &str) -> String {
+    if s.contains(',') || s.contains('"') || s.contains('\n') || s.contains('\r') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        String::from(s)
+    }
+}
+
+/// Escapes text for XML and HTML, in content and in quoted attributes.
+pub fn markup_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Hex encoded SHA-256 of a file.
+pub fn sha256_file(path: &Path) -> OpResult<String> {
+    let mut file = try!(File::open(path));
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1 << 20];
+    loop {
+        let read = try!(file.read(&mut buffer));
+        if read == 0 {
+            break;
+        }
+        hasher.input(&buffer[..read]);
+    }
+    Ok(hasher.result_str())
+}
+
+/// `<path>.tmp`, where an output is written before it is committed.
+pub fn tmp_path(path: &Path) -> PathBuf {
+    with_suffix(path, "tmp")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    name.push(format!(".{}", suffix));
+    path.with_file_name(name)
+}
+
+fn rename_file(from: &Path, to: &Path) -> OpResult<()> {
+    fs::rename(from, to).map_err(|err| {
+        OpError::from(err).join_msg(&format!("Unable to rename {} to {}", from.display(), to.display()))
+    })
+}
+
+/// Flushes a file that has already been closed from the OS cache to disk.
+pub fn sync_file(path: &Path) -> OpResult<()> {
+    let file = try!(File::open(path).map_err(|err| {
+        OpError::from(err).join_msg(&format!("Unable to open {}", path.display()))
+    }));
+    try!(file.sync_all());
+    Ok(())
+}
+
+/// Output files that are complete and synced under `<name>.tmp`, waiting to
+/// be moved to their final names all together.
+pub struct PendingFiles {
+    files: Vec<(PathBuf, PathBuf)>, // (temporary, final)
+}
+
+impl PendingFiles {
+    pub fn new() -> Self {
+        PendingFiles { files: Vec::new() }
+    }
+
+    /// Adds a temporary file that has been written and synced completely.
+    pub fn add(&mut self, tmp: PathBuf, path: PathBuf) {
+        self.files.push((tmp, path));
+    }
+
+    /// The (temporary, final) names of the files added so far.
+    pub fn files(&self) -> &[(PathBuf, PathBuf)] {
+        &self.files
+    }
+
+    /// Moves all files to their final names. Outputs of an earlier run are
+    /// kept aside until every rename has worked, so that a failure restores
+    /// them and leaves the new files under their temporary names.
+    pub fn commit(self) -> OpResult<Vec<PathBuf>> {
+        for &(ref tmp, _) in self.files.iter() {
+            if !tmp.exists() {
+                return Err(OpError::new(OpErrorKind::RuntimeError)
+                               .join_msg(&format!("Output {} is missing, nothing was committed", tmp.display())));
+            }
+        }
+
+        let mut kept_aside: Vec<(PathBuf, PathBuf)> = Vec::new();
+        let mut renamed: Vec<(PathBuf, PathBuf)> = Vec::new();
+        let result = (|| -> OpResult<()> {
+            for &(_, ref path) in self.files.iter() {
+                if path.exists() {
+                    let previous = with_suffix(path, "prev");
+                    try!(rename_file(path, &previous));
+                    kept_aside.push((path.clone(), previous));
+                }
+            }
+            for &(ref tmp, ref path) in self.files.iter() {
+                try!(rename_file(tmp, path));
+                renamed.push((tmp.clone(), path.clone()));
+            }
+            Ok(())
+        })();
+
+        if let Err(err) = result {
+            for &(ref tmp, ref path) in renamed.iter().rev() {
+                let _ = fs::rename(path, tmp);
+            }
+            for &(ref path, ref previous) in kept_aside.iter().rev() {
+                let _ = fs::rename(previous, path);
+            }
+            return Err(err.join_msg("No output was committed, the new files are left under their .tmp names"));
+        }
+
+        for &(_, ref previous) in kept_aside.iter() {
+            if let Err(err) = fs::remove_file(previous) {
+                warn!(target: "output", "Unable to remove {}: {}", previous.display(), err);
+            }
+        }
+        // The renames themselves are only durable once their folders are synced
+        let mut folders: Vec<&Path> = self.files.iter().filter_map(|&(_, ref path)| path.parent()).collect();
+        folders.sort();
+        folders.dedup();
+        for folder in folders {
+            if let Ok(dir) = File::open(folder) {
+                let _ = dir.sync_all();
+            }
+        }
+        Ok(self.files.into_iter().map(|(_, path)| path).collect())
+    }
+}
+
+/// Writes a whole file to `<path>.tmp`, syncs it and adds it to `pending`.
+pub fn write_pending<F>(path: &Path, pending: &mut PendingFiles, write: F) -> OpResult<()>
+    where F: FnOnce(&mut BufWriter<File>) -> OpResult<()>
+{
+    let tmp = tmp_path(path);
+    {
+        let mut writer = BufWriter::new(try!(File::create(&tmp)));
+        try!(write(&mut writer));
+        try!(writer.flush());
+        try!(writer.get_ref().sync_all());
+    }
+    pending.add(tmp, path.to_path_buf());
+    Ok(())
+}
+
+/// Writes a whole file through `<path>.tmp` and moves it into place once it is complete.
+pub fn write_file<F>(path: &Path, write: F) -> OpResult<()>
+    where F: FnOnce(&mut BufWriter<File>) -> OpResult<()>
+{
+    let mut pending = PendingFiles::new();
+    try!(write_pending(path, &mut pending, write));
+    try!(pending.commit());
+    Ok(())
+}
+
+fn create_writer(path: PathBuf) -> OpResult<LineWriter<File>> {
+    let file = match OpenOptions::new()
+              .write(true)
+              .create(true)
+              .truncate(true)
+              .open(&path) {
+        Ok(f) => f,
+        Err(err) => return Err(OpError::from(err)),
+    };
+    Ok(LineWriter::new(file))
+}
+
+fn create_optional_writer(enabled: bool, path: PathBuf) -> OpResult<Option<LineWriter<File>>> {
+    if enabled {
+        Ok(Some(try!(create_writer(path))))
+    } else {
+        Ok(None)
+    }
+}
+
+fn csv_error(path: &Path, err: csv::Error) -> OpError {
+    OpError::new(OpErrorKind::RuntimeError).join_msg(&format!("Unable to write {}: {}", path.display(), err))
+}
+
+/// Writes the results as plain CSV files, the original format of the tool.
+pub struct CsvOutput {
+    folder: PathBuf,
+    address_info_writer: Option<LineWriter<File>>,  // The file for address information
+    utxo_writer: Option<LineWriter<File>>,          // The file for utxo information
+    taint_mapping_writer: Option<LineWriter<File>>, // The file for taint mapping storage
+    overlap_writer: Option<LineWriter<File>>,       // The file for overlapping taint information
+    series_writer: Option<LineWriter<File>>,        // The file for the per-label taint time series
+    address_writer: Option<LineWriter<File>>,       // The file for per-address aggregated taint
+    tainted_utxo_writer: Option<csv::Writer<File>>,  // The file for the tainted subset of the UTXO set
+    flow_writer: Option<LineWriter<File>>,          // The file for taint flows
+}
+
+impl CsvOutput {
+    pub fn new(folder: &Path, tables: OutputTables) -> OpResult<Self> {
+        Ok(CsvOutput {
+            folder: folder.to_path_buf(),
+            taint_mapping_writer: try!(create_optional_writer(tables.labels, folder.join("taint_mapper.csv.tmp"))),
+            address_info_writer: try!(create_optional_writer(tables.address_info, folder.join("address_info.csv.tmp"))),
+            utxo_writer: try!(create_optional_writer(tables.utxo, folder.join("taint_utxo.csv.tmp"))),
+            overlap_writer: try!(create_optional_writer(tables.overlaps, folder.join("taint_overlap.csv.tmp"))),
+            series_writer: try!(create_optional_writer(tables.series, folder.join("taint_series.csv.tmp"))),
+            address_writer: try!(create_optional_writer(tables.address_balances, folder.join("address_balances.csv.tmp"))),
+            flow_writer: try!(create_optional_writer(tables.flows, folder.join("taint_flows.csv.tmp"))),
+            tainted_utxo_writer: if tables.tainted_utxos {
+                Some(try!(CsvOutput::create_tainted_utxo_writer(folder.join("tainted_utxo.csv.tmp"))))
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Creates the tainted UTXO CSV writer and writes its header. The schema
+    /// version is recorded in the manifest.
+    fn create_tainted_utxo_writer(path: PathBuf) -> OpResult<csv::Writer<File>> {
+        let file = try!(File::create(&path));
+        let mut writer = csv::Writer::from_writer(file);
+        try!(writer.write(["txid", "vout", "address", "value", "tainted_value", "taint"].iter().cloned())
+             .map_err(|err| csv_error(&path, err)));
+        Ok(writer)
+    }
+
+    /// Copies the lines of `folder/name` into `writer`, without the last `skip` ones.
+    /// The file is streamed, only the last `skip` lines read are held back.
+    fn copy_lines(writer: &mut Option<LineWriter<File>>, folder: &Path, name: &str, skip: usize) -> OpResult<usize> {
+        let writer = match *writer {
+            Some(ref mut writer) => writer,
+            None => return Ok(0),
+        };
+        let path = folder.join(name);
+        let file = try!(File::open(&path).map_err(|err| {
+            OpError::from(err).join_msg(&format!("The earlier run has no {}", path.display()))
+        }));
+        let mut held: VecDeque<String> = VecDeque::with_capacity(skip + 1);
+        let mut kept = 0;
+        for line in BufReader::new(file).lines() {
+            held.push_back(try!(line));
+            if held.len() > skip {
+                let line = held.pop_front().unwrap();
+                try!(writer.write_all(line.as_bytes()));
+                try!(writer.write_all(b"\n"));
+                kept += 1;
+            }
+        }
+        Ok(kept)
+    }
+
+    fn finish_writer(&self, writer: Option<LineWriter<File>>, name: &str, pending: &mut PendingFiles) -> OpResult<()> {
+        if let Some(mut writer) = writer {
+            try!(writer.flush());
+            try!(writer.get_ref().sync_all());
+            let path = self.folder.join(name);
+            pending.add(tmp_path(&path), path);
+        }
+        Ok(())
+    }
+}
+
+impl TaintOutput for CsvOutput {
+    fn write_label(&mut self, name: &str, id: u16) -> OpResult<()> {
+        if let Some(ref mut writer) = self.taint_mapping_writer {
+            try!(writer.write_all(format!("{},{}\n", name, id).as_bytes()));
+        }
+        Ok(())
+    }
+
+    fn write_overlap(&mut self, overlap: &TaintOverlap) -> OpResult<()> {
+        if let Some(ref mut writer) = self.overlap_writer {
+            try!(writer.write_all(format!("{},{},{},{},{}\n", overlap.left, overlap.right, overlap.value, overlap.txid, overlap.location).as_bytes()));
+        }
+        Ok(())
+    }
+
+    fn write_address_info(&mut self, tx_outpoint: &TxOutpoint, info: &AddressInfo) -> OpResult<()> {
+        if let Some(ref mut writer) = self.address_info_writer {
+            try!(writer.write_all(format!("{},{}\n", tx_outpoint, info).as_bytes()));
+        }
+        Ok(())
+    }
+
+    fn write_utxo(&mut self, tx_outpoint: &TxOutpoint, address: &str) -> OpResult<()> {
+        if let Some(ref mut writer) = self.utxo_writer {
+            try!(writer.write_all(format!("{},{},{}\n",
+                                          arr_to_hex_swapped(&tx_outpoint.txid),
+                                          tx_outpoint.index,
+                                          address)
+                                      .as_bytes()));
+        }
+        Ok(())
+    }
+
+    fn write_series_sample(&mut self, sample: &SeriesSample) -> OpResult<()> {
+        if let Some(ref mut writer) = self.series_writer {
+            try!(writer.write_all(format!("{},{},{},{},{}\n", sample.height, sample.date, sample.label, sample.value, sample.outputs).as_bytes()));
+        }
+        Ok(())
+    }
+
+    fn write_address_balance(&mut self, balance: &AddressBalance) -> OpResult<()> {
+        if let Some(ref mut writer) = self.address_writer {
+            let taint_str = balance.taints.iter().fold(String::new(), |a, (name, value)| {a + &name.to_string() + " " + &value.to_string() + ","});
+            try!(writer.write_all(format!("{},{},{},{}\n", balance.address, balance.outputs, balance.balance, taint_str).as_bytes()));
+        }
+        Ok(())
+    }
+
+    fn write_tainted_utxo(&mut self, tx_outpoint: &TxOutpoint, address: &str, info: &AddressInfo) -> OpResult<()> {
+        let folder = &self.folder;
+        if let Some(ref mut writer) = self.tainted_utxo_writer {
+            let (tainted_value, taint_str) = match info.tainted_balance {
+                Some(ref x) => {
+                    let parts = x.iter().filter(|tp| tp.name != 0);
+                    (parts.clone().fold(0, |a, tp| a + tp.value),
+                     parts.map(|tp| format!("{}:{}", tp.name, tp.value)).collect::<Vec<String>>().join(";"))
+                },
+                None => (0, String::new()),
+            };
+            let row = vec![arr_to_hex_swapped(&tx_outpoint.txid),
+                           tx_outpoint.index.to_string(),
+                           String::from(address),
+                           info.current_balance.to_string(),
+                           tainted_value.to_string(),
+                           taint_str];
+            try!(writer.write(row.into_iter()).map_err(|err| csv_error(&folder.join("tainted_utxo.csv.tmp"), err)));
+        }
+        Ok(())
+    }
+
+    fn write_flow(&mut self, flow: &TaintFlow) -> OpResult<()> {
+        if let Some(ref mut writer) = self.flow_writer {
+            let taint_str = flow.taint.iter().fold(String::new(), |a, k| {a + &k.name.to_string() + " " + &k.value.to_string() + ","});
+            try!(writer.write_all(format!("{},{},{},{},{},{},{}\n",
+                                          flow.height,
+                                          flow.txid,
+                                          flow.kind.as_str(),
+                                          arr_to_hex_swapped(&flow.outpoint.txid),
+                                          flow.outpoint.index,
+                                          flow.value,
+                                          taint_str)
+                                      .as_bytes()));
+        }
+        Ok(())
+    }
+
+    fn write_metadata(&mut self, _key: &str, _value: &str) -> OpResult<()> {
+        // The CSV files have no place for run metadata
+        Ok(())
+    }
+
+    fn finish(&mut self, pending: &mut PendingFiles) -> OpResult<()> {
+        let writers = vec![
+            (self.taint_mapping_writer.take(), "taint_mapper.csv"),
+            (self.overlap_writer.take(), "taint_overlap.csv"),
+            (self.utxo_writer.take(), "taint_utxo.csv"),
+            (self.address_info_writer.take(), "address_info.csv"),
+            (self.series_writer.take(), "taint_series.csv"),
+            (self.address_writer.take(), "address_balances.csv"),
+            (self.flow_writer.take(), "taint_flows.csv"),
+        ];
+        for (writer, name) in writers {
+            try!(self.finish_writer(writer, name, pending));
+        }
+        if let Some(mut writer) = self.tainted_utxo_writer.take() {
+            let path = self.folder.join("tainted_utxo.csv");
+            try!(writer.flush().map_err(|err| csv_error(&tmp_path(&path), err)));
+            drop(writer);
+            try!(sync_file(&tmp_path(&path)));
+            pending.add(tmp_path(&path), path);
+        }
+        Ok(())
+    }
+
+    fn carry_over(&mut self, folder: &Path, skip_series: usize) -> OpResult<usize> {
+        let mut copied = try!(CsvOutput::copy_lines(&mut self.overlap_writer, folder, "taint_overlap.csv", 0));
+        copied += try!(CsvOutput::copy_lines(&mut self.series_writer, folder, "taint_series.csv", skip_series));
+        copied += try!(CsvOutput::copy_lines(&mut self.flow_writer, folder, "taint_flows.csv", 0));
+        Ok(copied)
+    }
+}
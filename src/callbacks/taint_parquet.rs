@@ -0,0 +1,452 @@
+extern crate parquet;
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use self::parquet::data_type::{ByteArray, ByteArrayType, DataType, Int32Type, Int64Type};
+use self::parquet::errors::ParquetError;
+use self::parquet::file::metadata::KeyValue;
+use self::parquet::file::properties::WriterProperties;
+use self::parquet::file::writer::{SerializedFileWriter, SerializedRowGroupWriter};
+use self::parquet::schema::parser::parse_message_type;
+
+use errors::{OpError, OpErrorKind, OpResult};
+
+use blockchain::proto::tx::TxOutpoint;
+use blockchain::utils::arr_to_hex_swapped;
+
+use callbacks::taint_engine::{AddressInfo, TaintPart};
+use callbacks::taint_output::{TaintOutput, OutputTables, TaintOverlap, SeriesSample, AddressBalance, TaintFlow,
+                              PendingFiles, TAINTED_UTXO_SCHEMA_VERSION, sync_file};
+
+/// Rows buffered per table before they are written out as a row group.
+const ROW_GROUP_SIZE: usize = 1 << 20;
+
+/// A taint vector is stored as a nullable list of (label, amount) pairs.
+/// Clean outputs without any taint information are null.
+const TAINT_FIELD: &'static str = "
+    optional group taint (LIST) {
+        repeated group list {
+            required int32 label;
+            required int64 amount;
+        }
+    }";
+
+fn parquet_to_op_error(err: ParquetError) -> OpError {
+    OpError::new(OpErrorKind::RuntimeError).join_msg(&format!("Parquet error: {}", err))
+}
+
+/// Buffered values of one column. A taint column spans two leaf columns
+/// (label and amount) that share their definition and repetition levels.
+enum Column {
+    Int32(Vec<i32>),
+    Int64(Vec<i64>),
+    Utf8(Vec<ByteArray>),
+    Taint {
+        labels: Vec<i32>,
+        amounts: Vec<i64>,
+        def_levels: Vec<i16>,
+        rep_levels: Vec<i16>,
+    },
+}
+
+impl Column {
+    fn taint() -> Self {
+        Column::Taint {
+            labels: Vec::new(),
+            amounts: Vec::new(),
+            def_levels: Vec::new(),
+            rep_levels: Vec::new(),
+        }
+    }
+
+    fn push_i32(&mut self, value: i32) {
+        match *self {
+            Column::Int32(ref mut values) => values.push(value),
+            _ => panic!("Column is not an int32 column"),
+        }
+    }
+
+    fn push_i64(&mut self, value: i64) {
+        match *self {
+            Column::Int64(ref mut values) => values.push(value),
+            _ => panic!("Column is not an int64 column"),
+        }
+    }
+
+    fn push_str(&mut self, value: &str) {
+        match *self {
+            Column::Utf8(ref mut values) => values.push(ByteArray::from(value)),
+            _ => panic!("Column is not a string column"),
+        }
+    }
+
+    /// Appends one taint vector. `None` and empty vectors are stored as null.
+    fn push_taint<I>(&mut self, parts: Option<I>)
+        where I: Iterator<Item = (u16, u64)>
+    {
+        match *self {
+            Column::Taint { ref mut labels, ref mut amounts, ref mut def_levels, ref mut rep_levels } => {
+                let mut first = true;
+                if let Some(parts) = parts {
+                    for (label, amount) in parts {
+                        labels.push(label as i32);
+                        amounts.push(amount as i64);
+                        def_levels.push(2);
+                        rep_levels.push(if first { 0 } else { 1 });
+                        first = false;
+                    }
+                }
+                if first {
+                    def_levels.push(0);
+                    rep_levels.push(0);
+                }
+            },
+            _ => panic!("Column is not a taint column"),
+        }
+    }
+
+    fn write(&mut self, row_group: &mut SerializedRowGroupWriter<File>) -> OpResult<()> {
+        match *self {
+            Column::Int32(ref mut values) => {
+                try!(write_leaf::<Int32Type>(row_group, values, None, None));
+                values.clear();
+            },
+            Column::Int64(ref mut values) => {
+                try!(write_leaf::<Int64Type>(row_group, values, None, None));
+                values.clear();
+            },
+            Column::Utf8(ref mut values) => {
+                try!(write_leaf::<ByteArrayType>(row_group, values, None, None));
+                values.clear();
+            },
+            Column::Taint { ref mut labels, ref mut amounts, ref mut def_levels, ref mut rep_levels } => {
+                try!(write_leaf::<Int32Type>(row_group, labels, Some(&def_levels[..]), Some(&rep_levels[..])));
+                try!(write_leaf::<Int64Type>(row_group, amounts, Some(&def_levels[..]), Some(&rep_levels[..])));
+                labels.clear();
+                amounts.clear();
+                def_levels.clear();
+                rep_levels.clear();
+            },
+        }
+        Ok(())
+    }
+}
+
+fn write_leaf<T: DataType>(row_group: &mut SerializedRowGroupWriter<File>, values: &[T::T],
+                           def_levels: Option<&[i16]>, rep_levels: Option<&[i16]>) -> OpResult<()> {
+    let mut column = match try!(row_group.next_column().map_err(parquet_to_op_error)) {
+        Some(column) => column,
+        None => return Err(OpError::new(OpErrorKind::RuntimeError)
+                               .join_msg("Parquet schema has fewer columns than buffered")),
+    };
+    try!(column.typed::<T>().write_batch(values, def_levels, rep_levels).map_err(parquet_to_op_error));
+    try!(column.close().map_err(parquet_to_op_error));
+    Ok(())
+}
+
+/// One Parquet file being written, buffering up to `ROW_GROUP_SIZE` rows.
+struct ParquetTable {
+    name: &'static str,
+    writer: SerializedFileWriter<File>,
+    columns: Vec<Column>,
+    buffered: usize,
+    rows: usize,
+}
+
+impl ParquetTable {
+    fn new(folder: &Path, name: &'static str, schema: &str, columns: Vec<Column>, version: Option<u32>) -> OpResult<Self> {
+        let schema = Arc::new(try!(parse_message_type(schema).map_err(parquet_to_op_error)));
+        let metadata = version.map(|v| vec![KeyValue::new(String::from("schema_version"), v.to_string())]);
+        let props = Arc::new(WriterProperties::builder().set_key_value_metadata(metadata).build());
+        let file = try!(File::create(folder.join(format!("{}.parquet.tmp", name))));
+        Ok(ParquetTable {
+            name: name,
+            writer: try!(SerializedFileWriter::new(file, schema, props).map_err(parquet_to_op_error)),
+            columns: columns,
+            buffered: 0,
+            rows: 0,
+        })
+    }
+
+    /// Marks the end of a row, the caller has pushed one value to every column.
+    fn end_row(&mut self) -> OpResult<()> {
+        self.buffered += 1;
+        if self.buffered >= ROW_GROUP_SIZE {
+            try!(self.flush_row_group());
+        }
+        Ok(())
+    }
+
+    fn flush_row_group(&mut self) -> OpResult<()> {
+        if self.buffered == 0 {
+            return Ok(());
+        }
+        let mut row_group = try!(self.writer.next_row_group().map_err(parquet_to_op_error));
+        for column in self.columns.iter_mut() {
+            try!(column.write(&mut row_group));
+        }
+        try!(row_group.close().map_err(parquet_to_op_error));
+        self.rows += self.buffered;
+        self.buffered = 0;
+        Ok(())
+    }
+
+    /// Writes the remaining rows and the file footer, which carries `metadata`
+    /// next to the schema version. Returns the number of rows.
+    fn close(mut self, metadata: &[KeyValue]) -> OpResult<usize> {
+        try!(self.flush_row_group());
+        for kv in metadata.iter() {
+            self.writer.append_key_value_metadata(kv.clone());
+        }
+        try!(self.writer.close().map_err(parquet_to_op_error));
+        debug!(target: "FIFO [parquet]", "Wrote {} rows to {}.parquet", self.rows, self.name);
+        Ok(self.rows)
+    }
+}
+
+fn create_optional_table(enabled: bool, folder: &Path, name: &'static str, schema: &str, columns: Vec<Column>)
+                         -> OpResult<Option<ParquetTable>> {
+    create_versioned_table(enabled, folder, name, schema, columns, None)
+}
+
+fn create_versioned_table(enabled: bool, folder: &Path, name: &'static str, schema: &str, columns: Vec<Column>,
+                          version: Option<u32>) -> OpResult<Option<ParquetTable>> {
+    if enabled {
+        Ok(Some(try!(ParquetTable::new(folder, name, schema, columns, version))))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Writes the results as Parquet files, one per table, so they can be loaded
+/// into dataframes without parsing CSV.
+pub struct ParquetOutput {
+    folder: PathBuf,
+    address_info: Option<ParquetTable>,
+    utxo: Option<ParquetTable>,
+    labels: Option<ParquetTable>,
+    overlaps: Option<ParquetTable>,
+    series: Option<ParquetTable>,
+    address_balances: Option<ParquetTable>,
+    tainted_utxos: Option<ParquetTable>,
+    flows: Option<ParquetTable>,
+    metadata: Vec<KeyValue>, // Run metadata, written into the footer of every table
+}
+
+impl ParquetOutput {
+    pub fn new(folder: &Path, tables: OutputTables) -> OpResult<Self> {
+        Ok(ParquetOutput {
+            folder: folder.to_path_buf(),
+            address_info: try!(create_optional_table(tables.address_info, folder, "address_info",
+                &format!("message address_info {{
+                    required binary outpoint (UTF8);
+                    required binary timestamp (UTF8);
+                    required int64 current_balance;
+                    {}
+                }}", TAINT_FIELD),
+                vec![Column::Utf8(Vec::new()), Column::Utf8(Vec::new()), Column::Int64(Vec::new()), Column::taint()])),
+            utxo: try!(create_optional_table(tables.utxo, folder, "taint_utxo",
+                "message taint_utxo {
+                    required binary txid (UTF8);
+                    required int32 vout;
+                    required binary address (UTF8);
+                }",
+                vec![Column::Utf8(Vec::new()), Column::Int32(Vec::new()), Column::Utf8(Vec::new())])),
+            labels: try!(create_optional_table(tables.labels, folder, "taint_mapper",
+                "message taint_mapper {
+                    required binary name (UTF8);
+                    required int32 label;
+                }",
+                vec![Column::Utf8(Vec::new()), Column::Int32(Vec::new())])),
+            overlaps: try!(create_optional_table(tables.overlaps, folder, "taint_overlap",
+                "message taint_overlap {
+                    required int32 left;
+                    required int32 right;
+                    required int64 amount;
+                    required binary txid (UTF8);
+                    required int64 location;
+                }",
+                vec![Column::Int32(Vec::new()), Column::Int32(Vec::new()), Column::Int64(Vec::new()),
+                     Column::Utf8(Vec::new()), Column::Int64(Vec::new())])),
+            series: try!(create_optional_table(tables.series, folder, "taint_series",
+                "message taint_series {
+                    required int64 height;
+                    required binary date (UTF8);
+                    required int32 label;
+                    required int64 value;
+                    required int64 outputs;
+                }",
+                vec![Column::Int64(Vec::new()), Column::Utf8(Vec::new()), Column::Int32(Vec::new()),
+                     Column::Int64(Vec::new()), Column::Int64(Vec::new())])),
+            address_balances: try!(create_optional_table(tables.address_balances, folder, "address_balances",
+                &format!("message address_balances {{
+                    required binary address (UTF8);
+                    required int64 outputs;
+                    required int64 balance;
+                    {}
+                }}", TAINT_FIELD),
+                vec![Column::Utf8(Vec::new()), Column::Int64(Vec::new()), Column::Int64(Vec::new()), Column::taint()])),
+            tainted_utxos: try!(create_versioned_table(tables.tainted_utxos, folder, "tainted_utxo",
+                &format!("message tainted_utxo {{
+                    required binary txid (UTF8);
+                    required int32 vout;
+                    required binary address (UTF8);
+                    required int64 value;
+                    required int64 tainted_value;
+                    {}
+                }}", TAINT_FIELD),
+                vec![Column::Utf8(Vec::new()), Column::Int32(Vec::new()), Column::Utf8(Vec::new()),
+                     Column::Int64(Vec::new()), Column::Int64(Vec::new()), Column::taint()],
+                Some(TAINTED_UTXO_SCHEMA_VERSION))),
+            flows: try!(create_optional_table(tables.flows, folder, "taint_flows",
+                &format!("message taint_flows {{
+                    required int64 height;
+                    required binary txid (UTF8);
+                    required binary kind (UTF8);
+                    required binary outpoint_txid (UTF8);
+                    required int32 outpoint_vout;
+                    required int64 value;
+                    {}
+                }}", TAINT_FIELD),
+                vec![Column::Int64(Vec::new()), Column::Utf8(Vec::new()), Column::Utf8(Vec::new()),
+                     Column::Utf8(Vec::new()), Column::Int32(Vec::new()), Column::Int64(Vec::new()), Column::taint()])),
+            metadata: Vec::new(),
+        })
+    }
+
+    fn close_table(&self, table: Option<ParquetTable>, pending: &mut PendingFiles) -> OpResult<()> {
+        if let Some(table) = table {
+            let name = table.name;
+            try!(table.close(&self.metadata));
+            let tmp = self.folder.join(format!("{}.parquet.tmp", name));
+            try!(sync_file(&tmp));
+            pending.add(tmp, self.folder.join(format!("{}.parquet", name)));
+        }
+        Ok(())
+    }
+}
+
+fn taint_parts<'a>(taint: &'a [TaintPart]) -> Option<Box<Iterator<Item = (u16, u64)> + 'a>> {
+    if taint.is_empty() {
+        None
+    } else {
+        Some(Box::new(taint.iter().map(|tp| (tp.name, tp.value))))
+    }
+}
+
+impl TaintOutput for ParquetOutput {
+    fn write_label(&mut self, name: &str, id: u16) -> OpResult<()> {
+        if let Some(ref mut table) = self.labels {
+            table.columns[0].push_str(name);
+            table.columns[1].push_i32(id as i32);
+            try!(table.end_row());
+        }
+        Ok(())
+    }
+
+    fn write_overlap(&mut self, overlap: &TaintOverlap) -> OpResult<()> {
+        if let Some(ref mut table) = self.overlaps {
+            table.columns[0].push_i32(overlap.left as i32);
+            table.columns[1].push_i32(overlap.right as i32);
+            table.columns[2].push_i64(overlap.value as i64);
+            table.columns[3].push_str(overlap.txid);
+            table.columns[4].push_i64(overlap.location as i64);
+            try!(table.end_row());
+        }
+        Ok(())
+    }
+
+    fn write_address_info(&mut self, tx_outpoint: &TxOutpoint, info: &AddressInfo) -> OpResult<()> {
+        if let Some(ref mut table) = self.address_info {
+            table.columns[0].push_str(&tx_outpoint.to_string());
+            table.columns[1].push_str(&info.timestamp());
+            table.columns[2].push_i64(info.current_balance as i64);
+            table.columns[3].push_taint(taint_parts(info.taint()));
+            try!(table.end_row());
+        }
+        Ok(())
+    }
+
+    fn write_utxo(&mut self, tx_outpoint: &TxOutpoint, address: &str) -> OpResult<()> {
+        if let Some(ref mut table) = self.utxo {
+            table.columns[0].push_str(&arr_to_hex_swapped(&tx_outpoint.txid));
+            table.columns[1].push_i32(tx_outpoint.index as i32);
+            table.columns[2].push_str(address);
+            try!(table.end_row());
+        }
+        Ok(())
+    }
+
+    fn write_series_sample(&mut self, sample: &SeriesSample) -> OpResult<()> {
+        if let Some(ref mut table) = self.series {
+            table.columns[0].push_i64(sample.height as i64);
+            table.columns[1].push_str(sample.date);
+            table.columns[2].push_i32(sample.label as i32);
+            table.columns[3].push_i64(sample.value as i64);
+            table.columns[4].push_i64(sample.outputs as i64);
+            try!(table.end_row());
+        }
+        Ok(())
+    }
+
+    fn write_address_balance(&mut self, balance: &AddressBalance) -> OpResult<()> {
+        if let Some(ref mut table) = self.address_balances {
+            table.columns[0].push_str(balance.address);
+            table.columns[1].push_i64(balance.outputs as i64);
+            table.columns[2].push_i64(balance.balance as i64);
+            table.columns[3].push_taint(Some(balance.taints.iter().map(|(name, value)| (*name, *value))));
+            try!(table.end_row());
+        }
+        Ok(())
+    }
+
+    fn write_tainted_utxo(&mut self, tx_outpoint: &TxOutpoint, address: &str, info: &AddressInfo) -> OpResult<()> {
+        if let Some(ref mut table) = self.tainted_utxos {
+            let tainted_value = match info.tainted_balance {
+                Some(ref x) => x.iter().filter(|tp| tp.name != 0).fold(0, |a, tp| a + tp.value),
+                None => 0,
+            };
+            table.columns[0].push_str(&arr_to_hex_swapped(&tx_outpoint.txid));
+            table.columns[1].push_i32(tx_outpoint.index as i32);
+            table.columns[2].push_str(address);
+            table.columns[3].push_i64(info.current_balance as i64);
+            table.columns[4].push_i64(tainted_value as i64);
+            table.columns[5].push_taint(info.tainted_balance.as_ref().map(|x| {
+                x.iter().filter(|tp| tp.name != 0).map(|tp| (tp.name, tp.value))
+            }));
+            try!(table.end_row());
+        }
+        Ok(())
+    }
+
+    fn write_flow(&mut self, flow: &TaintFlow) -> OpResult<()> {
+        if let Some(ref mut table) = self.flows {
+            table.columns[0].push_i64(flow.height as i64);
+            table.columns[1].push_str(flow.txid);
+            table.columns[2].push_str(flow.kind.as_str());
+            table.columns[3].push_str(&arr_to_hex_swapped(&flow.outpoint.txid));
+            table.columns[4].push_i32(flow.outpoint.index as i32);
+            table.columns[5].push_i64(flow.value as i64);
+            table.columns[6].push_taint(Some(flow.taint.iter().map(|tp| (tp.name, tp.value))));
+            try!(table.end_row());
+        }
+        Ok(())
+    }
+
+    fn write_metadata(&mut self, key: &str, value: &str) -> OpResult<()> {
+        self.metadata.push(KeyValue::new(String::from(key), String::from(value)));
+        Ok(())
+    }
+
+    fn finish(&mut self, pending: &mut PendingFiles) -> OpResult<()> {
+        let tables = vec![self.labels.take(), self.overlaps.take(), self.utxo.take(), self.address_info.take(),
+                          self.series.take(), self.tainted_utxos.take(), self.address_balances.take(),
+                          self.flows.take()];
+        for table in tables {
+            try!(self.close_table(table, pending));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, App, SubCommand};
+
+use errors::OpResult;
+
+use callbacks::taint_results::{load_labels, read_address_info, read_utxos, read_overlaps, dirty_amount, normalize_outpoint};
+
+/// An unspent output that carries taint.
+pub struct IndexedOutpoint {
+    pub outpoint: String, // txid:index
+    pub address: String,
+    pub timestamp: String,
+    pub balance: u64,
+    pub taint: Vec<(u16, u64)>, // (label, amount) in FIFO order
+}
+
+impl IndexedOutpoint {
+    pub fn dirty(&self) -> u64 {
+        dirty_amount(&self.taint)
+    }
+
+    pub fn per_label(&self) -> BTreeMap<u16, u64> {
+        let mut per_label = BTreeMap::new();
+        for &(label, amount) in self.taint.iter().filter(|&&(label, _)| label != 0) {
+            *per_label.entry(label).or_insert(0) += amount;
+        }
+        per_label
+    }
+}
+
+/// Collisions between two labels, summed over the run.
+pub struct OverlapSummary {
+    pub kept: u16,
+    pub lost: u16,
+    pub count: usize,
+    pub value: u64,
+}
+
+/// Label totals over all tainted outpoints.
+pub struct LabelTotal {
+    pub label: u16,
+    pub name: String,
+    pub value: u64,
+    pub outputs: usize,
+}
+
+/// The tainted outpoints of a finished run, indexed by outpoint, address and label.
+///
+/// Clean outpoints are left out, which keeps the index small enough to hold
+/// in memory: an outpoint that is not found is either spent or clean.
+pub struct TaintIndex {
+    pub folder: PathBuf,
+    names: BTreeMap<u16, String>,
+    ids: BTreeMap<String, u16>,
+    outpoints: Vec<IndexedOutpoint>,
+    by_outpoint: HashMap<String, usize>,
+    by_address: HashMap<String, Vec<usize>>,
+    by_label: BTreeMap<u16, Vec<(u64, usize)>>, // label -> (amount, outpoint), largest first
+    overlaps: BTreeMap<(u16, u16), (usize, u64)>,
+}
+
+impl TaintIndex {
+    pub fn load(folder: &Path) -> OpResult<Self> {
+        let ids = try!(load_labels(folder));
+        let names: BTreeMap<u16, String> = ids.iter().map(|(name, id)| (*id, name.clone())).collect();
+
+        info!(target: "taintQuery", "Indexing address_info.csv...");
+        let mut outpoints = Vec::new();
+        let mut by_outpoint = HashMap::new();
+        try!(read_address_info(folder, |record| {
+            if dirty_amount(&record.taint) == 0 {
+                return;
+            }
+            by_outpoint.insert(record.outpoint.clone(), outpoints.len());
+            outpoints.push(IndexedOutpoint {
+                outpoint: record.outpoint,
+                address: String::new(),
+                timestamp: record.timestamp,
+                balance: record.balance,
+                taint: record.taint,
+            });
+        }));
+
+        // Snapshot folders have no UTXO set, their outpoints stay without address
+        let mut by_address: HashMap<String, Vec<usize>> = HashMap::new();
+        if folder.join("taint_utxo.csv").exists() {
+            info!(target: "taintQuery", "Indexing taint_utxo.csv...");
+            try!(read_utxos(folder, |outpoint, address| {
+                if let Some(&i) = by_outpoint.get(&outpoint) {
+                    outpoints[i].address = String::from(address);
+                    by_address.entry(String::from(address)).or_insert_with(Vec::new).push(i);
+                }
+            }));
+        }
+
+        let mut by_label: BTreeMap<u16, Vec<(u64, usize)>> = BTreeMap::new();
+        for (i, outpoint) in outpoints.iter().enumerate() {
+            for (label, amount) in outpoint.per_label() {
+                by_label.entry(label).or_insert_with(Vec::new).push((amount, i));
+            }
+        }
+        for holders in by_label.values_mut() {
+            holders.sort_by(|a, b| b.cmp(a));
+        }
+
+        let mut overlaps = BTreeMap::new();
+        if folder.join("taint_overlap.csv").exists() {
+            info!(target: "taintQuery", "Indexing taint_overlap.csv...");
+            try!(read_overlaps(folder, |overlap| {
+                let entry = overlaps.entry((overlap.left, overlap.right)).or_insert((0, 0));
+                entry.0 += 1;
+                entry.1 += overlap.value;
+            }));
+        }
+
+        info!(target: "taintQuery", "Indexed {} tainted outpoints of {} addresses.", outpoints.len(), by_address.len());
+        Ok(TaintIndex {
+            folder: folder.to_path_buf(),
+            names: names,
+            ids: ids,
+            outpoints: outpoints,
+            by_outpoint: by_outpoint,
+            by_address: by_address,
+            by_label: by_label,
+            overlaps: overlaps,
+        })
+    }
+
+    pub fn name(&self, label: u16) -> String {
+        self.names.get(&label).cloned().unwrap_or_else(|| label.to_string())
+    }
+
+    /// Resolves a taint name, or a numeric label id.
+    pub fn label(&self, name: &str) -> Option<u16> {
+        match self.ids.get(name) {
+            Some(id) => Some(*id),
+            None => name.parse::<u16>().ok().and_then(|id| if self.names.contains_key(&id) { Some(id) } else { None }),
+        }
+    }
+
+    /// Accepts `txid:index` as well as any other formatting of a txid followed by the index.
+    pub fn outpoint(&self, outpoint: &str) -> Option<&IndexedOutpoint> {
+        normalize_outpoint(outpoint).and_then(|o| self.by_outpoint.get(&o)).map(|&i| &self.outpoints[i])
+    }
+
+    pub fn address(&self, address: &str) -> Vec<&IndexedOutpoint> {
+        self.by_address.get(address).map(|v| v.iter().map(|&i| &self.outpoints[i]).collect()).unwrap_or_default()
+    }
+
+    /// Outpoints holding between `min` and `max` of `label`, largest first.
+    pub fn holders(&self, label: u16, min: u64, max: u64, limit: usize) -> Vec<(u64, &IndexedOutpoint)> {
+        self.by_label.get(&label).map(|holders| {
+            holders.iter()
+                .filter(|&&(amount, _)| min <= amount && amount <= max)
+                .take(limit)
+                .map(|&(amount, i)| (amount, &self.outpoints[i]))
+                .collect()
+        }).unwrap_or_default()
+    }
+
+    /// Outpoints whose taint, of any label, is between `min` and `max`, largest first.
+    pub fn by_value(&self, min: u64, max: u64, limit: usize) -> Vec<&IndexedOutpoint> {
+        let mut found: Vec<&IndexedOutpoint> = self.outpoints.iter()
+            .filter(|o| { let dirty = o.dirty(); min <= dirty && dirty <= max }).collect();
+        found.sort_by(|a, b| b.dirty().cmp(&a.dirty()).then(a.outpoint.cmp(&b.outpoint)));
+        found.truncate(limit);
+        found
+    }
+
+    /// Collisions in which `label` was kept or lost, largest amount first.
+    pub fn overlaps(&self, label: u16) -> Vec<OverlapSummary> {
+        let mut found: Vec<OverlapSummary> = self.overlaps.iter()
+            .filter(|&(&(kept, lost), _)| kept == label || lost == label)
+            .map(|(&(kept, lost), &(count, value))| OverlapSummary { kept: kept, lost: lost, count: count, value: value })
+            .collect();
+        found.sort_by(|a, b| b.value.cmp(&a.value));
+        found
+    }
+
+    pub fn labels(&self) -> Vec<LabelTotal> {
+        self.names.iter().filter(|&(id, _)| *id != 0).map(|(id, name)| {
+            let holders = self.by_label.get(id);
+            LabelTotal {
+                label: *id,
+                name: name.clone(),
+                value: holders.map(|h| h.iter().fold(0, |a, &(v, _)| a + v)).unwrap_or(0),
+                outputs: holders.map(|h| h.len()).unwrap_or(0),
+            }
+        }).collect()
+    }
+}
+
+const HELP: &'static str = "\
+outpoint <txid:index>          taint of an unspent output
+address <address>              tainted outputs of an address
+label <name|id> [min..max]     outputs holding a taint name, amounts in satoshi
+value <min> [max]              outputs whose taint is in a range of satoshi
+overlaps <name>                collisions a taint name was involved in
+labels                         all taint names with their remaining amount
+help                           this text
+quit                           leave the shell";
+
+fn taint_to_string(index: &TaintIndex, taint: &[(u16, u64)]) -> String {
+    let parts: Vec<String> = taint.iter().map(|&(label, amount)| format!("{} {}", index.name(label), amount)).collect();
+    parts.join(", ")
+}
+
+fn write_outpoint(out: &mut Write, index: &TaintIndex, o: &IndexedOutpoint) -> io::Result<()> {
+    writeln!(out, "{}  address {}  balance {}  created {}\n    taint: {}",
+             o.outpoint, o.address, o.balance, o.timestamp, taint_to_string(index, &o.taint))
+}
+
+fn parse_amount(arg: Option<&&str>, default: u64) -> Result<u64, String> {
+    match arg {
+        Some(s) => s.parse::<u64>().map_err(|_| format!("invalid amount `{}`", s)),
+        None => Ok(default),
+    }
+}
+
+/// Parses `min..max`, where a missing bound is open.
+fn parse_range(range: &str) -> Result<(u64, u64), String> {
+    let mut bounds = range.splitn(2, "..");
+    let min = match bounds.next() {
+        Some(s) if !s.is_empty() => try!(parse_amount(Some(&s), 0)),
+        _ => 0,
+    };
+    let max = match bounds.next() {
+        Some(s) if !s.is_empty() => try!(parse_amount(Some(&s), u64::max_value())),
+        _ => u64::max_value(),
+    };
+    Ok((min, max))
+}
+
+/// Runs one query and prints the answer. Errors in the query itself are printed, not returned.
+pub fn execute(index: &TaintIndex, query: &str, limit: usize, out: &mut Write) -> io::Result<()> {
+    let args: Vec<&str> = query.split_whitespace().collect();
+    if args.is_empty() {
+        return Ok(());
+    }
+    // Taint names may contain spaces, so everything after the command is the name
+    let rest = query.trim()[args[0].len()..].trim();
+    match args[0] {
+        "outpoint" | "o" => {
+            match index.outpoint(rest) {
+                Some(o) => try!(write_outpoint(out, index, o)),
+                None => try!(writeln!(out, "{} is spent, clean or unknown.", rest)),
+            }
+        },
+        "address" | "a" => {
+            let found = index.address(rest);
+            let mut total: BTreeMap<u16, u64> = BTreeMap::new();
+            for o in found.iter() {
+                try!(write_outpoint(out, index, o));
+                for (label, amount) in o.per_label() {
+                    *total.entry(label).or_insert(0) += amount;
+                }
+            }
+            let total: Vec<(u16, u64)> = total.into_iter().collect();
+            try!(writeln!(out, "{} tainted outputs, taint: {}", found.len(), taint_to_string(index, &total)));
+        },
+        "label" | "l" => {
+            // An optional `min..max` after the name or id, either bound may be left out
+            let (name, range) = match args.last() {
+                Some(last) if args.len() > 2 && last.contains("..") => (args[1..args.len() - 1].join(" "), parse_range(last)),
+                _ => (args[1..].join(" "), Ok((0, u64::max_value()))),
+            };
+            let (min, max) = match range {
+                Ok(range) => range,
+                Err(e) => return writeln!(out, "{}", e),
+            };
+            match index.label(&name) {
+                Some(label) => {
+                    let found = index.holders(label, min, max, limit);
+                    for &(amount, o) in found.iter() {
+                        try!(writeln!(out, "{}  {}  {}", o.outpoint, o.address, amount));
+                    }
+                    try!(writeln!(out, "{} outputs shown", found.len()));
+                },
+                None => try!(writeln!(out, "Unknown taint name `{}`", name)),
+            }
+        },
+        "value" | "v" => {
+            let (min, max) = match (parse_amount(args.get(1), 0), parse_amount(args.get(2), u64::max_value())) {
+                (Ok(min), Ok(max)) => (min, max),
+                (Err(e), _) | (_, Err(e)) => return writeln!(out, "{}", e),
+            };
+            let found = index.by_value(min, max, limit);
+            for o in found.iter() {
+                try!(write_outpoint(out, index, o));
+            }
+            try!(writeln!(out, "{} outputs shown", found.len()));
+        },
+        "overlaps" => {
+            match index.label(rest) {
+                Some(label) => {
+                    for o in index.overlaps(label) {
+                        try!(writeln!(out, "{} kept over {}: {} collisions, {} lost",
+                                      index.name(o.kept), index.name(o.lost), o.count, o.value));
+                    }
+                },
+                None => try!(writeln!(out, "Unknown taint name `{}`", rest)),
+            }
+        },
+        "labels" => {
+            for total in index.labels() {
+                try!(writeln!(out, "{:>5}  {}  {} in {} outputs", total.label, total.name, total.value, total.outputs));
+            }
+        },
+        "help" | "?" => try!(writeln!(out, "{}", HELP)),
+        other => try!(writeln!(out, "Unknown query `{}`, try `help`.", other)),
+    }
+    Ok(())
+}
+
+pub fn build_subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("taintQuery")
+        .about("Looks up outpoints, addresses and taint names in the dump folder of a finished run.")
+        .version("0.2")
+        .arg(Arg::with_name("dump-folder")
+                 .help("Dump folder of a finished taintFIFO run")
+                 .required(true))
+        .arg(Arg::with_name("query")
+                 .short("q")
+                 .long("query")
+                 .takes_value(true)
+                 .multiple(true)
+                 .number_of_values(1)
+                 .help("Run this query and exit instead of starting the shell, may be given more than once"))
+        .arg(Arg::with_name("limit")
+                 .long("limit")
+                 .takes_value(true)
+                 .help("Most outputs listed per query (default: 50)"))
+}
+
+pub fn run(matches: &ArgMatches) -> OpResult<()> {
+    let folder = PathBuf::from(matches.value_of("dump-folder").unwrap());
+    let limit = value_t!(matches, "limit", usize).unwrap_or(50);
+    let index = try!(TaintIndex::load(&folder));
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if let Some(queries) = matches.values_of("query") {
+        for query in queries {
+            try!(execute(&index, query, limit, &mut out));
+        }
+        return Ok(());
+    }
+
+    try!(writeln!(out, "{}\n", HELP));
+    let stdin = io::stdin();
+    loop {
+        try!(write!(out, "taint> "));
+        try!(out.flush());
+        let mut line = String::new();
+        if try!(stdin.lock().read_line(&mut line)) == 0 {
+            break;
+        }
+        match line.trim() {
+            "quit" | "exit" | "q" => break,
+            query => try!(execute(&index, query, limit, &mut out)),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,319 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, App, SubCommand};
+
+use errors::{OpError, OpErrorKind, OpResult};
+
+use callbacks::taint_output::{write_file, markup_escape};
+use callbacks::taint_results::{load_labels, read_address_info, read_utxos, read_overlaps, read_series, read_seeds};
+use callbacks::taint_svg::label_color;
+
+const CHART_WIDTH: f64 = 760.0;
+const CHART_HEIGHT: f64 = 220.0;
+const CHART_MARGIN: f64 = 50.0;
+
+const STYLE: &'static str = "
+body { font-family: sans-serif; margin: 2em; color: #222; }
+h1 { font-size: 1.6em; }
+h2 { border-bottom: 1px solid #ccc; padding-bottom: 0.2em; margin-top: 2em; }
+table { border-collapse: collapse; margin: 1em 0; }
+th, td { border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: left; }
+td.num { text-align: right; font-family: monospace; }
+td.id { font-family: monospace; font-size: 0.9em; }
+.swatch { display: inline-block; width: 0.9em; height: 0.9em; border: 1px solid #444; margin-right: 0.4em; }
+.note { color: #666; font-size: 0.9em; }
+svg text { font-size: 11px; }
+";
+
+/// What one label of the report is made of.
+struct LabelReport {
+    id: u16,
+    name: String,
+    seeds: Vec<(String, u64)>,            // Seeded transactions and their amount of the label
+    remaining: u64,                       // Held by unspent outputs at the end of the run
+    outputs: usize,                       // Unspent outputs holding some of it
+    holders: BinaryHeap<Reverse<(u64, String)>>, // The largest holders, smallest on top
+    lost: u64,                            // Overwritten by other labels in collisions
+    series: Vec<(usize, String, u64, usize)>, // height, date, value, outputs
+}
+
+impl LabelReport {
+    fn seeded(&self) -> u64 {
+        self.seeds.iter().fold(0, |a, &(_, v)| a + v)
+    }
+}
+
+/// Satoshi as an exact BTC amount.
+fn btc(satoshi: u64) -> String {
+    format!("{}.{:08}", satoshi / 100_000_000, satoshi % 100_000_000)
+}
+
+fn signed_btc(seeded: u64, accounted: u64) -> String {
+    if accounted > seeded {
+        format!("-{}", btc(accounted - seeded))
+    } else {
+        btc(seeded - accounted)
+    }
+}
+
+fn percent(part: u64, whole: u64) -> String {
+    if whole == 0 {
+        String::from("-")
+    } else {
+        format!("{:.2}%", part as f64 * 100.0 / whole as f64)
+    }
+}
+
+/// An inline SVG line chart of `points` (height, value); the dates label the x axis.
+fn line_chart(title: &str, color: &str, points: &[(usize, String, f64)]) -> String {
+    let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">\n",
+                          CHART_WIDTH + 2.0 * CHART_MARGIN, CHART_HEIGHT + 2.0 * CHART_MARGIN);
+    svg.push_str(&format!("<text x=\"{}\" y=\"20\" font-weight=\"bold\">{}</text>\n", CHART_MARGIN, markup_escape(title)));
+    if points.is_empty() {
+        svg.push_str("</svg>\n");
+        return svg;
+    }
+    let min_x = points[0].0 as f64;
+    let max_x = points[points.len() - 1].0 as f64;
+    let max_y = points.iter().fold(0.0, |a: f64, p| a.max(p.2));
+    let span_x = if max_x > min_x { max_x - min_x } else { 1.0 };
+    let span_y = if max_y > 0.0 { max_y } else { 1.0 };
+    let x = |h: usize| CHART_MARGIN + (h as f64 - min_x) / span_x * CHART_WIDTH;
+    let y = |v: f64| CHART_MARGIN + CHART_HEIGHT - v / span_y * CHART_HEIGHT;
+
+    // Axes, the top value and a few dated ticks
+    svg.push_str(&format!("<line x1=\"{0}\" y1=\"{1}\" x2=\"{0}\" y2=\"{2}\" stroke=\"#444\"/>\n",
+                          CHART_MARGIN, CHART_MARGIN, CHART_MARGIN + CHART_HEIGHT));
+    svg.push_str(&format!("<line x1=\"{0}\" y1=\"{1}\" x2=\"{2}\" y2=\"{1}\" stroke=\"#444\"/>\n",
+                          CHART_MARGIN, CHART_MARGIN + CHART_HEIGHT, CHART_MARGIN + CHART_WIDTH));
+    svg.push_str(&format!("<text x=\"{}\" y=\"{}\" text-anchor=\"end\">{}</text>\n", CHART_MARGIN - 4.0, CHART_MARGIN + 4.0, max_y));
+    svg.push_str(&format!("<text x=\"{}\" y=\"{}\" text-anchor=\"end\">0</text>\n", CHART_MARGIN - 4.0, CHART_MARGIN + CHART_HEIGHT + 4.0));
+    let ticks = ::std::cmp::min(points.len(), 5);
+    for i in 0..ticks {
+        let point = &points[if ticks > 1 { i * (points.len() - 1) / (ticks - 1) } else { 0 }];
+        svg.push_str(&format!("<text x=\"{:.1}\" y=\"{}\" text-anchor=\"middle\">{}</text>\n",
+                              x(point.0), CHART_MARGIN + CHART_HEIGHT + 16.0, markup_escape(&point.1)));
+        svg.push_str(&format!("<text x=\"{:.1}\" y=\"{}\" text-anchor=\"middle\" fill=\"#666\">{}</text>\n",
+                              x(point.0), CHART_MARGIN + CHART_HEIGHT + 30.0, point.0));
+    }
+
+    let line: Vec<String> = points.iter().map(|p| format!("{:.1},{:.1}", x(p.0), y(p.2))).collect();
+    svg.push_str(&format!("<polyline fill=\"none\" stroke=\"{}\" stroke-width=\"2\" points=\"{}\"/>\n", color, line.join(" ")));
+    svg.push_str("</svg>\n");
+    svg
+}
+
+pub fn build_subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("taintReport")
+        .about("Writes a self-contained HTML case report for one or more taint names.")
+        .version("0.2")
+        .arg(Arg::with_name("dump-folder")
+                 .help("Dump folder of a finished taintFIFO run")
+                 .required(true))
+        .arg(Arg::with_name("address-file")
+                 .long("address-file")
+                 .required(true)
+                 .takes_value(true)
+                 .help("The address file the run was seeded with"))
+        .arg(Arg::with_name("labels")
+                 .long("labels")
+                 .required(true)
+                 .takes_value(true)
+                 .multiple(true)
+                 .use_delimiter(true)
+                 .help("Taint names to report on, as in taint_mapper.csv"))
+        .arg(Arg::with_name("output")
+                 .short("o")
+                 .long("output")
+                 .takes_value(true)
+                 .help("HTML file to write (default: <dump-folder>/taint_report.html)"))
+        .arg(Arg::with_name("top")
+                 .long("top")
+                 .takes_value(true)
+                 .help("Number of largest holders listed per taint name (default: 25)"))
+}
+
+pub fn run(matches: &ArgMatches) -> OpResult<()> {
+    let folder = PathBuf::from(matches.value_of("dump-folder").unwrap());
+    let address_file = PathBuf::from(matches.value_of("address-file").unwrap());
+    let output = matches.value_of("output").map(PathBuf::from)
+        .unwrap_or_else(|| folder.join("taint_report.html"));
+    let top = value_t!(matches, "top", usize).unwrap_or(25);
+
+    let labels = try!(load_labels(&folder));
+    let names: BTreeMap<u16, String> = labels.iter().map(|(name, id)| (*id, name.clone())).collect();
+    let mut reports: BTreeMap<u16, LabelReport> = BTreeMap::new();
+    for name in matches.values_of("labels").unwrap() {
+        let id = match labels.get(name) {
+            Some(id) if *id != 0 => *id,
+            _ => return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                                .join_msg(&format!("Unknown taint name `{}`", name))),
+        };
+        reports.insert(id, LabelReport {
+            id: id,
+            name: String::from(name),
+            seeds: Vec::new(),
+            remaining: 0,
+            outputs: 0,
+            holders: BinaryHeap::new(),
+            lost: 0,
+            series: Vec::new(),
+        });
+    }
+
+    info!(target: "taintReport", "Reading the address file...");
+    for seed in try!(read_seeds(&address_file)) {
+        for &(ref name, value) in seed.taint.iter() {
+            if let Some(report) = labels.get(name).and_then(|id| reports.get_mut(id)) {
+                report.seeds.push((seed.txid.clone(), value));
+            }
+        }
+    }
+
+    info!(target: "taintReport", "Reading address_info.csv...");
+    try!(read_address_info(&folder, |record| {
+        for report in reports.values_mut() {
+            let amount = record.amount_of(report.id);
+            if amount == 0 {
+                continue;
+            }
+            report.remaining += amount;
+            report.outputs += 1;
+            report.holders.push(Reverse((amount, record.outpoint.clone())));
+            if report.holders.len() > top {
+                report.holders.pop();
+            }
+        }
+    }));
+
+    // Only the addresses of the listed holders are kept from the UTXO set
+    let mut addresses: HashMap<String, String> = HashMap::new();
+    {
+        let wanted: HashSet<&String> = reports.values()
+            .flat_map(|r| r.holders.iter().map(|&Reverse((_, ref outpoint))| outpoint)).collect();
+        info!(target: "taintReport", "Reading taint_utxo.csv...");
+        try!(read_utxos(&folder, |outpoint, address| {
+            if wanted.contains(&outpoint) {
+                addresses.insert(outpoint, String::from(address));
+            }
+        }));
+    }
+
+    // (kept, lost) -> (collisions, value), for pairs involving a reported label
+    let mut collisions: BTreeMap<(u16, u16), (usize, u64)> = BTreeMap::new();
+    if folder.join("taint_overlap.csv").exists() {
+        info!(target: "taintReport", "Reading taint_overlap.csv...");
+        try!(read_overlaps(&folder, |overlap| {
+            if let Some(report) = reports.get_mut(&overlap.right) {
+                report.lost += overlap.value;
+            }
+            if reports.contains_key(&overlap.left) || reports.contains_key(&overlap.right) {
+                let entry = collisions.entry((overlap.left, overlap.right)).or_insert((0, 0));
+                entry.0 += 1;
+                entry.1 += overlap.value;
+            }
+        }));
+    }
+
+    let has_series = folder.join("taint_series.csv").exists();
+    if has_series {
+        info!(target: "taintReport", "Reading taint_series.csv...");
+        try!(read_series(&folder, |sample| {
+            if let Some(report) = reports.get_mut(&sample.label) {
+                report.series.push((sample.height, sample.date, sample.value, sample.outputs));
+            }
+        }));
+    }
+
+    try!(write_report(&output, &folder, &address_file, &reports, &names, &addresses, &collisions, has_series));
+    info!(target: "taintReport", "Wrote the report for {} taint names to {}.", reports.len(), output.display());
+    Ok(())
+}
+
+fn write_report(path: &Path, folder: &Path, address_file: &Path,
+                reports: &BTreeMap<u16, LabelReport>,
+                names: &BTreeMap<u16, String>,
+                addresses: &HashMap<String, String>,
+                collisions: &BTreeMap<(u16, u16), (usize, u64)>,
+                has_series: bool) -> OpResult<()> {
+    let name_of = |id: u16| names.get(&id).map(|n| markup_escape(n)).unwrap_or_else(|| id.to_string());
+    let swatch = |id: u16| format!("<span class=\"swatch\" style=\"background:{}\"></span>", label_color(id));
+    let title: Vec<String> = reports.values().map(|r| markup_escape(&r.name)).collect();
+
+    write_file(path, |w| {
+        try!(write!(w, "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n"));
+        try!(write!(w, "<title>Taint report: {}</title>\n<style>{}</style>\n</head>\n<body>\n", title.join(", "), STYLE));
+        try!(write!(w, "<h1>Taint report: {}</h1>\n", title.join(", ")));
+        try!(write!(w, "<p class=\"note\">Results of the FIFO taint run in <code>{}</code>, seeded from <code>{}</code>. Amounts are in BTC.</p>\n",
+                    markup_escape(&folder.display().to_string()), markup_escape(&address_file.display().to_string())));
+
+        // Ledger of all reported labels
+        try!(write!(w, "<h2>Ledger</h2>\n<table>\n<tr><th>Taint</th><th>Seeded</th><th>Remaining in UTXOs</th><th>Outputs</th><th>Lost in collisions</th><th>Unaccounted</th></tr>\n"));
+        for report in reports.values() {
+            let seeded = report.seeded();
+            try!(write!(w, "<tr><td>{}{}</td><td class=\"num\">{}</td><td class=\"num\">{} ({})</td><td class=\"num\">{}</td><td class=\"num\">{} ({})</td><td class=\"num\">{}</td></tr>\n",
+                        swatch(report.id), markup_escape(&report.name), btc(seeded),
+                        btc(report.remaining), percent(report.remaining, seeded), report.outputs,
+                        btc(report.lost), percent(report.lost, seeded),
+                        signed_btc(seeded, report.remaining + report.lost)));
+        }
+        try!(write!(w, "</table>\n<p class=\"note\">Unaccounted is the seeded amount that is neither held by an unspent output nor recorded as lost in a collision. A negative value means more is held than was seeded.</p>\n"));
+
+        for report in reports.values() {
+            try!(write!(w, "<h2>{}{}</h2>\n", swatch(report.id), markup_escape(&report.name)));
+
+            try!(write!(w, "<h3>Seeds</h3>\n<table>\n<tr><th>Transaction</th><th>Amount</th></tr>\n"));
+            for &(ref txid, value) in report.seeds.iter() {
+                try!(write!(w, "<tr><td class=\"id\">{}</td><td class=\"num\">{}</td></tr>\n", markup_escape(txid), btc(value)));
+            }
+            try!(write!(w, "<tr><th>Total</th><td class=\"num\">{}</td></tr>\n</table>\n", btc(report.seeded())));
+
+            let mut holders: Vec<&(u64, String)> = report.holders.iter().map(|&Reverse(ref h)| h).collect();
+            holders.sort_by(|a, b| b.cmp(a));
+            try!(write!(w, "<h3>Largest current holders</h3>\n<table>\n<tr><th>Outpoint</th><th>Address</th><th>Amount</th><th>Share of remaining</th></tr>\n"));
+            for &&(amount, ref outpoint) in holders.iter() {
+                let address = addresses.get(outpoint).map(|a| markup_escape(a)).unwrap_or_default();
+                try!(write!(w, "<tr><td class=\"id\">{}</td><td class=\"id\">{}</td><td class=\"num\">{}</td><td class=\"num\">{}</td></tr>\n",
+                            markup_escape(outpoint), address, btc(amount), percent(amount, report.remaining)));
+            }
+            try!(write!(w, "</table>\n"));
+
+            try!(write!(w, "<h3>Dispersion over time</h3>\n"));
+            if report.series.is_empty() {
+                try!(write!(w, "<p class=\"note\">{}</p>\n", if has_series {
+                    "The time series has no samples of this taint."
+                } else {
+                    "No time series in this run, run taintFIFO with --series-interval or --series-daily to chart it."
+                }));
+            } else {
+                let values: Vec<(usize, String, f64)> = report.series.iter()
+                    .map(|&(h, ref d, v, _)| (h, d.clone(), v as f64 * 1e-8)).collect();
+                let outputs: Vec<(usize, String, f64)> = report.series.iter()
+                    .map(|&(h, ref d, _, o)| (h, d.clone(), o as f64)).collect();
+                try!(w.write_all(line_chart("Taint held in unspent outputs (BTC)", &label_color(report.id), &values).as_bytes()));
+                try!(w.write_all(line_chart("Unspent outputs holding the taint", &label_color(report.id), &outputs).as_bytes()));
+            }
+        }
+
+        try!(write!(w, "<h2>Collisions</h2>\n"));
+        if collisions.is_empty() {
+            try!(write!(w, "<p class=\"note\">No collisions involve the reported taint names.</p>\n"));
+        } else {
+            let mut pairs: Vec<(&(u16, u16), &(usize, u64))> = collisions.iter().collect();
+            pairs.sort_by(|a, b| (b.1).1.cmp(&(a.1).1).then(a.0.cmp(b.0)));
+            try!(write!(w, "<p class=\"note\">When two taints meet in an output the FIFO order keeps one of them, the amount of the other is lost.</p>\n"));
+            try!(write!(w, "<table>\n<tr><th>Kept</th><th>Lost</th><th>Collisions</th><th>Amount lost</th></tr>\n"));
+            for (&(kept, lost), &(count, value)) in pairs {
+                try!(write!(w, "<tr><td>{}{}</td><td>{}{}</td><td class=\"num\">{}</td><td class=\"num\">{}</td></tr>\n",
+                            swatch(kept), name_of(kept), swatch(lost), name_of(lost), count, btc(value)));
+            }
+            try!(write!(w, "</table>\n"));
+        }
+
+        try!(write!(w, "</body>\n</html>\n"));
+        Ok(())
+    })
+}
@@ -0,0 +1,308 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use errors::{OpError, OpErrorKind, OpResult};
+
+use callbacks::taint_output::FlowKind;
+
+/// A line of `taint_flows.csv`.
+pub struct FlowRecord {
+    pub height: usize,
+    pub txid: String,
+    pub kind: FlowKind,
+    pub outpoint_txid: String,
+    pub outpoint_vout: u32,
+    pub value: u64,
+    pub taint: Vec<(u16, u64)>, // (label, amount) in FIFO order
+}
+
+/// Total amount of `label` in a taint vector read from a result file.
+pub fn amount_of(taint: &[(u16, u64)], label: u16) -> u64 {
+    taint.iter().filter(|&&(name, _)| name == label).fold(0, |a, &(_, value)| a + value)
+}
+
+/// Total amount of all labels but the clean one.
+pub fn dirty_amount(taint: &[(u16, u64)]) -> u64 {
+    taint.iter().filter(|&&(name, _)| name != 0).fold(0, |a, &(_, value)| a + value)
+}
+
+impl FlowRecord {
+    /// Total amount of `label` carried by this flow.
+    pub fn amount_of(&self, label: u16) -> u64 {
+        amount_of(&self.taint, label)
+    }
+
+    pub fn outpoint(&self) -> String {
+        format!("{}:{}", self.outpoint_txid, self.outpoint_vout)
+    }
+}
+
+/// A line of `address_info.csv`: an outpoint and its taint.
+pub struct OutpointRecord {
+    pub outpoint: String, // txid:index
+    pub timestamp: String,
+    pub balance: u64,
+    pub taint: Vec<(u16, u64)>, // (label, amount) in FIFO order
+}
+
+impl OutpointRecord {
+    pub fn amount_of(&self, label: u16) -> u64 {
+        amount_of(&self.taint, label)
+    }
+}
+
+/// A line of `taint_overlap.csv`: `left` kept the place where `right` was lost.
+pub struct OverlapRecord {
+    pub left: u16,
+    pub right: u16,
+    pub value: u64,
+    pub txid: String,
+    pub location: usize,
+}
+
+/// A line of `taint_series.csv`.
+pub struct SeriesRecord {
+    pub height: usize,
+    pub date: String,
+    pub label: u16,
+    pub value: u64,
+    pub outputs: usize,
+}
+
+/// A seeded transaction of the address file with its taint names and amounts.
+pub struct SeedRecord {
+    pub txid: String,
+    pub taint: Vec<(String, u64)>,
+}
+
+pub fn parse_error(path: &Path, line_no: usize, msg: &str) -> OpError {
+    OpError::new(OpErrorKind::RuntimeError)
+        .join_msg(&format!("{}:{}: {}", path.display(), line_no + 1, msg))
+}
+
+pub fn parse_number<T: ::std::str::FromStr>(field: Option<&str>, path: &Path, line_no: usize) -> OpResult<T> {
+    match field.and_then(|f| f.trim().parse::<T>().ok()) {
+        Some(value) => Ok(value),
+        None => Err(parse_error(path, line_no, &format!("invalid number `{}`", field.unwrap_or("")))),
+    }
+}
+
+/// Parses the `<label> <value>,` taint fields written after the fixed columns
+/// of `address_info.csv` and `taint_flows.csv`. Empty fields are skipped.
+pub fn parse_taint_fields<'a, I>(fields: I, path: &Path, line_no: usize) -> OpResult<Vec<(u16, u64)>>
+    where I: Iterator<Item = &'a str>
+{
+    let mut taint = Vec::new();
+    for field in fields.filter(|f| !f.trim().is_empty()) {
+        let mut parts = field.trim().split(' ');
+        let label = try!(parse_number::<u16>(parts.next(), path, line_no));
+        let value = try!(parse_number::<u64>(parts.next(), path, line_no));
+        taint.push((label, value));
+    }
+    Ok(taint)
+}
+
+/// Calls `f` for every line of a result file.
+pub fn for_each_line<F>(path: &Path, mut f: F) -> OpResult<usize>
+    where F: FnMut(&str, usize) -> OpResult<()>
+{
+    let file = try!(File::open(path).map_err(|err| {
+        OpError::from(err).join_msg(&format!("Unable to open {}", path.display()))
+    }));
+    let mut lines = 0;
+    for (line_no, line) in BufReader::new(file).lines().enumerate() {
+        let line = try!(line);
+        if line.is_empty() {
+            continue;
+        }
+        try!(f(&line, line_no));
+        lines += 1;
+    }
+    Ok(lines)
+}
+
+/// Loads `taint_mapper.csv` as name -> id. Names may contain commas, the id is after the last one.
+pub fn load_labels(folder: &Path) -> OpResult<BTreeMap<String, u16>> {
+    let path = folder.join("taint_mapper.csv");
+    let mut labels = BTreeMap::new();
+    try!(for_each_line(&path, |line, line_no| {
+        let mut parts = line.rsplitn(2, ',');
+        let id = try!(parse_number::<u16>(parts.next(), &path, line_no));
+        let name = match parts.next() {
+            Some(name) => name,
+            None => return Err(parse_error(&path, line_no, "missing taint name")),
+        };
+        labels.insert(String::from(name), id);
+        Ok(())
+    }));
+    Ok(labels)
+}
+
+/// Reads `taint_flows.csv`, as written with `--record-flows`.
+pub fn read_flows<F>(folder: &Path, mut f: F) -> OpResult<usize>
+    where F: FnMut(FlowRecord)
+{
+    let path: PathBuf = folder.join("taint_flows.csv");
+    for_each_line(&path, |line, line_no| {
+        let mut fields = line.split(',');
+        let height = try!(parse_number::<usize>(fields.next(), &path, line_no));
+        let txid = String::from(fields.next().unwrap_or(""));
+        let kind = match fields.next().map(|kind| kind.parse::<FlowKind>()) {
+            Some(Ok(kind)) => kind,
+            _ => return Err(parse_error(&path, line_no, "invalid flow kind")),
+        };
+        let outpoint_txid = String::from(fields.next().unwrap_or(""));
+        let outpoint_vout = try!(parse_number::<u32>(fields.next(), &path, line_no));
+        let value = try!(parse_number::<u64>(fields.next(), &path, line_no));
+        let taint = try!(parse_taint_fields(fields, &path, line_no));
+        f(FlowRecord {
+            height: height,
+            txid: txid,
+            kind: kind,
+            outpoint_txid: outpoint_txid,
+            outpoint_vout: outpoint_vout,
+            value: value,
+            taint: taint,
+        });
+        Ok(())
+    })
+}
+
+/// Turns the outpoint column of `address_info.csv` into `txid:index`, whatever
+/// formatting of the parser's `TxOutpoint` it was written with.
+pub fn normalize_outpoint(field: &str) -> Option<String> {
+    let bytes = field.as_bytes();
+    let mut start = 0;
+    while start + 64 <= bytes.len() {
+        let run = bytes[start..].iter().take_while(|b| (**b as char).is_digit(16)).count();
+        if run >= 64 {
+            let txid = &field[start..start + 64];
+            let rest = &field[start + 64..];
+            let index: String = rest.chars().skip_while(|c| !c.is_digit(10)).take_while(|c| c.is_digit(10)).collect();
+            return if index.is_empty() { None } else { Some(format!("{}:{}", txid, index)) };
+        }
+        start += run + 1;
+    }
+    None
+}
+
+fn is_timestamp(field: &str) -> bool {
+    // %Y%m%d-%H%M%S
+    field.len() == 15 && field.char_indices().all(|(i, c)| if i == 8 { c == '-' } else { c.is_digit(10) })
+}
+
+/// Reads `address_info.csv`.
+pub fn read_address_info<F>(folder: &Path, mut f: F) -> OpResult<usize>
+    where F: FnMut(OutpointRecord)
+{
+    let path = folder.join("address_info.csv");
+    for_each_line(&path, |line, line_no| {
+        let fields: Vec<&str> = line.split(',').collect();
+        // The outpoint may itself contain commas, so look for the timestamp after it
+        let ts = match fields.iter().position(|f| is_timestamp(f)) {
+            Some(ts) => ts,
+            None => return Err(parse_error(&path, line_no, "missing timestamp")),
+        };
+        let outpoint = match normalize_outpoint(&fields[..ts].join(",")) {
+            Some(outpoint) => outpoint,
+            None => return Err(parse_error(&path, line_no, "invalid outpoint")),
+        };
+        let balance = try!(parse_number::<u64>(fields.get(ts + 1).cloned(), &path, line_no));
+        let taint = try!(parse_taint_fields(fields[ts + 2..].iter().cloned(), &path, line_no));
+        f(OutpointRecord {
+            outpoint: outpoint,
+            timestamp: String::from(fields[ts]),
+            balance: balance,
+            taint: taint,
+        });
+        Ok(())
+    })
+}
+
+/// Reads `taint_utxo.csv` as (txid:index, address).
+pub fn read_utxos<F>(folder: &Path, mut f: F) -> OpResult<usize>
+    where F: FnMut(String, &str)
+{
+    let path = folder.join("taint_utxo.csv");
+    for_each_line(&path, |line, line_no| {
+        let mut fields = line.splitn(3, ',');
+        let txid = fields.next().unwrap_or("");
+        let index = try!(parse_number::<u32>(fields.next(), &path, line_no));
+        f(format!("{}:{}", txid, index), fields.next().unwrap_or(""));
+        Ok(())
+    })
+}
+
+/// Reads `taint_overlap.csv`.
+pub fn read_overlaps<F>(folder: &Path, mut f: F) -> OpResult<usize>
+    where F: FnMut(OverlapRecord)
+{
+    let path = folder.join("taint_overlap.csv");
+    for_each_line(&path, |line, line_no| {
+        let mut fields = line.split(',');
+        let left = try!(parse_number::<u16>(fields.next(), &path, line_no));
+        let right = try!(parse_number::<u16>(fields.next(), &path, line_no));
+        let value = try!(parse_number::<u64>(fields.next(), &path, line_no));
+        let txid = String::from(fields.next().unwrap_or(""));
+        let location = try!(parse_number::<usize>(fields.next(), &path, line_no));
+        f(OverlapRecord {
+            left: left,
+            right: right,
+            value: value,
+            txid: txid,
+            location: location,
+        });
+        Ok(())
+    })
+}
+
+/// Reads `taint_series.csv`, as written with `--series-interval` or `--series-daily`.
+pub fn read_series<F>(folder: &Path, mut f: F) -> OpResult<usize>
+    where F: FnMut(SeriesRecord)
+{
+    let path = folder.join("taint_series.csv");
+    for_each_line(&path, |line, line_no| {
+        let mut fields = line.split(',');
+        let height = try!(parse_number::<usize>(fields.next(), &path, line_no));
+        let date = String::from(fields.next().unwrap_or(""));
+        let label = try!(parse_number::<u16>(fields.next(), &path, line_no));
+        let value = try!(parse_number::<u64>(fields.next(), &path, line_no));
+        let outputs = try!(parse_number::<usize>(fields.next(), &path, line_no));
+        f(SeriesRecord {
+            height: height,
+            date: date,
+            label: label,
+            value: value,
+            outputs: outputs,
+        });
+        Ok(())
+    })
+}
+
+/// Reads the address file given to `taintFIFO`, which loads its seeds with
+/// it as well. Only the first line of a transaction listed twice is used, and
+/// a taint name without an amount is an error.
+pub fn read_seeds(path: &Path) -> OpResult<Vec<SeedRecord>> {
+    let mut seeds = Vec::new();
+    let mut seen = HashSet::new();
+    try!(for_each_line(path, |line, line_no| {
+        let mut fields = line.split(',');
+        let txid = String::from(fields.next().unwrap_or(""));
+        if !seen.insert(txid.clone()) {
+            return Ok(());
+        }
+        let mut taint = Vec::new();
+        while let Some(name) = fields.next() {
+            let value = try!(parse_number::<u64>(fields.next(), path, line_no));
+            taint.push((String::from(name), value));
+        }
+        seeds.push(SeedRecord {
+            txid: txid,
+            taint: taint,
+        });
+        Ok(())
+    }));
+    Ok(seeds)
+}
@@ -0,0 +1,548 @@
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+
+use clap::{Arg, ArgMatches, App, SubCommand};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use errors::{OpError, OpErrorKind, OpResult};
+
+use blockchain::proto::Hashed;
+use blockchain::proto::block::Block;
+use blockchain::proto::header::BlockHeader;
+use blockchain::proto::script::{EvaluatedScript, ScriptPattern};
+use blockchain::proto::tx::{Tx, TxInput, TxOutpoint, TxOutput, EvaluatedTxOut};
+use blockchain::proto::varuint::VarUint;
+use blockchain::utils::arr_to_hex_swapped;
+
+use callbacks::Callback;
+use callbacks::bootstrap_taint_fifo::TaintFifo;
+use callbacks::taint_output::write_file;
+use callbacks::taint_output::FlowKind;
+use callbacks::taint_results::{FlowRecord, for_each_line, parse_error, parse_number, read_flows};
+
+/// Address of a synthetic output that can never be spent.
+pub const OP_RETURN_ADDRESS: &'static str = "op_return";
+
+/// Seconds between blocks whose time is not given.
+const BLOCK_INTERVAL: u32 = 600;
+
+/// An output of a synthetic transaction.
+#[derive(Clone, Debug)]
+pub struct OutputSpec {
+    pub address: String, // `op_return` for an unspendable output
+    pub value: u64,
+}
+
+/// Builds the `Block` and `Tx` values that `on_block` consumes, without
+/// blockchain files. Txids are derived from names, so that a scenario can
+/// refer to the outputs of its transactions.
+pub struct ChainBuilder {
+    prev_hash: [u8; 32],
+    time: u32,
+    blocks: usize,
+    txs: Vec<Hashed<Tx>>,
+}
+
+fn sha256_of(text: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.input_str(text);
+    let mut hash = [0u8; 32];
+    hasher.result(&mut hash);
+    hash
+}
+
+impl ChainBuilder {
+    /// A chain whose first block has the time `time`.
+    pub fn new(time: u32) -> Self {
+        ChainBuilder {
+            prev_hash: [0u8; 32],
+            time: time,
+            blocks: 0,
+            txs: Vec::new(),
+        }
+    }
+
+    /// The txid of the transaction named `name`.
+    pub fn txid(name: &str) -> [u8; 32] {
+        sha256_of(&format!("tx {}", name))
+    }
+
+    /// Starts the next block, `BLOCK_INTERVAL` after the previous one unless a time is given.
+    pub fn begin_block(&mut self, time: Option<u32>) {
+        if let Some(time) = time {
+            self.time = time;
+        } else if self.blocks > 0 {
+            self.time += BLOCK_INTERVAL;
+        }
+        self.txs.clear();
+    }
+
+    fn outputs(outputs: &[OutputSpec]) -> Vec<EvaluatedTxOut> {
+        outputs.iter().map(|output| {
+            let script_pubkey = if output.address == OP_RETURN_ADDRESS { vec![0x6a] } else { Vec::new() };
+            EvaluatedTxOut {
+                script: EvaluatedScript {
+                    address: if output.address == OP_RETURN_ADDRESS { String::new() } else { output.address.clone() },
+                    pattern: ScriptPattern::NotRecognised,
+                },
+                out: TxOutput {
+                    value: output.value,
+                    script_len: VarUint::from(script_pubkey.len() as u64),
+                    script_pubkey: script_pubkey,
+                },
+            }
+        }).collect()
+    }
+
+    fn push(&mut self, name: &str, inputs: Vec<TxOutpoint>, outputs: &[OutputSpec]) -> [u8; 32] {
+        let txid = ChainBuilder::txid(name);
+        let inputs: Vec<TxInput> = inputs.into_iter().map(|outpoint| TxInput {
+            outpoint: outpoint,
+            script_len: VarUint::from(0u64),
+            script_sig: Vec::new(),
+            seq_no: 0xffffffff,
+        }).collect();
+        let tx = Tx {
+            version: 1,
+            in_count: VarUint::from(inputs.len() as u64),
+            inputs: inputs,
+            out_count: VarUint::from(outputs.len() as u64),
+            outputs: ChainBuilder::outputs(outputs),
+            locktime: 0,
+        };
+        self.txs.push(Hashed { hash: txid, value: tx });
+        txid
+    }
+
+    /// Adds the coinbase transaction, it has to come first in the block.
+    pub fn coinbase(&mut self, name: &str, outputs: &[OutputSpec]) -> [u8; 32] {
+        let input = TxOutpoint { txid: [0u8; 32], index: 0xffffffff };
+        self.push(name, vec![input], outputs)
+    }
+
+    /// Adds a transaction spending `inputs`, which may be outputs of earlier transactions of the same block.
+    pub fn tx(&mut self, name: &str, inputs: Vec<TxOutpoint>, outputs: &[OutputSpec]) -> [u8; 32] {
+        self.push(name, inputs, outputs)
+    }
+
+    /// Returns the block with the transactions added since `begin_block`.
+    pub fn finish_block(&mut self) -> Block {
+        let hash = sha256_of(&format!("block {}", self.blocks));
+        let header = BlockHeader {
+            version: 4,
+            prev_hash: self.prev_hash,
+            merkle_root: [0u8; 32],
+            timestamp: self.time,
+            bits: 0x1d00ffff,
+            nonce: 0,
+        };
+        let txs: Vec<Hashed<Tx>> = self.txs.drain(..).collect();
+        self.prev_hash = hash;
+        self.blocks += 1;
+        Block {
+            blocksize: 0,
+            header: Hashed { hash: hash, value: header },
+            tx_count: VarUint::from(txs.len() as u64),
+            txs: txs,
+        }
+    }
+}
+
+enum TxSpec {
+    Coinbase(String, Vec<OutputSpec>),
+    Spend(String, Vec<(String, u32)>, Vec<OutputSpec>),
+}
+
+struct BlockSpec {
+    time: Option<u32>,
+    txs: Vec<TxSpec>,
+}
+
+enum Expectation {
+    Taint(String, u32, Vec<(String, u64)>), // Taint of an unspent outpoint in FIFO order, none when clean
+    Spent(String, u32),
+    Flow(String, u32, FlowKind, Vec<(String, u64)>), // Taint of a recorded flow to an outpoint
+    Collisions(u32),
+    Discrepancies(usize),
+}
+
+/// A small chain with seeds and the taint expected at its end, read from a
+/// scenario file:
+///
+/// ```text
+/// # A comment
+/// args --audit lenient                    extra taintFIFO arguments
+/// seed <tx> <label> <amount> ...          like a line of the address file
+/// block [<time>]                          starts a block, 600 seconds after the previous one by default
+/// coinbase <tx> <address>:<value> ...     the coinbase, first in its block
+/// tx <tx> <tx>:<index> ... -> <address>:<value> ...
+/// expect <tx>:<index> [<label> <amount> ...]   the taint in FIFO order, nothing when clean
+/// expect <tx>:<index> spent
+/// expect-flow <tx>:<index> <kind> [<label> <amount> ...]   a flow to the outpoint, needs --record-flows
+/// expect-collisions <n>
+/// expect-discrepancies <n>
+/// rollback <n>                            rolls back the last n blocks, the next block follows the new tip
+/// ```
+///
+/// The address `op_return` makes an output that can never be spent.
+pub struct Scenario {
+    pub name: String,
+    args: Vec<String>,
+    seeds: Vec<(String, Vec<(String, u64)>)>,
+    blocks: Vec<BlockSpec>,
+    rollbacks: Vec<(usize, usize, usize)>,   // Line, blocks before it and the number of blocks rolled back
+    expectations: Vec<(usize, Expectation)>, // Line of the expectation, for the report
+}
+
+fn parse_outputs(fields: &[&str], path: &Path, line_no: usize) -> OpResult<Vec<OutputSpec>> {
+    let mut outputs = Vec::new();
+    for field in fields {
+        let mut parts = field.rsplitn(2, ':');
+        let value = try!(parse_number::<u64>(parts.next(), path, line_no));
+        match parts.next() {
+            Some(address) if !address.is_empty() => outputs.push(OutputSpec { address: String::from(address), value: value }),
+            _ => return Err(parse_error(path, line_no, &format!("expected <address>:<value>, got `{}`", field))),
+        }
+    }
+    if outputs.is_empty() {
+        return Err(parse_error(path, line_no, "a transaction needs outputs"));
+    }
+    Ok(outputs)
+}
+
+fn parse_outpoint(field: &str, path: &Path, line_no: usize) -> OpResult<(String, u32)> {
+    let mut parts = field.rsplitn(2, ':');
+    let index = try!(parse_number::<u32>(parts.next(), path, line_no));
+    match parts.next() {
+        Some(name) if !name.is_empty() => Ok((String::from(name), index)),
+        _ => Err(parse_error(path, line_no, &format!("expected <tx>:<index>, got `{}`", field))),
+    }
+}
+
+fn parse_taint(fields: &[&str], path: &Path, line_no: usize) -> OpResult<Vec<(String, u64)>> {
+    if fields.len() % 2 != 0 {
+        return Err(parse_error(path, line_no, "taint names and amounts must come in pairs"));
+    }
+    let mut taint = Vec::new();
+    for pair in fields.chunks(2) {
+        taint.push((String::from(pair[0]), try!(parse_number::<u64>(Some(pair[1]), path, line_no))));
+    }
+    Ok(taint)
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> OpResult<Self> {
+        let mut scenario = Scenario {
+            name: path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default(),
+            args: Vec::new(),
+            seeds: Vec::new(),
+            blocks: Vec::new(),
+            rollbacks: Vec::new(),
+            expectations: Vec::new(),
+        };
+        let mut tip = 0; // Blocks on the chain after the rollbacks so far
+        let mut names = HashSet::new();
+        try!(for_each_line(path, |line, line_no| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() || fields[0].starts_with('#') {
+                return Ok(());
+            }
+            match fields[0] {
+                "args" => scenario.args.extend(fields[1..].iter().map(|arg| String::from(*arg))),
+                "seed" if fields.len() >= 2 => {
+                    let taint = try!(parse_taint(&fields[2..], path, line_no));
+                    scenario.seeds.push((String::from(fields[1]), taint));
+                },
+                "block" => {
+                    let time = match fields.get(1) {
+                        Some(time) => Some(try!(parse_number::<u32>(Some(*time), path, line_no))),
+                        None => None,
+                    };
+                    scenario.blocks.push(BlockSpec { time: time, txs: Vec::new() });
+                    tip += 1;
+                },
+                "rollback" => {
+                    let blocks = try!(parse_number::<usize>(fields.get(1).cloned(), path, line_no));
+                    if blocks == 0 || blocks >= tip {
+                        return Err(parse_error(path, line_no, &format!("cannot roll back {} of {} blocks, the first block has to stay", blocks, tip)));
+                    }
+                    scenario.rollbacks.push((line_no + 1, scenario.blocks.len(), blocks));
+                    tip -= blocks;
+                },
+                "coinbase" | "tx" if fields.len() >= 3 => {
+                    if !names.insert(String::from(fields[1])) {
+                        return Err(parse_error(path, line_no, &format!("transaction `{}` is defined twice", fields[1])));
+                    }
+                    let tx = if fields[0] == "coinbase" {
+                        TxSpec::Coinbase(String::from(fields[1]), try!(parse_outputs(&fields[2..], path, line_no)))
+                    } else {
+                        let arrow = match fields.iter().position(|field| *field == "->") {
+                            Some(arrow) if arrow > 2 => arrow,
+                            _ => return Err(parse_error(path, line_no, "expected tx <tx> <tx>:<index> ... -> <address>:<value> ...")),
+                        };
+                        let mut inputs = Vec::new();
+                        for field in fields[2..arrow].iter() {
+                            inputs.push(try!(parse_outpoint(field, path, line_no)));
+                        }
+                        TxSpec::Spend(String::from(fields[1]), inputs, try!(parse_outputs(&fields[arrow + 1..], path, line_no)))
+                    };
+                    match scenario.blocks.last_mut() {
+                        Some(block) => {
+                            if let TxSpec::Coinbase(..) = tx {
+                                if !block.txs.is_empty() {
+                                    return Err(parse_error(path, line_no, "the coinbase has to come first in its block"));
+                                }
+                            }
+                            block.txs.push(tx);
+                        },
+                        None => return Err(parse_error(path, line_no, "transactions have to follow a `block` line")),
+                    }
+                },
+                "expect" if fields.len() >= 2 => {
+                    let (name, index) = try!(parse_outpoint(fields[1], path, line_no));
+                    let expectation = if fields.len() == 3 && fields[2] == "spent" {
+                        Expectation::Spent(name, index)
+                    } else {
+                        Expectation::Taint(name, index, try!(parse_taint(&fields[2..], path, line_no)))
+                    };
+                    scenario.expectations.push((line_no + 1, expectation));
+                },
+                "expect-flow" if fields.len() >= 3 => {
+                    let (name, index) = try!(parse_outpoint(fields[1], path, line_no));
+                    let kind = try!(fields[2].parse::<FlowKind>().map_err(|_| {
+                        parse_error(path, line_no, &format!("unknown flow kind `{}`", fields[2]))
+                    }));
+                    let taint = try!(parse_taint(&fields[3..], path, line_no));
+                    scenario.expectations.push((line_no + 1, Expectation::Flow(name, index, kind, taint)));
+                },
+                "expect-collisions" => {
+                    let collisions = try!(parse_number::<u32>(fields.get(1).cloned(), path, line_no));
+                    scenario.expectations.push((line_no + 1, Expectation::Collisions(collisions)));
+                },
+                "expect-discrepancies" => {
+                    let discrepancies = try!(parse_number::<usize>(fields.get(1).cloned(), path, line_no));
+                    scenario.expectations.push((line_no + 1, Expectation::Discrepancies(discrepancies)));
+                },
+                other => return Err(parse_error(path, line_no, &format!("unknown or incomplete line `{}`", other))),
+            }
+            Ok(())
+        }));
+        if scenario.blocks.is_empty() {
+            return Err(OpError::new(OpErrorKind::RuntimeError)
+                           .join_msg(&format!("{} has no blocks", path.display())));
+        }
+        Ok(scenario)
+    }
+
+    /// Runs the scenario through `TaintFifo` with `folder` as the dump folder
+    /// and returns the expectations that were not met.
+    pub fn run(&self, folder: &Path) -> OpResult<Vec<String>> {
+        try!(fs::create_dir_all(folder));
+        let address_file = folder.join("seeds.csv");
+        try!(write_file(&address_file, |w| {
+            for &(ref name, ref taint) in self.seeds.iter() {
+                let mut line = arr_to_hex_swapped(&ChainBuilder::txid(name));
+                for &(ref label, amount) in taint.iter() {
+                    line += &format!(",{},{}", label, amount);
+                }
+                try!(writeln!(w, "{}", line));
+            }
+            Ok(())
+        }));
+
+        let mut args = vec![String::from("taintFIFO"), folder.to_string_lossy().into_owned(),
+                            String::from("--address-file"), address_file.to_string_lossy().into_owned()];
+        args.extend(self.args.iter().cloned());
+        let matches = try!(TaintFifo::build_subcommand().get_matches_from_safe(args).map_err(|err| {
+            OpError::new(OpErrorKind::InvalidArgsError).join_msg(&format!("{}: {}", self.name, err))
+        }));
+        let mut fifo = try!(TaintFifo::with_interrupt_flag(&matches, Arc::new(AtomicBool::new(false))));
+
+        try!(fifo.start(0));
+        let mut chain = ChainBuilder::new(1231006505);
+        let mut failures = Vec::new();
+        let mut before = Vec::new(); // The state before each block of the current chain
+        let mut rollbacks = self.rollbacks.iter().peekable();
+        for (index, spec) in self.blocks.iter().enumerate() {
+            let height = before.len();
+            before.push(fifo.state_view());
+            chain.begin_block(spec.time);
+            for tx in spec.txs.iter() {
+                match *tx {
+                    TxSpec::Coinbase(ref name, ref outputs) => { chain.coinbase(name, outputs); },
+                    TxSpec::Spend(ref name, ref inputs, ref outputs) => {
+                        let inputs = inputs.iter().map(|&(ref name, index)| TxOutpoint { txid: ChainBuilder::txid(name), index: index }).collect();
+                        chain.tx(name, inputs, outputs);
+                    },
+                }
+            }
+            try!(fifo.process(chain.finish_block(), height).map_err(|err| {
+                err.join_msg(&format!("{}: block {} failed", self.name, height))
+            }));
+            while rollbacks.peek().map_or(false, |rollback| rollback.1 == index + 1) {
+                let &(line, _, blocks) = rollbacks.next().unwrap();
+                let tip = try!(fifo.rollback(blocks).map_err(|err| err.join_msg(&format!("{}: line {}", self.name, line))));
+                // The state has to be the one from before the first rolled back block
+                let expected = before.split_off(tip + 1);
+                for difference in fifo.state_view().differences(&expected[0]) {
+                    failures.push(format!("line {}: after the rollback, {}", line, difference));
+                }
+            }
+        }
+        fifo.on_complete(before.len() - 1);
+
+        let mut flows: Vec<FlowRecord> = Vec::new();
+        if self.expectations.iter().any(|&(_, ref expectation)| if let Expectation::Flow(..) = *expectation { true } else { false }) {
+            try!(read_flows(folder, |flow| flows.push(flow)));
+        }
+        let labels = fifo.labels_by_id();
+        let label_name = |id: u16| labels.get(&id).cloned().unwrap_or_else(|| format!("#{}", id));
+        for &(line, ref expectation) in self.expectations.iter() {
+            match *expectation {
+                Expectation::Taint(ref name, index, ref expected) => {
+                    let outpoint = TxOutpoint { txid: ChainBuilder::txid(name), index: index };
+                    match fifo.outpoint_state(&outpoint) {
+                        Some(info) => {
+                            let got: Vec<(String, u64)> = info.taint().iter().map(|tp| (label_name(tp.name), tp.value)).collect();
+                            if got != *expected {
+                                failures.push(format!("line {}: {}:{} has taint {:?}, expected {:?}", line, name, index, got, expected));
+                            }
+                        },
+                        None => failures.push(format!("line {}: {}:{} is spent or was never created", line, name, index)),
+                    }
+                },
+                Expectation::Spent(ref name, index) => {
+                    let outpoint = TxOutpoint { txid: ChainBuilder::txid(name), index: index };
+                    if fifo.outpoint_state(&outpoint).is_some() {
+                        failures.push(format!("line {}: {}:{} is unspent, expected it spent", line, name, index));
+                    }
+                },
+                Expectation::Flow(ref name, index, kind, ref expected) => {
+                    let txid = arr_to_hex_swapped(&ChainBuilder::txid(name));
+                    let got: Vec<Vec<(String, u64)>> = flows.iter()
+                        .filter(|flow| flow.kind == kind && flow.outpoint_txid == txid && flow.outpoint_vout == index)
+                        .map(|flow| flow.taint.iter().map(|&(label, value)| (label_name(label), value)).collect())
+                        .collect();
+                    if !got.contains(expected) {
+                        failures.push(format!("line {}: the {} flows to {}:{} have taint {:?}, expected {:?}",
+                                              line, kind.as_str(), name, index, got, expected));
+                    }
+                },
+                Expectation::Collisions(expected) => {
+                    if fifo.taint_collisions() != expected {
+                        failures.push(format!("line {}: {} collisions, expected {}", line, fifo.taint_collisions(), expected));
+                    }
+                },
+                Expectation::Discrepancies(expected) => {
+                    if fifo.audit_discrepancies() != expected {
+                        failures.push(format!("line {}: {} audit discrepancies, expected {}", line, fifo.audit_discrepancies(), expected));
+                    }
+                },
+            }
+        }
+        Ok(failures)
+    }
+}
+
+pub fn build_subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("taintScenario")
+        .about("Runs taintFIFO over small synthetic chains and checks the taint they end with.")
+        .version("0.2")
+        .arg(Arg::with_name("scenario")
+                 .help("Scenario files")
+                 .required(true)
+                 .multiple(true))
+        .arg(Arg::with_name("work-folder")
+                 .long("work-folder")
+                 .takes_value(true)
+                 .help("Folder for the dump folder of each scenario (default: taint_scenarios in the temporary folder)"))
+}
+
+pub fn run(matches: &ArgMatches) -> OpResult<()> {
+    let work_folder = matches.value_of("work-folder").map(PathBuf::from)
+        .unwrap_or_else(|| env::temp_dir().join("taint_scenarios"));
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut failed = 0;
+    let mut total = 0;
+    for path in matches.values_of("scenario").unwrap() {
+        let scenario = try!(Scenario::load(Path::new(path)));
+        let folder = work_folder.join(&scenario.name);
+        if folder.exists() {
+            // Only ever a dump folder of an earlier run of the same scenario
+            try!(fs::remove_dir_all(&folder));
+        }
+        total += 1;
+        match scenario.run(&folder) {
+            Ok(ref failures) if failures.is_empty() => try!(writeln!(out, "ok    {}", scenario.name)),
+            Ok(failures) => {
+                try!(writeln!(out, "FAIL  {}", scenario.name));
+                for failure in failures.iter() {
+                    try!(writeln!(out, "      {}", failure));
+                }
+                failed += 1;
+            },
+            Err(err) => {
+                // A strict audit failure or a bad argument only ends this scenario
+                try!(writeln!(out, "FAIL  {}", scenario.name));
+                try!(writeln!(out, "      {}", err));
+                failed += 1;
+            },
+        }
+    }
+    try!(writeln!(out, "\n{} of {} scenarios passed, dump folders in {}", total - failed, total, work_folder.display()));
+    if failed > 0 {
+        return Err(OpError::new(OpErrorKind::RuntimeError)
+                       .join_msg(&format!("{} scenarios failed", failed)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::Scenario;
+
+    /// Runs every scenario, each in a folder of its own below a folder unique
+    /// to this test run, and reports all failing scenarios together.
+    #[test]
+    fn scenarios_pass() {
+        let scenarios = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenarios");
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let work_folder = env::temp_dir().join(format!("taint_scenarios_test_{}_{}", now.as_secs(), now.subsec_nanos()));
+        let mut count = 0;
+        let mut failed = Vec::new();
+        for entry in fs::read_dir(&scenarios).unwrap() {
+            let path = entry.unwrap().path();
+            if !path.to_string_lossy().ends_with(".scn") {
+                continue;
+            }
+            count += 1;
+            let scenario = match Scenario::load(&path) {
+                Ok(scenario) => scenario,
+                Err(err) => {
+                    failed.push(format!("{}: {}", path.display(), err));
+                    continue;
+                }
+            };
+            match scenario.run(&work_folder.join(&scenario.name)) {
+                Ok(ref failures) if failures.is_empty() => (),
+                Ok(failures) => failed.push(format!("{}: {:?}", path.display(), failures)),
+                Err(err) => failed.push(format!("{}: {}", path.display(), err)),
+            }
+        }
+        let _ = fs::remove_dir_all(&work_folder);
+        assert!(count > 0, "no scenarios in {}", scenarios.display());
+        assert!(failed.is_empty(), "{} of {} scenarios failed:\n{}", failed.len(), count, failed.join("\n"));
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{Arg, ArgMatches, App, SubCommand};
+use rustc_serialize::json::Json;
+
+use errors::OpResult;
+
+use callbacks::taint_query::{TaintIndex, IndexedOutpoint};
+use callbacks::taint_results::read_seeds;
+
+/// Seconds a client gets to send its request and read the answer.
+const REQUEST_TIMEOUT: u64 = 10;
+
+/// Longest request line or header line that is read.
+const MAX_LINE_BYTES: u64 = 8 * 1024;
+
+/// Most bytes read of the request line and headers together.
+const MAX_HEAD_BYTES: u64 = 64 * 1024;
+
+/// An answer of the API: HTTP status and JSON body.
+type Response = (u16, Json);
+
+fn object(fields: Vec<(&str, Json)>) -> Json {
+    Json::Object(fields.into_iter().map(|(k, v)| (String::from(k), v)).collect::<BTreeMap<String, Json>>())
+}
+
+fn error(status: u16, msg: &str) -> Response {
+    (status, object(vec![("error", Json::String(String::from(msg)))]))
+}
+
+fn status_text(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        431 => "Request Header Fields Too Large",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Decodes `%XX` escapes, and `+` into a space where `query` is set, as only
+/// form-encoded query strings use it for spaces.
+fn percent_decode(s: &str, query: bool) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = ::std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|h| u8::from_str_radix(h, 16).ok());
+                match hex {
+                    Some(b) => { out.push(b); i += 3; continue; },
+                    None => out.push(b'%'),
+                }
+            },
+            b'+' if query => out.push(b' '),
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn taint_json(index: &TaintIndex, taint: &[(u16, u64)]) -> Json {
+    Json::Array(taint.iter().map(|&(label, amount)| object(vec![
+        ("label", Json::U64(label as u64)),
+        ("name", Json::String(index.name(label))),
+        ("amount", Json::U64(amount)),
+    ])).collect())
+}
+
+fn outpoint_json(index: &TaintIndex, o: &IndexedOutpoint) -> Json {
+    object(vec![
+        ("outpoint", Json::String(o.outpoint.clone())),
+        ("address", Json::String(o.address.clone())),
+        ("timestamp", Json::String(o.timestamp.clone())),
+        ("balance", Json::U64(o.balance)),
+        ("taint", taint_json(index, &o.taint)),
+    ])
+}
+
+/// Serves the lookups of `taintQuery` as JSON.
+struct TaintServer {
+    index: TaintIndex,
+    seeded: BTreeMap<String, u64>, // Seeded amount per taint name, when the address file is known
+    limit: usize,
+}
+
+/// Reads one line of the request head into `line`. Returns the bytes read,
+/// or None for a line longer than `MAX_LINE_BYTES`.
+fn read_head_line<R: BufRead>(reader: &mut R, line: &mut String) -> io::Result<Option<usize>> {
+    let read = try!(reader.by_ref().take(MAX_LINE_BYTES).read_line(line));
+    if read as u64 == MAX_LINE_BYTES && !line.ends_with('\n') {
+        return Ok(None);
+    }
+    Ok(Some(read))
+}
+
+impl TaintServer {
+    fn route(&self, path: &str) -> Response {
+        let (path, query) = match path.find('?') {
+            Some(i) => (&path[..i], &path[i + 1..]),
+            None => (path, ""),
+        };
+        let params: BTreeMap<String, String> = query.split('&').filter(|p| !p.is_empty()).map(|p| {
+            let mut kv = p.splitn(2, '=');
+            (percent_decode(kv.next().unwrap_or(""), true), percent_decode(kv.next().unwrap_or(""), true))
+        }).collect();
+        let number = |name: &str, default: u64| -> Result<u64, Response> {
+            match params.get(name) {
+                Some(v) => v.parse::<u64>().map_err(|_| error(400, &format!("invalid {} `{}`", name, v))),
+                None => Ok(default),
+            }
+        };
+        let segments: Vec<String> = path.split('/').filter(|s| !s.is_empty()).map(|s| percent_decode(s, false)).collect();
+        let segment = |i: usize| segments.get(i).map(|s| s.as_str()).unwrap_or("");
+
+        match (segments.len(), segment(0)) {
+            (1, "labels") => (200, Json::Array(self.index.labels().into_iter().map(|t| object(vec![
+                ("label", Json::U64(t.label as u64)),
+                ("name", Json::String(t.name)),
+                ("value", Json::U64(t.value)),
+                ("outputs", Json::U64(t.outputs as u64)),
+            ])).collect())),
+            (2, "outpoint") => match self.index.outpoint(segment(1)) {
+                Some(o) => (200, outpoint_json(&self.index, o)),
+                None => error(404, "outpoint is spent, clean or unknown"),
+            },
+            (2, "address") => {
+                let found = self.index.address(segment(1));
+                (200, Json::Array(found.iter().map(|o| outpoint_json(&self.index, o)).collect()))
+            },
+            (3, "label") => {
+                let name = segment(1);
+                let label = match self.index.label(name) {
+                    Some(label) => label,
+                    None => return error(404, &format!("unknown taint name `{}`", name)),
+                };
+                match segment(2) {
+                    "holders" => {
+                        let (min, max, limit) = match (number("min", 0), number("max", u64::max_value()),
+                                                       number("limit", self.limit as u64)) {
+                            (Ok(min), Ok(max), Ok(limit)) => (min, max, limit as usize),
+                            (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => return e,
+                        };
+                        (200, Json::Array(self.index.holders(label, min, max, limit).into_iter().map(|(amount, o)| object(vec![
+                            ("amount", Json::U64(amount)),
+                            ("outpoint", outpoint_json(&self.index, o)),
+                        ])).collect()))
+                    },
+                    "overlaps" => (200, Json::Array(self.index.overlaps(label).into_iter().map(|o| object(vec![
+                        ("kept", Json::String(self.index.name(o.kept))),
+                        ("lost", Json::String(self.index.name(o.lost))),
+                        ("collisions", Json::U64(o.count as u64)),
+                        ("value", Json::U64(o.value)),
+                    ])).collect())),
+                    "ledger" => {
+                        let total = self.index.labels().into_iter().find(|t| t.label == label);
+                        let overlaps = self.index.overlaps(label);
+                        let lost = overlaps.iter().filter(|o| o.lost == label).fold(0, |a, o| a + o.value);
+                        let won = overlaps.iter().filter(|o| o.kept == label).fold(0, |a, o| a + o.value);
+                        let name = self.index.name(label);
+                        let mut ledger = vec![
+                            ("label", Json::U64(label as u64)),
+                            ("name", Json::String(name.clone())),
+                            ("remaining", Json::U64(total.as_ref().map(|t| t.value).unwrap_or(0))),
+                            ("outputs", Json::U64(total.as_ref().map(|t| t.outputs as u64).unwrap_or(0))),
+                            ("lost_in_collisions", Json::U64(lost)),
+                            ("kept_in_collisions", Json::U64(won)),
+                        ];
+                        if let Some(seeded) = self.seeded.get(&name) {
+                            ledger.push(("seeded", Json::U64(*seeded)));
+                        }
+                        (200, object(ledger))
+                    },
+                    _ => error(404, "unknown endpoint"),
+                }
+            },
+            _ => error(404, "unknown endpoint"),
+        }
+    }
+
+    fn handle(&self, stream: TcpStream) -> OpResult<()> {
+        // Requests are served one at a time, a stalled client must not block the others
+        try!(stream.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT))));
+        try!(stream.set_write_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT))));
+        // A client cannot make the lines of its request grow without limit
+        let mut reader = BufReader::new(try!(stream.try_clone()).take(MAX_HEAD_BYTES));
+        let mut request_line = String::new();
+        let mut rejected = match try!(read_head_line(&mut reader, &mut request_line)) {
+            Some(_) => None,
+            None => Some(error(400, "request line too long")),
+        };
+        // Skip the headers, requests have no body
+        while rejected.is_none() {
+            let mut header = String::new();
+            match try!(read_head_line(&mut reader, &mut header)) {
+                Some(0) if reader.get_ref().limit() == 0 => rejected = Some(error(431, "request headers too large")),
+                Some(0) => break,
+                Some(_) if header.trim().is_empty() => break,
+                Some(_) => {},
+                None => rejected = Some(error(431, "request header line too long")),
+            }
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let (status, body) = match (rejected, parts.next(), parts.next()) {
+            (Some(response), _, _) => response,
+            (None, Some("GET"), Some(path)) => self.route(path),
+            (None, Some(_), Some(_)) => error(405, "only GET is supported"),
+            _ => error(400, "invalid request"),
+        };
+        debug!(target: "taintServer", "{} -> {}", request_line.trim(), status);
+
+        let body = body.to_string();
+        let mut stream = stream;
+        try!(write!(stream, "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status, status_text(status), body.len(), body));
+        try!(stream.flush());
+        Ok(())
+    }
+}
+
+pub fn build_subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("taintServer")
+        .about("Serves taint lookups over a JSON API on localhost.")
+        .version("0.2")
+        .arg(Arg::with_name("dump-folder")
+                 .help("Dump folder of a finished taintFIFO run, or one of its snapshot or checkpoint folders")
+                 .required(true))
+        .arg(Arg::with_name("port")
+                 .long("port")
+                 .takes_value(true)
+                 .help("Port to listen on at 127.0.0.1 (default: 8337)"))
+        .arg(Arg::with_name("address-file")
+                 .long("address-file")
+                 .takes_value(true)
+                 .help("The address file of the run, adds the seeded amounts to the ledgers"))
+        .arg(Arg::with_name("limit")
+                 .long("limit")
+                 .takes_value(true)
+                 .help("Default number of holders returned (default: 100)"))
+}
+
+pub fn run(matches: &ArgMatches) -> OpResult<()> {
+    let folder = PathBuf::from(matches.value_of("dump-folder").unwrap());
+    let port = value_t!(matches, "port", u16).unwrap_or(8337);
+
+    let mut seeded = BTreeMap::new();
+    if let Some(path) = matches.value_of("address-file") {
+        for seed in try!(read_seeds(&PathBuf::from(path))) {
+            for (name, value) in seed.taint {
+                *seeded.entry(name).or_insert(0) += value;
+            }
+        }
+    }
+    let server = TaintServer {
+        index: try!(TaintIndex::load(&folder)),
+        seeded: seeded,
+        limit: value_t!(matches, "limit", usize).unwrap_or(100),
+    };
+
+    // Only reachable from this machine, the results are not meant to be public
+    let listener = try!(TcpListener::bind(("127.0.0.1", port)));
+    info!(target: "taintServer", "Serving {} on http://127.0.0.1:{}/", folder.display(), port);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => if let Err(err) = server.handle(stream) {
+                warn!(target: "taintServer", "Request failed: {}", err);
+            },
+            Err(err) => warn!(target: "taintServer", "Connection failed: {}", err),
+        }
+    }
+    Ok(())
+}
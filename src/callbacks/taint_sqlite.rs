@@ -0,0 +1,251 @@
+extern crate rusqlite;
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use self::rusqlite::{Connection, ToSql};
+
+use errors::{OpError, OpErrorKind, OpResult};
+
+use blockchain::proto::tx::TxOutpoint;
+use blockchain::utils::arr_to_hex_swapped;
+
+use callbacks::taint_engine::AddressInfo;
+use callbacks::taint_output::{TaintOutput, OutputTables, TaintOverlap, SeriesSample, AddressBalance, TaintFlow,
+                              PendingFiles, sync_file};
+
+/// File name of the result database inside the dump folder.
+pub const SQLITE_FILE: &'static str = "taint_results.sqlite";
+
+const SCHEMA: &'static str = "
+    CREATE TABLE metadata (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE labels (
+        label INTEGER PRIMARY KEY,
+        name TEXT NOT NULL
+    );
+    CREATE TABLE outpoints (
+        txid TEXT NOT NULL,
+        vout INTEGER NOT NULL,
+        timestamp TEXT NOT NULL,
+        balance INTEGER NOT NULL,
+        PRIMARY KEY (txid, vout)
+    );
+    CREATE TABLE outpoint_taints (
+        txid TEXT NOT NULL,
+        vout INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        label INTEGER NOT NULL,
+        amount INTEGER NOT NULL
+    );
+    CREATE TABLE utxos (
+        txid TEXT NOT NULL,
+        vout INTEGER NOT NULL,
+        address TEXT NOT NULL,
+        PRIMARY KEY (txid, vout)
+    );
+    CREATE TABLE overlaps (
+        kept_label INTEGER NOT NULL,
+        lost_label INTEGER NOT NULL,
+        amount INTEGER NOT NULL,
+        txid TEXT NOT NULL,
+        location INTEGER NOT NULL
+    );
+    CREATE TABLE flows (
+        height INTEGER NOT NULL,
+        txid TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        outpoint_txid TEXT NOT NULL,
+        outpoint_vout INTEGER NOT NULL,
+        value INTEGER NOT NULL,
+        label INTEGER NOT NULL,
+        amount INTEGER NOT NULL
+    );
+    CREATE TABLE series (
+        height INTEGER NOT NULL,
+        date TEXT NOT NULL,
+        label INTEGER NOT NULL,
+        value INTEGER NOT NULL,
+        outputs INTEGER NOT NULL
+    );
+    CREATE TABLE address_balances (
+        address TEXT NOT NULL,
+        outputs INTEGER NOT NULL,
+        balance INTEGER NOT NULL,
+        label INTEGER NOT NULL,
+        amount INTEGER NOT NULL
+    );";
+
+/// Created once all rows are in, which is a lot faster than keeping them up to date.
+const INDEXES: &'static str = "
+    CREATE INDEX outpoint_taints_outpoint ON outpoint_taints (txid, vout);
+    CREATE INDEX outpoint_taints_label ON outpoint_taints (label);
+    CREATE INDEX utxos_address ON utxos (address);
+    CREATE INDEX overlaps_txid ON overlaps (txid);
+    CREATE INDEX overlaps_kept_label ON overlaps (kept_label);
+    CREATE INDEX overlaps_lost_label ON overlaps (lost_label);
+    CREATE INDEX flows_txid ON flows (txid);
+    CREATE INDEX flows_outpoint ON flows (outpoint_txid, outpoint_vout);
+    CREATE INDEX flows_label ON flows (label);
+    CREATE INDEX series_label ON series (label);
+    CREATE INDEX address_balances_address ON address_balances (address);
+    CREATE INDEX address_balances_label ON address_balances (label);";
+
+fn sqlite_to_op_error(err: rusqlite::Error) -> OpError {
+    OpError::new(OpErrorKind::RuntimeError).join_msg(&format!("SQLite error: {}", err))
+}
+
+/// Writes all result tables into a single SQLite database.
+///
+/// Only outpoints that carry taint are kept, and the UTXO table only holds
+/// the addresses of those outpoints. This relies on the outpoints being
+/// written before the UTXO set, as `TaintFifo` does.
+pub struct SqliteOutput {
+    folder: PathBuf,
+    connection: Option<Connection>,
+    tables: OutputTables,
+    tainted: HashSet<(String, u32)>, // Outpoints written to the outpoint table
+}
+
+impl SqliteOutput {
+    pub fn new(folder: &Path, tables: OutputTables) -> OpResult<Self> {
+        let path = folder.join(format!("{}.tmp", SQLITE_FILE));
+        if path.exists() {
+            try!(fs::remove_file(&path));
+        }
+        let connection = try!(Connection::open(&path).map_err(sqlite_to_op_error));
+        // The database is rebuilt from scratch when a run fails, so there is
+        // nothing to gain from a journal.
+        try!(connection.execute_batch("PRAGMA journal_mode = OFF; PRAGMA synchronous = OFF;")
+                 .map_err(sqlite_to_op_error));
+        try!(connection.execute_batch(SCHEMA).map_err(sqlite_to_op_error));
+        try!(connection.execute_batch("BEGIN").map_err(sqlite_to_op_error));
+        Ok(SqliteOutput {
+            folder: folder.to_path_buf(),
+            connection: Some(connection),
+            tables: tables,
+            tainted: HashSet::new(),
+        })
+    }
+
+    fn execute(&self, sql: &str, params: &[&ToSql]) -> OpResult<()> {
+        let connection = match self.connection {
+            Some(ref connection) => connection,
+            None => return Err(OpError::new(OpErrorKind::RuntimeError)
+                                   .join_msg("SQLite output was already committed")),
+        };
+        let mut statement = try!(connection.prepare_cached(sql).map_err(sqlite_to_op_error));
+        try!(statement.execute(params).map_err(sqlite_to_op_error));
+        Ok(())
+    }
+}
+
+impl TaintOutput for SqliteOutput {
+    fn write_label(&mut self, name: &str, id: u16) -> OpResult<()> {
+        if self.tables.labels {
+            try!(self.execute("INSERT INTO labels (label, name) VALUES (?, ?)",
+                              &[&(id as i64) as &ToSql, &name]));
+        }
+        Ok(())
+    }
+
+    fn write_overlap(&mut self, overlap: &TaintOverlap) -> OpResult<()> {
+        if self.tables.overlaps {
+            try!(self.execute("INSERT INTO overlaps (kept_label, lost_label, amount, txid, location) VALUES (?, ?, ?, ?, ?)",
+                              &[&(overlap.left as i64) as &ToSql, &(overlap.right as i64), &(overlap.value as i64),
+                                &overlap.txid, &(overlap.location as i64)]));
+        }
+        Ok(())
+    }
+
+    fn write_address_info(&mut self, tx_outpoint: &TxOutpoint, info: &AddressInfo) -> OpResult<()> {
+        let taints = match info.tainted_balance {
+            Some(ref x) if self.tables.address_info => x,
+            _ => return Ok(()),
+        };
+        let txid = arr_to_hex_swapped(&tx_outpoint.txid);
+        let vout = tx_outpoint.index as i64;
+        try!(self.execute("INSERT INTO outpoints (txid, vout, timestamp, balance) VALUES (?, ?, ?, ?)",
+                          &[&txid as &ToSql, &vout, &info.timestamp(), &(info.current_balance as i64)]));
+        for (position, tp) in taints.iter().enumerate() {
+            try!(self.execute("INSERT INTO outpoint_taints (txid, vout, position, label, amount) VALUES (?, ?, ?, ?, ?)",
+                              &[&txid as &ToSql, &vout, &(position as i64), &(tp.name as i64), &(tp.value as i64)]));
+        }
+        self.tainted.insert((txid, tx_outpoint.index));
+        Ok(())
+    }
+
+    fn write_utxo(&mut self, tx_outpoint: &TxOutpoint, address: &str) -> OpResult<()> {
+        let txid = arr_to_hex_swapped(&tx_outpoint.txid);
+        if self.tables.utxo && self.tainted.contains(&(txid.clone(), tx_outpoint.index)) {
+            try!(self.execute("INSERT INTO utxos (txid, vout, address) VALUES (?, ?, ?)",
+                              &[&txid as &ToSql, &(tx_outpoint.index as i64), &address]));
+        }
+        Ok(())
+    }
+
+    fn write_series_sample(&mut self, sample: &SeriesSample) -> OpResult<()> {
+        if self.tables.series {
+            try!(self.execute("INSERT INTO series (height, date, label, value, outputs) VALUES (?, ?, ?, ?, ?)",
+                              &[&(sample.height as i64) as &ToSql, &sample.date, &(sample.label as i64),
+                                &(sample.value as i64), &(sample.outputs as i64)]));
+        }
+        Ok(())
+    }
+
+    fn write_address_balance(&mut self, balance: &AddressBalance) -> OpResult<()> {
+        if self.tables.address_balances {
+            for (label, amount) in balance.taints.iter() {
+                try!(self.execute("INSERT INTO address_balances (address, outputs, balance, label, amount) VALUES (?, ?, ?, ?, ?)",
+                                  &[&balance.address as &ToSql, &(balance.outputs as i64), &(balance.balance as i64),
+                                    &(*label as i64), &(*amount as i64)]));
+            }
+        }
+        Ok(())
+    }
+
+    fn write_tainted_utxo(&mut self, _tx_outpoint: &TxOutpoint, _address: &str, _info: &AddressInfo) -> OpResult<()> {
+        // Already covered by joining `outpoints`, `outpoint_taints` and `utxos`
+        Ok(())
+    }
+
+    fn write_flow(&mut self, flow: &TaintFlow) -> OpResult<()> {
+        if self.tables.flows {
+            let mut per_label: BTreeMap<u16, u64> = BTreeMap::new();
+            for tp in flow.taint.iter().filter(|tp| tp.name != 0) {
+                *per_label.entry(tp.name).or_insert(0) += tp.value;
+            }
+            let outpoint_txid = arr_to_hex_swapped(&flow.outpoint.txid);
+            for (label, amount) in per_label.iter() {
+                try!(self.execute("INSERT INTO flows (height, txid, kind, outpoint_txid, outpoint_vout, value, label, amount) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+                                  &[&(flow.height as i64) as &ToSql, &flow.txid, &flow.kind.as_str(), &outpoint_txid,
+                                    &(flow.outpoint.index as i64), &(flow.value as i64), &(*label as i64), &(*amount as i64)]));
+            }
+        }
+        Ok(())
+    }
+
+    fn write_metadata(&mut self, key: &str, value: &str) -> OpResult<()> {
+        self.execute("INSERT OR REPLACE INTO metadata (key, value) VALUES (?, ?)", &[&key as &ToSql, &value])
+    }
+
+    fn finish(&mut self, pending: &mut PendingFiles) -> OpResult<()> {
+        let connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => return Ok(()),
+        };
+        try!(connection.execute_batch("COMMIT").map_err(sqlite_to_op_error));
+        info!(target: "FIFO [sqlite]", "Creating indexes...");
+        try!(connection.execute_batch(INDEXES).map_err(sqlite_to_op_error));
+        try!(connection.close().map_err(|(_, err)| sqlite_to_op_error(err)));
+
+        // Written without journal or syncing, so sync the whole file once here
+        let tmp = self.folder.join(format!("{}.tmp", SQLITE_FILE));
+        try!(sync_file(&tmp));
+        pending.add(tmp, self.folder.join(SQLITE_FILE));
+        Ok(())
+    }
+}
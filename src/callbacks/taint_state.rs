@@ -0,0 +1,352 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, File};
+use std::hash::{BuildHasher, BuildHasherDefault};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use rustc_serialize::json::{self, Json};
+use twox_hash::XxHash;
+
+use errors::{OpError, OpErrorKind, OpResult};
+
+use blockchain::proto::tx::TxOutpoint;
+use blockchain::utils::{arr_to_hex_swapped, hex_to_arr32_swapped};
+
+use callbacks::taint_engine::{AddressInfo, TaintPart, TaintVec, date_to_timestamp};
+use callbacks::taint_output::{PendingFiles, write_pending};
+use callbacks::taint_results::{load_labels, read_address_info, read_utxos, for_each_line, normalize_outpoint,
+                               parse_error, parse_number};
+
+/// File with the heights and counters of a saved state.
+pub const STATE_FILE: &'static str = "state.json";
+/// Seeds of the address file whose transactions were not reached yet, in the address file format.
+pub const PENDING_SEEDS_FILE: &'static str = "pending_seeds.csv";
+
+/// Orders outpoints as their txids are displayed, then by index, so that
+/// exports do not depend on the order of the hash maps.
+pub fn outpoint_order(a: &TxOutpoint, b: &TxOutpoint) -> Ordering {
+    a.txid.iter().rev().cmp(b.txid.iter().rev()).then(a.index.cmp(&b.index))
+}
+
+pub fn sorted_by_outpoint<V, S: BuildHasher>(map: &HashMap<TxOutpoint, V, S>) -> Vec<(&TxOutpoint, &V)> {
+    let mut entries: Vec<(&TxOutpoint, &V)> = map.iter().collect();
+    entries.sort_by(|a, b| outpoint_order(a.0, b.0));
+    entries
+}
+
+/// Every address of the UTXO set stored once, outputs refer to it by id.
+/// An address counts the unspent outputs that refer to it and is dropped
+/// with the last of them; its id is then given to the next new address.
+#[derive(Default)]
+pub struct AddressBook {
+    ids: HashMap<Rc<str>, u32, BuildHasherDefault<XxHash>>,
+    names: Vec<(Rc<str>, u32)>, // Address and the number of outputs referring to it
+    free: Vec<u32>,             // Ids of dropped addresses
+}
+
+impl AddressBook {
+    /// The id of `address`, counted as referred to by one more output.
+    pub fn intern(&mut self, address: &str) -> u32 {
+        if let Some(id) = self.ids.get(address) {
+            self.names[*id as usize].1 += 1;
+            return *id;
+        }
+        let name: Rc<str> = Rc::from(address);
+        let id = match self.free.pop() {
+            Some(id) => {
+                self.names[id as usize] = (name.clone(), 1);
+                id
+            },
+            None => {
+                self.names.push((name.clone(), 1));
+                (self.names.len() - 1) as u32
+            },
+        };
+        self.ids.insert(name, id);
+        id
+    }
+
+    /// One output less refers to `id`, the address is dropped with the last one.
+    pub fn release(&mut self, id: u32) {
+        let entry = &mut self.names[id as usize];
+        entry.1 -= 1;
+        if entry.1 == 0 {
+            self.ids.remove(&entry.0);
+            entry.0 = Rc::from("");
+            self.free.push(id);
+        }
+    }
+
+    pub fn get(&self, id: u32) -> &str {
+        &self.names[id as usize].0
+    }
+
+    /// Number of addresses with unspent outputs.
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+}
+
+/// Adds an unspent output to the UTXO set, or moves it to another address.
+pub fn insert_utxo<S: BuildHasher>(utxo_set: &mut HashMap<TxOutpoint, u32, S>,
+                                   addresses: &mut AddressBook,
+                                   outpoint: TxOutpoint,
+                                   address: &str) {
+    let id = addresses.intern(address);
+    if let Some(previous) = utxo_set.insert(outpoint, id) {
+        addresses.release(previous);
+    }
+}
+
+/// Takes a spent output out of the UTXO set.
+pub fn remove_utxo<S: BuildHasher>(utxo_set: &mut HashMap<TxOutpoint, u32, S>,
+                                   addresses: &mut AddressBook,
+                                   outpoint: &TxOutpoint) {
+    if let Some(id) = utxo_set.remove(outpoint) {
+        addresses.release(id);
+    }
+}
+
+/// What, besides the maps, is needed to continue a run where it stopped.
+pub struct RunState {
+    pub start_height: usize,
+    pub end_height: usize,  // Last block whose effects are in the state
+    pub taint_collisions: u32,
+    pub address_file: String,
+    pub address_file_sha256: String,
+    pub series_last_date: Option<String>,
+    pub series_closing_rows: usize, // Rows of the series sample written only because the run ended
+    pub snapshot_files: Vec<PathBuf>, // Snapshot files committed so far, relative to the dump folder
+    pub partial: bool,      // Saved because the run was interrupted
+}
+
+impl RunState {
+    fn to_json(&self) -> Json {
+        let mut obj = BTreeMap::new();
+        obj.insert(String::from("version"), Json::String(String::from("0.2")));
+        obj.insert(String::from("start_height"), Json::U64(self.start_height as u64));
+        obj.insert(String::from("end_height"), Json::U64(self.end_height as u64));
+        obj.insert(String::from("taint_collisions"), Json::U64(self.taint_collisions as u64));
+        obj.insert(String::from("address_file"), Json::String(self.address_file.clone()));
+        obj.insert(String::from("address_file_sha256"), Json::String(self.address_file_sha256.clone()));
+        obj.insert(String::from("series_closing_rows"), Json::U64(self.series_closing_rows as u64));
+        obj.insert(String::from("series_last_date"), match self.series_last_date {
+            Some(ref date) => Json::String(date.clone()),
+            None => Json::Null,
+        });
+        obj.insert(String::from("snapshot_files"), Json::Array(
+            self.snapshot_files.iter().map(|path| Json::String(path.to_string_lossy().into_owned())).collect()));
+        obj.insert(String::from("partial"), Json::Boolean(self.partial));
+        Json::Object(obj)
+    }
+
+    pub fn load(folder: &Path) -> OpResult<Self> {
+        let path = folder.join(STATE_FILE);
+        let mut text = String::new();
+        try!(try!(File::open(&path).map_err(|err| {
+            OpError::from(err).join_msg(&format!("Unable to open {}", path.display()))
+        })).read_to_string(&mut text));
+        let state = try!(Json::from_str(&text).map_err(|err| parse_error(&path, 0, &format!("{}", err))));
+        let number = |key: &str| -> OpResult<u64> {
+            state.find(key).and_then(|v| v.as_u64()).ok_or_else(|| parse_error(&path, 0, &format!("missing `{}`", key)))
+        };
+        Ok(RunState {
+            start_height: try!(number("start_height")) as usize,
+            end_height: try!(number("end_height")) as usize,
+            taint_collisions: try!(number("taint_collisions")) as u32,
+            address_file: state.find("address_file").and_then(|v| v.as_string()).map(String::from).unwrap_or_default(),
+            address_file_sha256: state.find("address_file_sha256").and_then(|v| v.as_string()).map(String::from).unwrap_or_default(),
+            series_last_date: state.find("series_last_date").and_then(|v| v.as_string()).map(String::from),
+            series_closing_rows: state.find("series_closing_rows").and_then(|v| v.as_u64()).unwrap_or(0) as usize,
+            snapshot_files: state.find("snapshot_files").and_then(|v| v.as_array())
+                .map(|files| files.iter().filter_map(|f| f.as_string()).map(PathBuf::from).collect())
+                .unwrap_or_default(),
+            partial: state.find("partial").and_then(|v| v.as_boolean()).unwrap_or(false),
+        })
+    }
+}
+
+fn taint_fields(taint: &[TaintPart]) -> String {
+    taint.iter().fold(String::new(), |acc, tp| acc + &format!("{} {},", tp.name, tp.value))
+}
+
+/// Writes the whole state of a run into `folder` in the layout of a dump
+/// folder, so that `taintQuery` and `taintServer` can read it as well.
+/// Unlike a dump folder, clean outpoints and the pending seeds are included.
+pub fn write_state<S1, S2>(folder: &Path,
+                           state: &RunState,
+                           labels: &BTreeMap<u16, String>,
+                           address_mapping: &HashMap<TxOutpoint, AddressInfo, S1>,
+                           utxo_set: &HashMap<TxOutpoint, u32, S2>,
+                           addresses: &AddressBook,
+                           pending_seeds: &HashMap<String, VecDeque<TaintPart>>,
+                           pending: &mut PendingFiles) -> OpResult<()>
+    where S1: BuildHasher, S2: BuildHasher
+{
+    try!(fs::create_dir_all(folder));
+    try!(write_pending(&folder.join("taint_mapper.csv"), pending, |w| {
+        for (id, name) in labels.iter() {
+            try!(writeln!(w, "{},{}", name, id));
+        }
+        Ok(())
+    }));
+    try!(write_pending(&folder.join("address_info.csv"), pending, |w| {
+        for (outpoint, info) in sorted_by_outpoint(address_mapping) {
+            try!(writeln!(w, "{},{},{},{},{}", arr_to_hex_swapped(&outpoint.txid), outpoint.index,
+                          info.timestamp(), info.current_balance, taint_fields(info.taint())));
+        }
+        Ok(())
+    }));
+    try!(write_pending(&folder.join("taint_utxo.csv"), pending, |w| {
+        for (outpoint, id) in sorted_by_outpoint(utxo_set) {
+            try!(writeln!(w, "{},{},{}", arr_to_hex_swapped(&outpoint.txid), outpoint.index, addresses.get(*id)));
+        }
+        Ok(())
+    }));
+    try!(write_pending(&folder.join(PENDING_SEEDS_FILE), pending, |w| {
+        let mut txids: Vec<&String> = pending_seeds.keys().collect();
+        txids.sort();
+        for txid in txids {
+            let mut line = txid.clone();
+            for tp in pending_seeds[txid].iter() {
+                line += &format!(",{},{}", labels.get(&tp.name).map(|n| n.as_str()).unwrap_or(""), tp.value);
+            }
+            try!(writeln!(w, "{}", line));
+        }
+        Ok(())
+    }));
+    write_pending(&folder.join(STATE_FILE), pending, |w| {
+        try!(writeln!(w, "{}", json::as_pretty_json(&state.to_json())));
+        Ok(())
+    })
+}
+
+fn parse_outpoint(outpoint: &str) -> Option<TxOutpoint> {
+    let mut parts = outpoint.splitn(2, ':');
+    let txid = parts.next().unwrap_or("");
+    let index = parts.next().and_then(|i| i.parse::<u32>().ok());
+    match index {
+        Some(index) if txid.len() == 64 && txid.chars().all(|c| c.is_digit(16)) => Some(TxOutpoint { txid: hex_to_arr32_swapped(txid), index: index }),
+        _ => None,
+    }
+}
+
+/// Loads the outpoints, UTXO set and taint names saved by `write_state`, or
+/// by the CSV output of a finished run.
+pub fn load_state<S1, S2>(folder: &Path,
+                          address_mapping: &mut HashMap<TxOutpoint, AddressInfo, S1>,
+                          utxo_set: &mut HashMap<TxOutpoint, u32, S2>,
+                          addresses: &mut AddressBook)
+                          -> OpResult<BTreeMap<String, u16>>
+    where S1: BuildHasher, S2: BuildHasher
+{
+    let labels = try!(load_labels(folder));
+
+    let path = folder.join("address_info.csv");
+    let mut invalid = None;
+    try!(read_address_info(folder, |record| {
+        match parse_outpoint(&record.outpoint) {
+            Some(outpoint) => {
+                let taint: TaintVec = record.taint.iter()
+                    .map(|&(name, value)| TaintPart { name: name, value: value }).collect();
+                address_mapping.insert(outpoint, AddressInfo {
+                    time: date_to_timestamp(&record.timestamp),
+                    current_balance: record.balance,
+                    tainted_balance: if taint.is_empty() { None } else { Some(taint) },
+                });
+            },
+            None => invalid = Some(record.outpoint),
+        }
+    }));
+    if let Some(outpoint) = invalid {
+        return Err(parse_error(&path, 0, &format!("invalid outpoint `{}`", outpoint)));
+    }
+
+    let path = folder.join("taint_utxo.csv");
+    try!(read_utxos(folder, |outpoint, address| {
+        match parse_outpoint(&outpoint) {
+            Some(parsed) => insert_utxo(utxo_set, addresses, parsed, address),
+            None => invalid = Some(outpoint),
+        }
+    }));
+    if let Some(outpoint) = invalid {
+        return Err(parse_error(&path, 0, &format!("invalid outpoint `{}`", outpoint)));
+    }
+    if address_mapping.is_empty() && !utxo_set.is_empty() {
+        return Err(OpError::new(OpErrorKind::RuntimeError)
+                       .join_msg(&format!("{} has a UTXO set but no outpoints", folder.display())));
+    }
+    Ok(labels)
+}
+
+/// An unspent output of an imported UTXO snapshot.
+pub struct SnapshotUtxo {
+    pub outpoint: TxOutpoint,
+    pub value: u64,
+    pub address: String,
+    pub taint: Vec<(String, u64)>, // Taint name -> amount, in FIFO order
+}
+
+/// The height in the `# height <n>` header of a UTXO snapshot.
+fn snapshot_height(line: &str) -> Option<usize> {
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next(), words.next().and_then(|h| h.parse::<usize>().ok()), words.next()) {
+        (Some("#"), Some("height"), Some(height), None) => Some(height),
+        _ => None,
+    }
+}
+
+/// Reads a UTXO snapshot with one output per line:
+/// `txid:index,value,address[,taint name,amount...]`, spaces around the
+/// fields allowed. The first line must be `# height <n>`, the block after
+/// which the snapshot was taken, and the run has to start at the next block.
+pub fn read_utxo_snapshot<F>(path: &Path, start_height: usize, mut f: F) -> OpResult<usize>
+    where F: FnMut(SnapshotUtxo) -> OpResult<()>
+{
+    let mut height = None;
+    let lines = try!(for_each_line(path, |line, line_no| {
+        if height.is_none() {
+            let snapshot_height = match snapshot_height(line) {
+                Some(h) => h,
+                None => return Err(parse_error(path, line_no, "the first line must be `# height <n>`")),
+            };
+            if snapshot_height + 1 != start_height {
+                return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                           .join_msg(&format!("The UTXO snapshot {} was taken after block {}, the parser has to start at block {} instead of {}",
+                                              path.display(), snapshot_height, snapshot_height + 1, start_height)));
+            }
+            height = Some(snapshot_height);
+            return Ok(());
+        }
+        let mut fields = line.split(',').map(str::trim);
+        let outpoint = match fields.next().and_then(|o| normalize_outpoint(o)).and_then(|o| parse_outpoint(&o)) {
+            Some(outpoint) => outpoint,
+            None => return Err(parse_error(path, line_no, "invalid outpoint, expected txid:index")),
+        };
+        let value = try!(parse_number::<u64>(fields.next(), path, line_no));
+        let address = String::from(fields.next().unwrap_or(""));
+        let rest: Vec<&str> = fields.collect();
+        if rest.len() % 2 != 0 {
+            return Err(parse_error(path, line_no, "taint names and amounts must come in pairs"));
+        }
+        let mut taint = Vec::new();
+        for pair in rest.chunks(2) {
+            taint.push((String::from(pair[0]), try!(parse_number::<u64>(Some(pair[1]), path, line_no))));
+        }
+        if taint.iter().fold(0, |a, &(_, amount)| a + amount) > value {
+            return Err(parse_error(path, line_no, "the taint is larger than the value"));
+        }
+        f(SnapshotUtxo {
+            outpoint: outpoint,
+            value: value,
+            address: address,
+            taint: taint,
+        })
+    }));
+    if height.is_none() {
+        return Err(parse_error(path, 0, "the first line must be `# height <n>`"));
+    }
+    Ok(lines - 1)
+}
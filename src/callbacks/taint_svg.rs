@@ -0,0 +1,184 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::Path;
+
+use errors::OpResult;
+
+use callbacks::taint_engine::TaintPart;
+use callbacks::taint_output::{PendingFiles, write_pending, markup_escape};
+
+const BAR_WIDTH: f64 = 30.0;  // Width of one UTXO bar
+const COLUMN_WIDTH: f64 = 40.0; // Distance between the bars of two blocks
+const TOP_MARGIN: f64 = 10.0;
+const BAR_GAP: f64 = 25.0;    // Vertical space between two bars of a block
+
+/// A tainted output created inside the rendered height range.
+struct SvgUtxo {
+    outpoint: String,
+    value: u64,
+    taint: Vec<TaintPart>,
+    inputs: Vec<String>, // Txids of the transactions whose outputs were spent
+}
+
+/// Draws the tainted outputs of a range of blocks as stacked bars of their
+/// dirty fragments, one column per block, like `Visualiser/svg_generator.py`.
+///
+/// Each label keeps the same colour between runs with the same address file,
+/// and an output is connected to the outputs of earlier blocks it spends.
+pub struct TaintSvg {
+    from: usize,
+    to: usize,
+    scale: f64,                          // Pixels per BTC
+    labels: BTreeMap<u16, String>,       // Label id -> taint name
+    blocks: BTreeMap<usize, Vec<SvgUtxo>>, // Height -> outputs in block order
+}
+
+/// The colour of a label, spread over the hue circle by the golden angle so
+/// that neighbouring ids are easy to tell apart.
+pub fn label_color(label: u16) -> String {
+    if label == 0 {
+        return String::from("white");
+    }
+    let hue = (label as f64 * 137.507_764) % 360.0;
+    let saturation = [70, 55, 85][(label % 3) as usize];
+    let lightness = [50, 40, 60][((label / 3) % 3) as usize];
+    format!("hsl({:.1},{}%,{}%)", hue, saturation, lightness)
+}
+
+fn to_btc(satoshi: u64) -> f64 {
+    satoshi as f64 * 1e-8
+}
+
+impl TaintSvg {
+    pub fn new(from: usize, to: usize, scale: f64) -> Self {
+        TaintSvg {
+            from: from,
+            to: to,
+            scale: scale,
+            labels: BTreeMap::new(),
+            blocks: BTreeMap::new(),
+        }
+    }
+
+    pub fn set_labels(&mut self, dirtmapper: &HashMap<String, u16>) {
+        self.labels = dirtmapper.iter().map(|(name, id)| (*id, name.clone())).collect();
+    }
+
+    pub fn in_range(&self, height: usize) -> bool {
+        self.from <= height && height <= self.to
+    }
+
+    pub fn starts_after(&self, height: usize) -> bool {
+        self.from > height
+    }
+
+    /// Remembers an output of block `height`; outputs without dirty fragments are left out.
+    pub fn add_utxo(&mut self, height: usize, outpoint: String, value: u64, taint: &[TaintPart], inputs: &[String]) {
+        if !self.in_range(height) || !taint.iter().any(|tp| tp.name != 0) {
+            return;
+        }
+        self.blocks.entry(height).or_insert_with(Vec::new).push(SvgUtxo {
+            outpoint: outpoint,
+            value: value,
+            taint: taint.to_vec(),
+            inputs: inputs.to_vec(),
+        });
+    }
+
+    fn fragment_height(&self, value: u64) -> f64 {
+        (to_btc(value) * self.scale).max(1.0)
+    }
+
+    fn bar_height(&self, utxo: &SvgUtxo) -> f64 {
+        utxo.taint.iter().filter(|tp| tp.name != 0).map(|tp| self.fragment_height(tp.value)).sum()
+    }
+
+    fn label_name(&self, label: u16) -> &str {
+        self.labels.get(&label).map(|name| name.as_str()).unwrap_or("?")
+    }
+
+    /// The label holding most of the output's dirty value, used to colour its links.
+    fn main_label(utxo: &SvgUtxo) -> u16 {
+        let mut per_label: BTreeMap<u16, u64> = BTreeMap::new();
+        for tp in utxo.taint.iter().filter(|tp| tp.name != 0) {
+            *per_label.entry(tp.name).or_insert(0) += tp.value;
+        }
+        per_label.iter().fold((0, 0), |best, (label, value)| if *value > best.1 {(*label, *value)} else {best}).0
+    }
+
+    pub fn utxo_count(&self) -> usize {
+        self.blocks.values().map(|utxos| utxos.len()).sum()
+    }
+
+    pub fn write_svg(&self, path: &Path, pending: &mut PendingFiles) -> OpResult<()> {
+        let mut bars = Vec::new();
+        let mut links = Vec::new();
+        let mut coords: HashMap<&str, (f64, f64)> = HashMap::new(); // Txid -> middle of its last bar
+        let mut width = 0.0;
+        let mut height = 0.0;
+
+        for (column, (block_height, utxos)) in self.blocks.iter().enumerate() {
+            let x = COLUMN_WIDTH * column as f64;
+            let mut y = TOP_MARGIN;
+            for utxo in utxos.iter() {
+                let bar_height = self.bar_height(utxo);
+                let taints: Vec<String> = utxo.taint.iter()
+                    .map(|tp| format!("{}, {}", self.label_name(tp.name), tp.value)).collect();
+                bars.push(format!("<g><title>UTXO: {}&#10;Block: {}&#10;V: {}&#10;T: {}</title>\n",
+                                  markup_escape(&utxo.outpoint), block_height, utxo.value, markup_escape(&taints.join(" "))));
+                bars.push(format!("  <rect x=\"{:.2}\" y=\"{:.2}\" width=\"{}\" height=\"{:.2}\" fill=\"none\" stroke=\"black\"/>\n",
+                                  x, y, BAR_WIDTH, bar_height));
+
+                let mut offset = 0.0;
+                let mut value_offset = 0;
+                for tp in utxo.taint.iter() {
+                    value_offset += tp.value;
+                    if tp.name == 0 {
+                        continue;
+                    }
+                    let h = self.fragment_height(tp.value);
+                    bars.push(format!("  <rect x=\"{:.2}\" y=\"{:.2}\" width=\"{}\" height=\"{:.2}\" fill=\"{}\" stroke=\"black\"><title>{}, {}/{} starts at {}</title></rect>\n",
+                                      x, y + offset, BAR_WIDTH, h, label_color(tp.name),
+                                      markup_escape(self.label_name(tp.name)), tp.value, utxo.value, value_offset - tp.value));
+                    offset += h;
+                }
+                bars.push(String::from("</g>\n"));
+
+                let middle = (x, y + bar_height / 2.0);
+                let color = label_color(Self::main_label(utxo));
+                for input in utxo.inputs.iter() {
+                    if let Some(&(ix, iy)) = coords.get(input.as_str()) {
+                        links.push(format!("<line x1=\"{:.2}\" y1=\"{:.2}\" x2=\"{:.2}\" y2=\"{:.2}\" style=\"stroke:{};stroke-width:1\"/>\n",
+                                           middle.0, middle.1, ix, iy, color));
+                    }
+                }
+                let txid = utxo.outpoint.split(':').next().unwrap_or("");
+                coords.insert(txid, middle);
+
+                y += bar_height + BAR_GAP;
+            }
+            width = x + COLUMN_WIDTH;
+            if y > height {
+                height = y;
+            }
+        }
+
+        write_pending(path, pending, |w| {
+            try!(write!(w, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n"));
+            try!(write!(w, "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{:.0}\" height=\"{:.0}\">\n",
+                        width + TOP_MARGIN, height + TOP_MARGIN));
+            try!(write!(w, "<!-- Blocks {} to {} -->\n", self.from, self.to));
+            for (label, name) in self.labels.iter().filter(|&(label, _)| *label != 0) {
+                try!(write!(w, "<!-- {} {} {} -->\n", label, label_color(*label), markup_escape(name).replace("--", "- -")));
+            }
+            for bar in bars.iter() {
+                try!(w.write_all(bar.as_bytes()));
+            }
+            for link in links.iter() {
+                try!(w.write_all(link.as_bytes()));
+            }
+            try!(write!(w, "</svg>\n"));
+            Ok(())
+        })
+    }
+}
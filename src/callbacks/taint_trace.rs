@@ -0,0 +1,65 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::Path;
+
+use errors::{OpError, OpResult};
+
+/// Explains step by step how the taint of a few chosen transactions is
+/// matched, without turning on debug logging for the whole run.
+pub struct TxTracer {
+    txids: BTreeSet<String>,
+    seen: BTreeSet<String>,
+    writer: LineWriter<File>,
+    failed: bool,
+}
+
+impl TxTracer {
+    pub fn new(path: &Path, txids: Vec<String>) -> OpResult<Self> {
+        // Written as it goes rather than committed at the end, so that the
+        // trace is still there when a failed assertion stops the run
+        let file = try!(File::create(path).map_err(|err| {
+            OpError::from(err).join_msg(&format!("Unable to create {}", path.display()))
+        }));
+        Ok(TxTracer {
+            txids: txids.into_iter().map(|txid| txid.trim().to_lowercase()).collect(),
+            seen: BTreeSet::new(),
+            writer: LineWriter::new(file),
+            failed: false,
+        })
+    }
+
+    pub fn traces(&self, txid: &str) -> bool {
+        self.txids.contains(txid)
+    }
+
+    pub fn write_labels(&mut self, labels: &BTreeMap<u16, String>) {
+        let legend: Vec<String> = labels.iter().map(|(id, name)| format!("{} {}", id, name)).collect();
+        self.line(&format!("Labels: {}", legend.join(", ")));
+    }
+
+    /// Starts the explanation of one transaction.
+    pub fn begin(&mut self, txid: &str, block_height: usize) {
+        self.seen.insert(String::from(txid));
+        self.line(&format!("\nTx {} in block {}", txid, block_height));
+    }
+
+    pub fn line(&mut self, text: &str) {
+        if self.failed {
+            return;
+        }
+        if let Err(err) = writeln!(self.writer, "{}", text) {
+            warn!(target: "FIFO [trace]", "Unable to write the transaction trace, it stops here: {}", err);
+            self.failed = true;
+        }
+    }
+
+    /// Lists the traced transactions that never showed up.
+    pub fn finish(&mut self) {
+        let missing: Vec<String> = self.txids.difference(&self.seen).cloned().collect();
+        for txid in missing.iter() {
+            warn!(target: "FIFO [trace]", "Traced transaction {} was not in the processed blocks.", txid);
+        }
+        let _ = self.writer.flush();
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::BuildHasher;
+
+use blockchain::proto::tx::TxOutpoint;
+use blockchain::utils::arr_to_hex_swapped;
+
+use callbacks::taint_engine::{AddressInfo, TaintPart};
+use callbacks::taint_state::{AddressBook, insert_utxo, remove_utxo};
+
+/// What a block changed, as the values from before it.
+pub struct BlockUndo {
+    pub height: usize,
+    pub outpoints: Vec<(TxOutpoint, Option<AddressInfo>, Option<String>)>, // Outpoint -> state and address before the block
+    pub seeds: Vec<(String, VecDeque<TaintPart>)>, // Seeds the block consumed
+    pub taint_collisions: u32,
+    pub series_last_date: Option<String>,
+}
+
+impl BlockUndo {
+    /// Puts the outpoints and seeds back as they were before the block.
+    pub fn restore<S1, S2>(self,
+                           address_mapping: &mut HashMap<TxOutpoint, AddressInfo, S1>,
+                           utxo_set: &mut HashMap<TxOutpoint, u32, S2>,
+                           addresses: &mut AddressBook,
+                           seeds: &mut HashMap<String, VecDeque<TaintPart>>)
+        where S1: BuildHasher, S2: BuildHasher
+    {
+        for (outpoint, info, address) in self.outpoints {
+            match info {
+                Some(info) => { address_mapping.insert(outpoint.clone(), info); },
+                None => { address_mapping.remove(&outpoint); },
+            }
+            match address {
+                Some(address) => insert_utxo(utxo_set, addresses, outpoint, &address),
+                None => remove_utxo(utxo_set, addresses, &outpoint),
+            }
+        }
+        for (txid, seed) in self.seeds {
+            seeds.insert(txid, seed);
+        }
+    }
+}
+
+/// A copy of the state that a rollback restores, to check it against the
+/// state from before the rolled back blocks.
+pub struct StateView {
+    pub outpoints: HashMap<TxOutpoint, AddressInfo>,
+    pub utxos: HashMap<TxOutpoint, String>, // Outpoint -> address
+    pub addresses: usize,                   // Addresses still referred to
+    pub seeds: HashMap<String, VecDeque<TaintPart>>,
+    pub taint_collisions: u32,
+}
+
+impl StateView {
+    pub fn new<S1, S2>(address_mapping: &HashMap<TxOutpoint, AddressInfo, S1>,
+                       utxo_set: &HashMap<TxOutpoint, u32, S2>,
+                       addresses: &AddressBook,
+                       seeds: &HashMap<String, VecDeque<TaintPart>>,
+                       taint_collisions: u32) -> Self
+        where S1: BuildHasher, S2: BuildHasher
+    {
+        StateView {
+            outpoints: address_mapping.iter().map(|(outpoint, info)| (outpoint.clone(), info.clone())).collect(),
+            utxos: utxo_set.iter().map(|(outpoint, id)| (outpoint.clone(), String::from(addresses.get(*id)))).collect(),
+            addresses: addresses.len(),
+            seeds: seeds.clone(),
+            taint_collisions: taint_collisions,
+        }
+    }
+
+    /// How `self` differs from `expected`, empty when they are the same.
+    pub fn differences(&self, expected: &StateView) -> Vec<String> {
+        let name = |outpoint: &TxOutpoint| format!("{}:{}", arr_to_hex_swapped(&outpoint.txid), outpoint.index);
+        let mut differences = Vec::new();
+        for (outpoint, info) in self.outpoints.iter() {
+            match expected.outpoints.get(outpoint) {
+                Some(before) if before == info => {},
+                Some(before) => differences.push(format!("outpoint {} is {}, expected {}", name(outpoint), info, before)),
+                None => differences.push(format!("outpoint {} should not exist", name(outpoint))),
+            }
+        }
+        for outpoint in expected.outpoints.keys().filter(|outpoint| !self.outpoints.contains_key(*outpoint)) {
+            differences.push(format!("outpoint {} is missing", name(outpoint)));
+        }
+        for (outpoint, address) in self.utxos.iter() {
+            match expected.utxos.get(outpoint) {
+                Some(before) if before == address => {},
+                Some(before) => differences.push(format!("UTXO {} has the address {}, expected {}", name(outpoint), address, before)),
+                None => differences.push(format!("UTXO {} should not exist", name(outpoint))),
+            }
+        }
+        for outpoint in expected.utxos.keys().filter(|outpoint| !self.utxos.contains_key(*outpoint)) {
+            differences.push(format!("UTXO {} is missing", name(outpoint)));
+        }
+        if self.addresses != expected.addresses {
+            differences.push(format!("{} addresses, expected {}", self.addresses, expected.addresses));
+        }
+        if self.seeds != expected.seeds {
+            let mut txids: Vec<&String> = self.seeds.keys().chain(expected.seeds.keys())
+                .filter(|txid| self.seeds.get(*txid) != expected.seeds.get(*txid)).collect();
+            txids.sort();
+            txids.dedup();
+            for txid in txids {
+                differences.push(format!("seed {} is {:?}, expected {:?}", txid, self.seeds.get(txid), expected.seeds.get(txid)));
+            }
+        }
+        if self.taint_collisions != expected.taint_collisions {
+            differences.push(format!("{} collisions, expected {}", self.taint_collisions, expected.taint_collisions));
+        }
+        differences
+    }
+}
+
+/// Undo records of the most recent blocks, the oldest dropped beyond `depth`.
+pub struct UndoJournal {
+    depth: usize,
+    blocks: VecDeque<BlockUndo>,
+}
+
+impl UndoJournal {
+    pub fn new(depth: usize) -> Self {
+        UndoJournal {
+            depth: depth,
+            blocks: VecDeque::with_capacity(depth),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.depth > 0
+    }
+
+    /// Number of blocks that can be rolled back.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Height of the newest journaled block.
+    pub fn tip(&self) -> Option<usize> {
+        self.blocks.back().map(|undo| undo.height)
+    }
+
+    pub fn push(&mut self, undo: BlockUndo) {
+        if !self.is_enabled() {
+            return;
+        }
+        if let Some(last) = self.blocks.back() {
+            if undo.height != last.height + 1 {
+                // Not a continuation of the journaled chain, the old records cannot be applied anymore
+                self.blocks.clear();
+            }
+        }
+        if self.blocks.len() == self.depth {
+            self.blocks.pop_front();
+        }
+        self.blocks.push_back(undo);
+    }
+
+    pub fn pop(&mut self) -> Option<BlockUndo> {
+        self.blocks.pop_back()
+    }
+}
@@ -0,0 +1,231 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+
+use clap::{Arg, ArgMatches, App, SubCommand};
+use rustc_serialize::json::{self, Json};
+
+use errors::{OpError, OpErrorKind, OpResult};
+
+use callbacks::taint_output::FlowKind;
+use callbacks::taint_results::{load_labels, read_flows};
+
+/// What a transaction passed on through one of its outputs.
+struct TreeEdge {
+    outpoint: String,
+    amount: u64, // FIFO-split amount of the label sent to the outpoint
+    fee: bool,
+}
+
+/// The flow records of one label, indexed for walking down from a transaction.
+struct FlowIndex {
+    heights: HashMap<String, usize>,          // txid -> block height
+    outputs: HashMap<String, Vec<TreeEdge>>,  // txid -> outpoints it sent the label to
+    spenders: HashMap<String, String>,        // outpoint -> txid spending it
+}
+
+impl FlowIndex {
+    fn load(folder: &PathBuf, label: u16) -> OpResult<Self> {
+        let mut index = FlowIndex {
+            heights: HashMap::new(),
+            outputs: HashMap::new(),
+            spenders: HashMap::new(),
+        };
+        try!(read_flows(folder, |flow| {
+            let amount = flow.amount_of(label);
+            if flow.kind == FlowKind::Output {
+                // The output flow has the final taint of the outpoint and takes the
+                // place of its seed flow, which comes first. A seed that collided
+                // with the taint of the inputs may be gone from it.
+                let outpoint = flow.outpoint();
+                if let Some(edges) = index.outputs.get_mut(&flow.txid) {
+                    if let Some(position) = edges.iter().position(|e| e.outpoint == outpoint) {
+                        if amount == 0 {
+                            edges.remove(position);
+                        } else {
+                            edges[position].amount = amount;
+                        }
+                        return;
+                    }
+                }
+            }
+            if amount == 0 {
+                return;
+            }
+            index.heights.insert(flow.txid.clone(), flow.height);
+            match flow.kind {
+                FlowKind::Input => {
+                    index.spenders.insert(flow.outpoint(), flow.txid);
+                },
+                FlowKind::Seed | FlowKind::Output | FlowKind::Fee => {
+                    let outpoint = flow.outpoint();
+                    index.outputs.entry(flow.txid).or_insert_with(Vec::new).push(TreeEdge {
+                        outpoint: outpoint,
+                        amount: amount,
+                        fee: flow.kind == FlowKind::Fee,
+                    });
+                },
+            }
+        }));
+        Ok(index)
+    }
+}
+
+/// Builds the nested JSON the weighted tree visualiser expects.
+struct TreeBuilder<'a> {
+    index: &'a FlowIndex,
+    field: String,     // "agg_<label>", the value field of the visualiser
+    max_depth: usize,
+    min_value: u64,
+    max_nodes: usize,
+    nodes: usize,
+}
+
+fn to_btc(satoshi: u64) -> f64 {
+    satoshi as f64 * 1e-8
+}
+
+fn short_txid(txid: &str) -> &str {
+    &txid[..cmp::min(txid.len(), 16)]
+}
+
+impl<'a> TreeBuilder<'a> {
+    fn node(&mut self, key: String, id: &str, amount: u64, children: Vec<Json>) -> Json {
+        self.nodes += 1;
+        let mut obj = BTreeMap::new();
+        obj.insert(String::from("key"), Json::String(key));
+        obj.insert(String::from("id"), Json::String(String::from(id)));
+        obj.insert(self.field.clone(), Json::F64(to_btc(amount)));
+        if !children.is_empty() {
+            obj.insert(String::from("values"), Json::Array(children));
+        }
+        Json::Object(obj)
+    }
+
+    /// The children of a transaction: the transactions spending the outpoints
+    /// it sent the label to, or the outpoints themselves while unspent.
+    fn children(&mut self, txid: &str, id: &str, depth: usize) -> Vec<Json> {
+        let mut children = Vec::new();
+        let index = self.index;
+        let edges = match index.outputs.get(txid) {
+            Some(edges) => edges,
+            None => return children,
+        };
+        for (i, edge) in edges.iter().enumerate() {
+            if edge.amount < self.min_value || self.nodes >= self.max_nodes {
+                continue;
+            }
+            let child_id = format!("{}_{}", id, i);
+            let child = match index.spenders.get(&edge.outpoint) {
+                Some(spender) if depth < self.max_depth => {
+                    let grandchildren = self.children(spender, &child_id, depth + 1);
+                    let key = format!("Tx {} (block {})", short_txid(spender),
+                                      index.heights.get(spender).cloned().unwrap_or(0));
+                    self.node(key, &child_id, edge.amount, grandchildren)
+                },
+                Some(spender) => {
+                    let key = format!("Tx {} ...", short_txid(spender));
+                    self.node(key, &child_id, edge.amount, Vec::new())
+                },
+                None => {
+                    let key = if edge.fee {
+                        format!("Fee to {}", edge.outpoint)
+                    } else {
+                        format!("UTXO {}", edge.outpoint)
+                    };
+                    self.node(key, &child_id, edge.amount, Vec::new())
+                },
+            };
+            children.push(child);
+        }
+        children
+    }
+}
+
+pub fn build_subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("taintTree")
+        .about("Exports the taint flows of one label as weighted tree JSON for the visualiser.")
+        .version("0.2")
+        .arg(Arg::with_name("dump-folder")
+                 .help("Dump folder of a taintFIFO run made with --record-flows")
+                 .required(true))
+        .arg(Arg::with_name("label")
+                 .long("label")
+                 .required(true)
+                 .takes_value(true)
+                 .help("Taint name to follow, as in taint_mapper.csv"))
+        .arg(Arg::with_name("root-tx")
+                 .long("root-tx")
+                 .required(true)
+                 .takes_value(true)
+                 .help("Transaction at the root of the tree"))
+        .arg(Arg::with_name("output")
+                 .short("o")
+                 .long("output")
+                 .takes_value(true)
+                 .help("JSON file to write (default: <dump-folder>/weightedtree.json)"))
+        .arg(Arg::with_name("max-depth")
+                 .long("max-depth")
+                 .takes_value(true)
+                 .help("Number of transaction levels below the root (default: 8)"))
+        .arg(Arg::with_name("min-value")
+                 .long("min-value")
+                 .takes_value(true)
+                 .help("Leave out branches carrying less than this many satoshi"))
+        .arg(Arg::with_name("max-nodes")
+                 .long("max-nodes")
+                 .takes_value(true)
+                 .help("Stop adding nodes after this many (default: 5000)"))
+}
+
+pub fn run(matches: &ArgMatches) -> OpResult<()> {
+    let folder = PathBuf::from(matches.value_of("dump-folder").unwrap());
+    let label_name = matches.value_of("label").unwrap();
+    let root_tx = matches.value_of("root-tx").unwrap();
+    let output = matches.value_of("output").map(PathBuf::from)
+        .unwrap_or_else(|| folder.join("weightedtree.json"));
+
+    let labels = try!(load_labels(&folder));
+    let label = match labels.get(label_name) {
+        Some(label) => *label,
+        None => return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                               .join_msg(&format!("Unknown taint name `{}`", label_name))),
+    };
+
+    let index = try!(FlowIndex::load(&folder, label));
+    if !index.outputs.contains_key(root_tx) {
+        return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                       .join_msg(&format!("Transaction {} did not move any `{}` taint", root_tx, label_name)));
+    }
+
+    let mut builder = TreeBuilder {
+        index: &index,
+        field: format!("agg_{}", label_name),
+        max_depth: value_t!(matches, "max-depth", usize).unwrap_or(8),
+        min_value: value_t!(matches, "min-value", u64).unwrap_or(0),
+        max_nodes: value_t!(matches, "max-nodes", usize).unwrap_or(5000),
+        nodes: 0,
+    };
+    let children = builder.children(root_tx, "0", 1);
+    let total = index.outputs[root_tx].iter().fold(0, |a, e| a + e.amount);
+    let root_key = format!("Tx {} (block {})", short_txid(root_tx), index.heights.get(root_tx).cloned().unwrap_or(0));
+    let mut root = match builder.node(root_key, "0", total, children) {
+        Json::Object(obj) => obj,
+        _ => unreachable!(),
+    };
+    root.insert(String::from("valueFields"), Json::Array(vec![Json::String(String::from(label_name))]));
+    if builder.nodes >= builder.max_nodes {
+        warn!(target: "taintTree", "Tree was cut off at {} nodes.", builder.max_nodes);
+    }
+
+    let tmp_path = output.with_extension("json.tmp");
+    {
+        let mut file = try!(File::create(&tmp_path));
+        try!(file.write_all(format!("{}\n", json::as_pretty_json(&Json::Object(root))).as_bytes()));
+    }
+    try!(fs::rename(&tmp_path, &output));
+    info!(target: "taintTree", "Wrote {} nodes to {}.", builder.nodes, output.display());
+    Ok(())
+}